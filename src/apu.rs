@@ -0,0 +1,1019 @@
+use std::io;
+
+use crate::model::Model;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const NR10_ADDRESS: usize = 0xFF10;
+pub const NR52_ADDRESS: usize = 0xFF26;
+pub const WAVE_RAM_BEGIN: usize = 0xFF30;
+pub const WAVE_RAM_END: usize = 0xFF3F;
+const WAVE_RAM_SIZE: usize = WAVE_RAM_END - WAVE_RAM_BEGIN + 1;
+
+const NR11_ADDRESS: usize = 0xFF11;
+const NR12_ADDRESS: usize = 0xFF12;
+const NR13_ADDRESS: usize = 0xFF13;
+pub const NR14_ADDRESS: usize = 0xFF14;
+const NR21_ADDRESS: usize = 0xFF16;
+const NR22_ADDRESS: usize = 0xFF17;
+const NR23_ADDRESS: usize = 0xFF18;
+const NR24_ADDRESS: usize = 0xFF19;
+const NR30_ADDRESS: usize = 0xFF1A;
+const NR31_ADDRESS: usize = 0xFF1B;
+const NR32_ADDRESS: usize = 0xFF1C;
+const NR33_ADDRESS: usize = 0xFF1D;
+const NR34_ADDRESS: usize = 0xFF1E;
+const NR41_ADDRESS: usize = 0xFF20;
+const NR42_ADDRESS: usize = 0xFF21;
+const NR43_ADDRESS: usize = 0xFF22;
+const NR44_ADDRESS: usize = 0xFF23;
+const NR50_ADDRESS: usize = 0xFF24;
+const NR51_ADDRESS: usize = 0xFF25;
+
+/// Stereo samples are produced at this rate, interleaved left then right
+pub const SAMPLE_RATE: u32 = 48000;
+const CLOCK_RATE: u32 = 4194304;
+
+// Bits that always read back as 1, from NR10 to NR52. Frequencies and lengths are write only.
+const READ_MASKS: [u8; 23] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70,
+];
+
+const TRIGGER: u8 = 0x80;
+const LENGTH_ENABLE: u8 = 0x40;
+
+// The frame sequencer runs at 512Hz and clocks lengths, sweep and envelopes
+const FRAME_SEQUENCER_CYCLES: u32 = 8192;
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Waveforms for the 12.5%, 25%, 50% and 75% duty cycles, played from the lowest bit up
+const DUTY_PATTERNS: [u8; 4] = [0b1000_0000, 0b1000_0001, 0b1110_0001, 0b0111_1110];
+
+struct Length
+{
+    counter: u16,
+    enabled: bool,
+    maximum: u16,
+}
+
+impl Length
+{
+    fn new(maximum: u16) -> Self
+    {
+        Length { counter: 0, enabled: false, maximum }
+    }
+
+    fn load(&mut self, value: u8)
+    {
+        self.counter = self.maximum - value as u16;
+    }
+
+    fn trigger(&mut self)
+    {
+        if self.counter == 0
+        {
+            self.counter = self.maximum;
+        }
+    }
+
+    /// Returns true when the length runs out and the channel is switched off
+    fn clock(&mut self) -> bool
+    {
+        if self.enabled && self.counter > 0
+        {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+struct Envelope
+{
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope
+{
+    fn new() -> Self
+    {
+        Envelope { initial_volume: 0, increase: false, period: 0, volume: 0, timer: 0 }
+    }
+
+    fn write(&mut self, value: u8)
+    {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    /// The DAC is off when the envelope would start silent and only get quieter
+    fn dac_enabled(&self) -> bool
+    {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self)
+    {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self)
+    {
+        if self.period == 0
+        {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0
+        {
+            self.timer = self.period;
+            if self.increase && self.volume < 15
+            {
+                self.volume += 1;
+            }
+            else if !self.increase && self.volume > 0
+            {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Sweep
+{
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Sweep
+{
+    fn new() -> Self
+    {
+        Sweep { period: 0, negate: false, shift: 0, timer: 0, enabled: false, shadow_frequency: 0 }
+    }
+
+    fn write(&mut self, value: u8)
+    {
+        self.period = (value >> 4) & 0x07;
+        self.negate = value & 0x08 != 0;
+        self.shift = value & 0x07;
+    }
+
+    /// Returns `None` when the new frequency overflows, which switches the channel off
+    fn next_frequency(&self) -> Option<u16>
+    {
+        let change = self.shadow_frequency >> self.shift;
+        let frequency = if self.negate
+        {
+            self.shadow_frequency.wrapping_sub(change)
+        }
+        else
+        {
+            self.shadow_frequency + change
+        };
+        (frequency <= 0x7FF).then_some(frequency)
+    }
+}
+
+struct SquareChannel
+{
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+}
+
+impl SquareChannel
+{
+    fn new() -> Self
+    {
+        SquareChannel {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    fn period(&self) -> u32
+    {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn trigger(&mut self)
+    {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+    }
+
+    fn step(&mut self, cycles: u32)
+    {
+        let mut cycles = cycles;
+        while cycles >= self.timer
+        {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> Option<u8>
+    {
+        if !self.envelope.dac_enabled()
+        {
+            return None;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] >> self.duty_position & 0x1 != 0;
+        Some(if self.enabled && high { self.envelope.volume } else { 0 })
+    }
+}
+
+struct WaveChannel
+{
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+    // Set while the channel has just fetched a byte from wave RAM
+    fetched: bool,
+}
+
+impl WaveChannel
+{
+    fn new() -> Self
+    {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            fetched: false,
+        }
+    }
+
+    fn period(&self) -> u32
+    {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn trigger(&mut self)
+    {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn step(&mut self, cycles: u32)
+    {
+        let mut cycles = cycles;
+        self.fetched = false;
+        while cycles >= self.timer
+        {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % (WAVE_RAM_SIZE * 2);
+            self.fetched = true;
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self, wave_ram: &[u8; WAVE_RAM_SIZE]) -> Option<u8>
+    {
+        if !self.dac_enabled
+        {
+            return None;
+        }
+        if !self.enabled || self.volume_code == 0
+        {
+            return Some(0);
+        }
+        // Each byte holds two samples, the high nibble playing first
+        let byte = wave_ram[self.position / 2];
+        let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        Some(sample >> (self.volume_code - 1))
+    }
+}
+
+struct NoiseChannel
+{
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    shift: u8,
+    width_7: bool,
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl NoiseChannel
+{
+    fn new() -> Self
+    {
+        NoiseChannel {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            shift: 0,
+            width_7: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    fn period(&self) -> u32
+    {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.shift
+    }
+
+    fn trigger(&mut self)
+    {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+    }
+
+    fn step(&mut self, cycles: u32)
+    {
+        let mut cycles = cycles;
+        while cycles >= self.timer
+        {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+            self.lfsr = (self.lfsr >> 1) | feedback << 14;
+            if self.width_7
+            {
+                self.lfsr = (self.lfsr & !0x40) | feedback << 6;
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> Option<u8>
+    {
+        if !self.envelope.dac_enabled()
+        {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 0x1 == 0 { self.envelope.volume } else { 0 })
+    }
+}
+
+/// Audio processing unit: two square channels, the first with a frequency sweep, a channel
+/// playing 4 bit samples from wave RAM and a noise channel.
+pub struct APU
+{
+    model: Model,
+    powered: bool,
+    registers: [u8; NR52_ADDRESS - NR10_ADDRESS + 1],
+    wave_ram: [u8; WAVE_RAM_SIZE],
+    square1: SquareChannel,
+    sweep: Sweep,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    frame_sequencer_cycles: u32,
+    frame_sequencer_step: u8,
+    // Counts up by the sample rate every cycle, a sample is due whenever it passes the clock rate
+    sample_cycles: u32,
+    samples: Vec<f32>,
+}
+
+impl APU
+{
+    pub fn new(model: Model) -> Self
+    {
+        APU {
+            model,
+            powered: false,
+            registers: [0; NR52_ADDRESS - NR10_ADDRESS + 1],
+            wave_ram: [0; WAVE_RAM_SIZE],
+            square1: SquareChannel::new(),
+            sweep: Sweep::new(),
+            square2: SquareChannel::new(),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+            sample_cycles: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn read_register(&self, address: usize) -> u8
+    {
+        let index = address - NR10_ADDRESS;
+        if address == NR52_ADDRESS
+        {
+            return READ_MASKS[index]
+                | (self.powered as u8) << 7
+                | (self.noise.enabled as u8) << 3
+                | (self.wave.enabled as u8) << 2
+                | (self.square2.enabled as u8) << 1
+                | self.square1.enabled as u8;
+        }
+        READ_MASKS[index] | self.registers[index]
+    }
+
+    pub fn write_register(&mut self, address: usize, value: u8)
+    {
+        if address == NR52_ADDRESS
+        {
+            let powered = value & 0x80 != 0;
+            if self.powered && !powered
+            {
+                // Switching the APU off clears every register
+                *self = APU {
+                    wave_ram: self.wave_ram,
+                    sample_cycles: self.sample_cycles,
+                    samples: std::mem::take(&mut self.samples),
+                    ..APU::new(self.model)
+                };
+            }
+            else if !self.powered && powered
+            {
+                self.frame_sequencer_step = 0;
+            }
+            self.powered = powered;
+            return;
+        }
+        if !self.powered
+        {
+            return;
+        }
+
+        self.registers[address - NR10_ADDRESS] = value;
+        match address
+        {
+            NR10_ADDRESS => self.sweep.write(value),
+            NR11_ADDRESS =>
+            {
+                self.square1.duty = value >> 6;
+                self.square1.length.load(value & 0x3F);
+            }
+            NR12_ADDRESS =>
+            {
+                self.square1.envelope.write(value);
+                self.square1.enabled &= self.square1.envelope.dac_enabled();
+            }
+            NR13_ADDRESS =>
+            {
+                self.square1.frequency = (self.square1.frequency & 0x700) | value as u16
+            }
+            NR14_ADDRESS =>
+            {
+                self.square1.frequency =
+                    (self.square1.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                self.square1.length.enabled = value & LENGTH_ENABLE != 0;
+                if value & TRIGGER != 0
+                {
+                    self.square1.trigger();
+                    self.trigger_sweep();
+                }
+            }
+            NR21_ADDRESS =>
+            {
+                self.square2.duty = value >> 6;
+                self.square2.length.load(value & 0x3F);
+            }
+            NR22_ADDRESS =>
+            {
+                self.square2.envelope.write(value);
+                self.square2.enabled &= self.square2.envelope.dac_enabled();
+            }
+            NR23_ADDRESS =>
+            {
+                self.square2.frequency = (self.square2.frequency & 0x700) | value as u16
+            }
+            NR24_ADDRESS =>
+            {
+                self.square2.frequency =
+                    (self.square2.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                self.square2.length.enabled = value & LENGTH_ENABLE != 0;
+                if value & TRIGGER != 0
+                {
+                    self.square2.trigger();
+                }
+            }
+            NR30_ADDRESS =>
+            {
+                self.wave.dac_enabled = value & 0x80 != 0;
+                self.wave.enabled &= self.wave.dac_enabled;
+            }
+            NR31_ADDRESS => self.wave.length.load(value),
+            NR32_ADDRESS => self.wave.volume_code = (value >> 5) & 0x03,
+            NR33_ADDRESS => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            NR34_ADDRESS =>
+            {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                self.wave.length.enabled = value & LENGTH_ENABLE != 0;
+                if value & TRIGGER != 0
+                {
+                    self.wave.trigger();
+                }
+            }
+            NR41_ADDRESS => self.noise.length.load(value & 0x3F),
+            NR42_ADDRESS =>
+            {
+                self.noise.envelope.write(value);
+                self.noise.enabled &= self.noise.envelope.dac_enabled();
+            }
+            NR43_ADDRESS =>
+            {
+                self.noise.shift = value >> 4;
+                self.noise.width_7 = value & 0x08 != 0;
+                self.noise.divisor_code = value & 0x07;
+            }
+            NR44_ADDRESS =>
+            {
+                self.noise.length.enabled = value & LENGTH_ENABLE != 0;
+                if value & TRIGGER != 0
+                {
+                    self.noise.trigger();
+                }
+            }
+            _ =>
+            {}
+        }
+    }
+
+    /// Set a register without the side effects of writing it: nothing is triggered, lengths
+    /// aren't reloaded and switching the APU off doesn't clear it. The low bits of NR52 switch
+    /// the channels on and off.
+    pub fn poke_register(&mut self, address: usize, value: u8)
+    {
+        if address == NR52_ADDRESS
+        {
+            self.powered = value & 0x80 != 0;
+            self.noise.enabled = value & 0x08 != 0;
+            self.wave.enabled = value & 0x04 != 0;
+            self.square2.enabled = value & 0x02 != 0;
+            self.square1.enabled = value & 0x01 != 0;
+            return;
+        }
+
+        self.registers[address - NR10_ADDRESS] = value;
+        // Frequencies are split between two registers
+        let frequency = |registers: &[u8], low: usize| {
+            let index = low - NR10_ADDRESS;
+            ((registers[index + 1] & 0x07) as u16) << 8 | registers[index] as u16
+        };
+        match address
+        {
+            NR10_ADDRESS => self.sweep.write(value),
+            NR11_ADDRESS => self.square1.duty = value >> 6,
+            NR12_ADDRESS => self.square1.envelope.write(value),
+            NR13_ADDRESS | NR14_ADDRESS =>
+            {
+                self.square1.frequency = frequency(&self.registers, NR13_ADDRESS);
+                self.square1.length.enabled =
+                    self.registers[NR14_ADDRESS - NR10_ADDRESS] & LENGTH_ENABLE != 0;
+            }
+            NR21_ADDRESS => self.square2.duty = value >> 6,
+            NR22_ADDRESS => self.square2.envelope.write(value),
+            NR23_ADDRESS | NR24_ADDRESS =>
+            {
+                self.square2.frequency = frequency(&self.registers, NR23_ADDRESS);
+                self.square2.length.enabled =
+                    self.registers[NR24_ADDRESS - NR10_ADDRESS] & LENGTH_ENABLE != 0;
+            }
+            NR30_ADDRESS => self.wave.dac_enabled = value & 0x80 != 0,
+            NR32_ADDRESS => self.wave.volume_code = (value >> 5) & 0x03,
+            NR33_ADDRESS | NR34_ADDRESS =>
+            {
+                self.wave.frequency = frequency(&self.registers, NR33_ADDRESS);
+                self.wave.length.enabled =
+                    self.registers[NR34_ADDRESS - NR10_ADDRESS] & LENGTH_ENABLE != 0;
+            }
+            NR42_ADDRESS => self.noise.envelope.write(value),
+            NR43_ADDRESS =>
+            {
+                self.noise.shift = value >> 4;
+                self.noise.width_7 = value & 0x08 != 0;
+                self.noise.divisor_code = value & 0x07;
+            }
+            NR44_ADDRESS => self.noise.length.enabled = value & LENGTH_ENABLE != 0,
+            _ =>
+            {}
+        }
+    }
+
+    /// Wave RAM as it is stored, whether or not the wave channel is playing
+    pub fn peek_wave_ram(&self, address: usize) -> u8
+    {
+        self.wave_ram[address - WAVE_RAM_BEGIN]
+    }
+
+    pub fn poke_wave_ram(&mut self, address: usize, value: u8)
+    {
+        self.wave_ram[address - WAVE_RAM_BEGIN] = value;
+    }
+
+    /// While the wave channel is playing, wave RAM accesses go to the byte it is playing. The
+    /// CGB always allows this, the DMG only right as the channel fetches the byte and reads
+    /// 0xFF otherwise.
+    pub fn read_wave_ram(&self, address: usize) -> u8
+    {
+        if self.wave.enabled
+        {
+            return if self.model.is_cgb() || self.wave.fetched
+            {
+                self.wave_ram[self.wave.position / 2]
+            }
+            else
+            {
+                0xFF
+            };
+        }
+        self.wave_ram[address - WAVE_RAM_BEGIN]
+    }
+
+    pub fn write_wave_ram(&mut self, address: usize, value: u8)
+    {
+        if self.wave.enabled
+        {
+            if self.model.is_cgb() || self.wave.fetched
+            {
+                self.wave_ram[self.wave.position / 2] = value;
+            }
+            return;
+        }
+        self.wave_ram[address - WAVE_RAM_BEGIN] = value;
+    }
+
+    /// Advance by a number of cycles at the normal clock speed
+    pub fn step(&mut self, cycles: u8)
+    {
+        let cycles = cycles as u32;
+        // Samples keep coming while the APU is off so the output stays in time
+        self.sample_cycles += cycles * SAMPLE_RATE;
+        while self.sample_cycles >= CLOCK_RATE
+        {
+            self.sample_cycles -= CLOCK_RATE;
+            let sample = self.mix();
+            self.samples.extend_from_slice(&sample);
+        }
+
+        if !self.powered
+        {
+            return;
+        }
+
+        self.square1.step(cycles);
+        self.square2.step(cycles);
+        self.wave.step(cycles);
+        self.noise.step(cycles);
+
+        self.frame_sequencer_cycles += cycles;
+        if self.frame_sequencer_cycles >= FRAME_SEQUENCER_CYCLES
+        {
+            self.frame_sequencer_cycles -= FRAME_SEQUENCER_CYCLES;
+            self.clock_frame_sequencer();
+        }
+    }
+
+    /// Samples produced since the last call to `clear_samples`
+    pub fn samples(&self) -> &[f32]
+    {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self)
+    {
+        self.samples.clear();
+    }
+
+    /// Mix the channels into a left and right sample between -1 and 1
+    fn mix(&self) -> [f32; 2]
+    {
+        if !self.powered
+        {
+            return [0.0; 2];
+        }
+
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(&self.wave_ram),
+            self.noise.output(),
+        ];
+        let panning = self.registers[NR51_ADDRESS - NR10_ADDRESS];
+        let volume = self.registers[NR50_ADDRESS - NR10_ADDRESS];
+
+        let mut mixed = [0.0; 2];
+        for (channel, output) in outputs.iter().enumerate()
+        {
+            // Each DAC turns 0 to 15 into -1 to 1, a disabled DAC outputs nothing
+            let Some(output) = output
+            else
+            {
+                continue;
+            };
+            let analog = *output as f32 / 7.5 - 1.0;
+            // NR51 sends the channels to the right with its low bits and to the left with
+            // its high bits
+            if panning & (0x10 << channel) != 0
+            {
+                mixed[0] += analog;
+            }
+            if panning & (0x01 << channel) != 0
+            {
+                mixed[1] += analog;
+            }
+        }
+
+        let left_volume = ((volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (volume & 0x07) as f32 + 1.0;
+        [mixed[0] / 4.0 * left_volume / 8.0, mixed[1] / 4.0 * right_volume / 8.0]
+    }
+
+    fn clock_frame_sequencer(&mut self)
+    {
+        let step = self.frame_sequencer_step;
+        self.frame_sequencer_step = (step + 1) % 8;
+
+        if step.is_multiple_of(2)
+        {
+            if self.square1.length.clock()
+            {
+                self.square1.enabled = false;
+            }
+            if self.square2.length.clock()
+            {
+                self.square2.enabled = false;
+            }
+            if self.wave.length.clock()
+            {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock()
+            {
+                self.noise.enabled = false;
+            }
+        }
+        if step == 2 || step == 6
+        {
+            self.clock_sweep();
+        }
+        if step == 7
+        {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+    }
+
+    fn trigger_sweep(&mut self)
+    {
+        self.sweep.shadow_frequency = self.square1.frequency;
+        self.sweep.timer = if self.sweep.period == 0 { 8 } else { self.sweep.period };
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        // The overflow check happens straight away when the shift is set
+        if self.sweep.shift != 0 && self.sweep.next_frequency().is_none()
+        {
+            self.square1.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self)
+    {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0
+        {
+            return;
+        }
+        self.sweep.timer = if self.sweep.period == 0 { 8 } else { self.sweep.period };
+        if !self.sweep.enabled || self.sweep.period == 0
+        {
+            return;
+        }
+
+        match self.sweep.next_frequency()
+        {
+            Some(frequency) if self.sweep.shift != 0 =>
+            {
+                self.sweep.shadow_frequency = frequency;
+                self.square1.frequency = frequency;
+                // The new frequency is checked for overflow again without being applied
+                if self.sweep.next_frequency().is_none()
+                {
+                    self.square1.enabled = false;
+                }
+            }
+            Some(_) =>
+            {}
+            None => self.square1.enabled = false,
+        }
+    }
+}
+
+impl SaveState for Length
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.u16(self.counter);
+        writer.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.counter = reader.u16()?.min(self.maximum);
+        self.enabled = reader.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.u8(self.initial_volume);
+        writer.bool(self.increase);
+        writer.u8(self.period);
+        writer.u8(self.volume);
+        writer.u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.initial_volume = reader.u8()?;
+        self.increase = reader.bool()?;
+        self.period = reader.u8()?;
+        self.volume = reader.u8()?;
+        self.timer = reader.u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Sweep
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.u8(self.period);
+        writer.bool(self.negate);
+        writer.u8(self.shift);
+        writer.u8(self.timer);
+        writer.bool(self.enabled);
+        writer.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.period = reader.u8()?;
+        self.negate = reader.bool()?;
+        self.shift = reader.u8()?;
+        self.timer = reader.u8()?;
+        self.enabled = reader.bool()?;
+        self.shadow_frequency = reader.u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.u8(self.duty);
+        writer.u8(self.duty_position);
+        writer.u16(self.frequency);
+        writer.u32(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.enabled = reader.bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.duty = reader.u8()? & 0x03;
+        self.duty_position = reader.u8()? % 8;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.u32()?;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        self.length.save_state(writer);
+        writer.u8(self.volume_code);
+        writer.u16(self.frequency);
+        writer.u32(self.timer);
+        writer.usize(self.position);
+        writer.bool(self.fetched);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.length.load_state(reader)?;
+        self.volume_code = reader.u8()? & 0x03;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.u32()?;
+        self.position = reader.usize()? % (WAVE_RAM_SIZE * 2);
+        self.fetched = reader.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.u8(self.shift);
+        writer.bool(self.width_7);
+        writer.u8(self.divisor_code);
+        writer.u16(self.lfsr);
+        writer.u32(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.enabled = reader.bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.shift = reader.u8()? & 0x0F;
+        self.width_7 = reader.bool()?;
+        self.divisor_code = reader.u8()? & 0x07;
+        self.lfsr = reader.u16()?;
+        self.timer = reader.u32()?;
+        Ok(())
+    }
+}
+
+// Samples waiting to be played aren't part of the state
+impl SaveState for APU
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bool(self.powered);
+        writer.bytes(&self.registers);
+        writer.bytes(&self.wave_ram);
+        self.square1.save_state(writer);
+        self.sweep.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.u32(self.frame_sequencer_cycles);
+        writer.u8(self.frame_sequencer_step);
+        writer.u32(self.sample_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.powered = reader.bool()?;
+        reader.bytes(&mut self.registers)?;
+        reader.bytes(&mut self.wave_ram)?;
+        self.square1.load_state(reader)?;
+        self.sweep.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.frame_sequencer_cycles = reader.u32()? % FRAME_SEQUENCER_CYCLES;
+        self.frame_sequencer_step = reader.u8()? % 8;
+        self.sample_cycles = reader.u32()? % CLOCK_RATE;
+        Ok(())
+    }
+}
@@ -0,0 +1,638 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::crc::crc32;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const ROM_BEGIN: usize = 0x0000;
+pub const ROM_END: usize = 0x7FFF;
+pub const EXTERNAL_RAM_BEGIN: usize = 0xA000;
+pub const EXTERNAL_RAM_END: usize = 0xBFFF;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// Cartridge header locations
+const TITLE_BEGIN: usize = 0x134;
+const TITLE_END: usize = 0x143;
+const CGB_FLAG_ADDRESS: usize = 0x143;
+const NEW_LICENSEE_ADDRESS: usize = 0x144;
+const SGB_FLAG_ADDRESS: usize = 0x146;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x147;
+const RAM_SIZE_ADDRESS: usize = 0x149;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x14D;
+const OLD_LICENSEE_ADDRESS: usize = 0x14B;
+const HEADER_END: usize = 0x14F;
+
+// MBC3 real time clock registers, selected by writing 0x08-0x0C to 0x4000-0x5FFF
+const RTC_SECONDS: usize = 0x08;
+const RTC_DAY_HIGH: usize = 0x0C;
+// Bits of the day high register
+const RTC_DAY_MSB: u8 = 0x01;
+const RTC_HALT: u8 = 0x40;
+const RTC_DAY_CARRY: u8 = 0x80;
+// Valid bits of the seconds, minutes, hours, day low and day high registers
+const RTC_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, RTC_DAY_MSB | RTC_HALT | RTC_DAY_CARRY];
+// The clock crystal ticks at the same rate as the Game Boy's, even in double speed mode
+const RTC_CYCLES_PER_SECOND: u32 = 4_194_304;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MbcType
+{
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+/// The real time clock of MBC3 cartridges. It counts emulated time rather than wall clock time,
+/// so a game runs the same way every time it's given the same inputs.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Rtc
+{
+    // Seconds, minutes, hours, day low and day high, as counted
+    registers: [u8; 5],
+    // What the game reads, copied from the registers when it latches the clock
+    latched: [u8; 5],
+    cycles: u32,
+    // Latching takes a write of 0 and then 1
+    latch_armed: bool,
+}
+
+impl Rtc
+{
+    pub fn registers(&self) -> [u8; 5]
+    {
+        self.registers
+    }
+
+    /// Set the time, such as the starting time of a movie
+    pub fn set_registers(&mut self, registers: [u8; 5])
+    {
+        for (register, (value, mask)) in
+            self.registers.iter_mut().zip(registers.iter().zip(RTC_MASKS))
+        {
+            *register = value & mask;
+        }
+        self.latched = self.registers;
+        self.cycles = 0;
+    }
+
+    fn step(&mut self, cycles: u8)
+    {
+        if self.registers[4] & RTC_HALT != 0
+        {
+            return;
+        }
+        self.cycles += cycles as u32;
+        while self.cycles >= RTC_CYCLES_PER_SECOND
+        {
+            self.cycles -= RTC_CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+
+    /// Count a second. Out of range values count up to the limit of their bits and wrap to 0
+    /// without carrying, as on the real clock.
+    fn tick(&mut self)
+    {
+        let [seconds, minutes, hours, day_low, day_high] = &mut self.registers;
+        for (register, limit, mask) in [(seconds, 60, 0x3F), (minutes, 60, 0x3F), (hours, 24, 0x1F)]
+        {
+            *register = (*register + 1) & mask;
+            if *register != limit
+            {
+                return;
+            }
+            *register = 0;
+        }
+        let day = (*day_low as u16 | ((*day_high & RTC_DAY_MSB) as u16) << 8) + 1;
+        *day_low = day as u8;
+        *day_high = (*day_high & !RTC_DAY_MSB) | (day >> 8) as u8 & RTC_DAY_MSB;
+        // The day counter is 9 bits, and the carry stays set until the game clears it
+        if day == 0x200
+        {
+            *day_high |= RTC_DAY_CARRY;
+        }
+    }
+
+    fn latch(&mut self, value: u8)
+    {
+        if self.latch_armed && value == 0x01
+        {
+            self.latched = self.registers;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    fn read(&self, select: usize) -> u8
+    {
+        self.latched[select - RTC_SECONDS]
+    }
+
+    fn write(&mut self, select: usize, value: u8)
+    {
+        let index = select - RTC_SECONDS;
+        self.registers[index] = value & RTC_MASKS[index];
+        self.latched[index] = self.registers[index];
+        // Writing the seconds restarts the current second
+        if select == RTC_SECONDS
+        {
+            self.cycles = 0;
+        }
+    }
+}
+
+impl SaveState for Rtc
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bytes(&self.registers);
+        writer.bytes(&self.latched);
+        writer.u32(self.cycles);
+        writer.bool(self.latch_armed);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        reader.bytes(&mut self.registers)?;
+        reader.bytes(&mut self.latched)?;
+        self.cycles = reader.u32()?;
+        self.latch_armed = reader.bool()?;
+        Ok(())
+    }
+}
+
+pub struct Cartridge
+{
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: MbcType,
+    rom_bank: usize,
+    ram_bank: usize,
+    ram_enabled: bool,
+    // MBC1 only: selects whether the upper bank bits apply to RAM and the low ROM area
+    advanced_banking: bool,
+    // Only MBC3 cartridges with a timer have one
+    rtc: Option<Rtc>,
+    // Worked out once, as save states and rewind snapshots need it many times a second
+    rom_checksum: u32,
+}
+
+impl Cartridge
+{
+    pub fn load(path: &Path) -> io::Result<Self>
+    {
+        let mut file = File::open(path)?;
+        let mut rom = Vec::new();
+        file.read_to_end(&mut rom)?;
+        Self::new(rom)
+    }
+
+    pub fn new(rom: Vec<u8>) -> io::Result<Self>
+    {
+        if rom.len() <= HEADER_END
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ROM is too small for a header",
+            ));
+        }
+
+        let mbc = match rom[CARTRIDGE_TYPE_ADDRESS]
+        {
+            0x00 | 0x08 | 0x09 => MbcType::None,
+            0x01..=0x03 => MbcType::Mbc1,
+            0x05 | 0x06 => MbcType::Mbc2,
+            0x0F..=0x13 => MbcType::Mbc3,
+            0x19..=0x1E => MbcType::Mbc5,
+            cartridge_type =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("unsupported cartridge type 0x{:02x}", cartridge_type),
+                ))
+            }
+        };
+
+        let ram_size = match (mbc, rom[RAM_SIZE_ADDRESS])
+        {
+            // MBC2 has 512 half bytes of RAM built in
+            (MbcType::Mbc2, _) => 0x200,
+            (_, 0x02) => 0x2000,
+            (_, 0x03) => 0x8000,
+            (_, 0x04) => 0x20000,
+            (_, 0x05) => 0x10000,
+            _ => 0,
+        };
+
+        let rtc = matches!(rom[CARTRIDGE_TYPE_ADDRESS], 0x0F | 0x10).then(Rtc::default);
+
+        Ok(Cartridge {
+            rtc,
+            rom_checksum: crc32(&rom),
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            advanced_banking: false,
+        })
+    }
+
+    pub fn title(&self) -> String
+    {
+        let title = &self.rom[TITLE_BEGIN..TITLE_END];
+        let length = title.iter().position(|&byte| byte == 0).unwrap_or(title.len());
+        String::from_utf8_lossy(&title[..length]).trim().to_string()
+    }
+
+    /// True if the header marks the game as supporting Game Boy Color features
+    pub fn supports_cgb(&self) -> bool
+    {
+        self.rom[CGB_FLAG_ADDRESS] & 0x80 != 0
+    }
+
+    /// True if the header marks the game as supporting Super Game Boy functions. The flag is
+    /// ignored unless the header uses the new licensee code.
+    pub fn supports_sgb(&self) -> bool
+    {
+        self.rom[SGB_FLAG_ADDRESS] == 0x03 && self.rom[OLD_LICENSEE_ADDRESS] == 0x33
+    }
+
+    /// The full 16 byte title area, including the CGB flag which overlaps its last byte
+    pub fn title_bytes(&self) -> &[u8]
+    {
+        &self.rom[TITLE_BEGIN..=CGB_FLAG_ADDRESS]
+    }
+
+    /// Sum of the title bytes, which the CGB boot ROM uses to recognise Nintendo's monochrome
+    /// games
+    pub fn title_checksum(&self) -> u8
+    {
+        self.title_bytes().iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+    }
+
+    /// True if the header names Nintendo as the licensee, either directly or through the new
+    /// licensee code
+    pub fn licensed_by_nintendo(&self) -> bool
+    {
+        match self.rom[OLD_LICENSEE_ADDRESS]
+        {
+            0x01 => true,
+            0x33 => &self.rom[NEW_LICENSEE_ADDRESS..NEW_LICENSEE_ADDRESS + 2] == b"01",
+            _ => false,
+        }
+    }
+
+    pub fn header_checksum(&self) -> u8
+    {
+        self.rom[HEADER_CHECKSUM_ADDRESS]
+    }
+
+    /// CRC-32 of the whole ROM, which tells games apart far better than the header checksums.
+    /// It's of the ROM as loaded, so patching it while debugging doesn't make it another game.
+    pub fn rom_checksum(&self) -> u32
+    {
+        self.rom_checksum
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8
+    {
+        self.rom[self.rom_offset(address)]
+    }
+
+    /// Where in the ROM file the byte mapped at an address from 0x0000 to 0x7FFF comes from
+    pub fn rom_offset(&self, address: u16) -> usize
+    {
+        let offset = self.rom_bank_at(address) * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE);
+        offset % self.rom.len()
+    }
+
+    pub fn rom_size(&self) -> usize
+    {
+        self.rom.len()
+    }
+
+    /// Read from any ROM bank, mapped or not
+    pub fn peek_rom_bank(&self, bank: usize, address: u16) -> u8
+    {
+        self.rom[(bank * ROM_BANK_SIZE + address as usize % ROM_BANK_SIZE) % self.rom.len()]
+    }
+
+    /// Patch any ROM bank, for trying out changes to a game
+    pub fn poke_rom_bank(&mut self, bank: usize, address: u16, value: u8)
+    {
+        let length = self.rom.len();
+        self.rom[(bank * ROM_BANK_SIZE + address as usize % ROM_BANK_SIZE) % length] = value;
+    }
+
+    /// The ROM bank mapped at an address from 0x0000 to 0x7FFF
+    pub fn rom_bank_at(&self, address: u16) -> usize
+    {
+        let bank = match address
+        {
+            0x0000..=0x3FFF if self.mbc == MbcType::Mbc1 && self.advanced_banking =>
+            {
+                self.rom_bank & !0x1F
+            }
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank,
+        };
+        // Banks past the end of the ROM mirror the ones before
+        bank % (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    /// The external RAM bank mapped at 0xA000 to 0xBFFF
+    pub fn ram_bank(&self) -> usize
+    {
+        match self.mbc
+        {
+            MbcType::Mbc1 if !self.advanced_banking => 0,
+            MbcType::Mbc2 => 0,
+            MbcType::Mbc3 => self.ram_bank & 0x03,
+            _ => self.ram_bank,
+        }
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc>
+    {
+        self.rtc.as_ref()
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc>
+    {
+        self.rtc.as_mut()
+    }
+
+    /// Advance the real time clock by a number of cycles at the normal speed
+    pub fn step(&mut self, cycles: u8)
+    {
+        if let Some(rtc) = self.rtc.as_mut()
+        {
+            rtc.step(cycles);
+        }
+    }
+
+    /// The clock register selected in place of RAM, if any
+    fn rtc_select(&self) -> Option<usize>
+    {
+        let selected = self.mbc == MbcType::Mbc3
+            && self.ram_enabled
+            && self.rtc.is_some()
+            && (RTC_SECONDS..=RTC_DAY_HIGH).contains(&self.ram_bank);
+        selected.then_some(self.ram_bank)
+    }
+
+    /// Writes to the ROM area program the memory bank controller
+    pub fn write_rom(&mut self, address: u16, value: u8)
+    {
+        let address = address as usize;
+        match (self.mbc, address)
+        {
+            (MbcType::None, _) =>
+            {}
+            (MbcType::Mbc1, 0x0000..=0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (MbcType::Mbc1, 0x2000..=0x3FFF) =>
+            {
+                // Bank 0 can't be selected in the switchable area, 0 maps to 1 instead
+                let low = (value & 0x1F).max(1) as usize;
+                self.rom_bank = (self.rom_bank & !0x1F) | low;
+            }
+            (MbcType::Mbc1, 0x4000..=0x5FFF) =>
+            {
+                let high = (value & 0x03) as usize;
+                self.rom_bank = (self.rom_bank & 0x1F) | (high << 5);
+                self.ram_bank = high;
+            }
+            (MbcType::Mbc1, 0x6000..=0x7FFF) => self.advanced_banking = value & 0x01 != 0,
+            (MbcType::Mbc2, 0x0000..=0x3FFF) =>
+            {
+                // Address bit 8 selects between the RAM enable and ROM bank registers
+                if address & 0x100 == 0
+                {
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                }
+                else
+                {
+                    self.rom_bank = (value & 0x0F).max(1) as usize;
+                }
+            }
+            (MbcType::Mbc3, 0x0000..=0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (MbcType::Mbc3, 0x2000..=0x3FFF) => self.rom_bank = (value & 0x7F).max(1) as usize,
+            // 0x00-0x03 select a RAM bank and 0x08-0x0C a clock register
+            (MbcType::Mbc3, 0x4000..=0x5FFF) => self.ram_bank = (value & 0x0F) as usize,
+            (MbcType::Mbc3, 0x6000..=0x7FFF) =>
+            {
+                if let Some(rtc) = self.rtc.as_mut()
+                {
+                    rtc.latch(value);
+                }
+            }
+            (MbcType::Mbc5, 0x0000..=0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (MbcType::Mbc5, 0x2000..=0x2FFF) =>
+            {
+                self.rom_bank = (self.rom_bank & 0x100) | value as usize;
+            }
+            (MbcType::Mbc5, 0x3000..=0x3FFF) =>
+            {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((value as usize & 0x01) << 8);
+            }
+            (MbcType::Mbc5, 0x4000..=0x5FFF) => self.ram_bank = (value & 0x0F) as usize,
+            _ =>
+            {}
+        }
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8
+    {
+        if let (Some(select), Some(rtc)) = (self.rtc_select(), self.rtc.as_ref())
+        {
+            return rtc.read(select);
+        }
+        match self.ram_offset(address)
+        {
+            // MBC2 RAM only stores the low nibble
+            Some(offset) if self.mbc == MbcType::Mbc2 => 0xF0 | self.ram[offset],
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8)
+    {
+        if let Some(select) = self.rtc_select()
+        {
+            if let Some(rtc) = self.rtc.as_mut()
+            {
+                rtc.write(select, value);
+            }
+            return;
+        }
+        if let Some(offset) = self.ram_offset(address)
+        {
+            self.ram[offset] = if self.mbc == MbcType::Mbc2 { value & 0x0F } else { value };
+        }
+    }
+
+    /// Read from any RAM bank, even while the RAM is disabled
+    pub fn peek_ram_bank(&self, bank: usize, address: u16) -> u8
+    {
+        if self.ram.is_empty()
+        {
+            return 0xFF;
+        }
+        let offset =
+            (bank * RAM_BANK_SIZE + address as usize - EXTERNAL_RAM_BEGIN) % self.ram.len();
+        match self.mbc
+        {
+            MbcType::Mbc2 => 0xF0 | self.ram[offset],
+            _ => self.ram[offset],
+        }
+    }
+
+    /// Write to any RAM bank, even while the RAM is disabled
+    pub fn poke_ram_bank(&mut self, bank: usize, address: u16, value: u8)
+    {
+        if self.ram.is_empty()
+        {
+            return;
+        }
+        let offset =
+            (bank * RAM_BANK_SIZE + address as usize - EXTERNAL_RAM_BEGIN) % self.ram.len();
+        self.ram[offset] = if self.mbc == MbcType::Mbc2 { value & 0x0F } else { value };
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize>
+    {
+        if !self.ram_enabled || self.ram.is_empty()
+        {
+            return None;
+        }
+
+        let address = address as usize - EXTERNAL_RAM_BEGIN;
+        Some((self.ram_bank() * RAM_BANK_SIZE + address) % self.ram.len())
+    }
+}
+
+// The ROM itself isn't part of a state, only what the game can change
+impl SaveState for Cartridge
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.vec(&self.ram);
+        writer.usize(self.rom_bank);
+        writer.usize(self.ram_bank);
+        writer.bool(self.ram_enabled);
+        writer.bool(self.advanced_banking);
+        if let Some(rtc) = self.rtc.as_ref()
+        {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        reader.vec_into(&mut self.ram)?;
+        self.rom_bank = reader.usize()?;
+        self.ram_bank = reader.usize()?;
+        self.ram_enabled = reader.bool()?;
+        self.advanced_banking = reader.bool()?;
+        if let Some(rtc) = self.rtc.as_mut()
+        {
+            rtc.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn mbc3_with_timer() -> Cartridge
+    {
+        let mut rom = vec![0; ROM_BANK_SIZE * 2];
+        rom[CARTRIDGE_TYPE_ADDRESS] = 0x10;
+        rom[RAM_SIZE_ADDRESS] = 0x03;
+        let mut cartridge = Cartridge::new(rom).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge
+    }
+
+    fn read_clock(cartridge: &mut Cartridge) -> [u8; 5]
+    {
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        (RTC_SECONDS..=RTC_DAY_HIGH)
+            .map(|select| {
+                cartridge.write_rom(0x4000, select as u8);
+                cartridge.read_ram(0xA000)
+            })
+            .collect::<Vec<u8>>()
+            .try_into()
+            .unwrap()
+    }
+
+    fn run_seconds(cartridge: &mut Cartridge, seconds: u32)
+    {
+        for _ in 0..seconds * RTC_CYCLES_PER_SECOND / 128
+        {
+            cartridge.step(128);
+        }
+    }
+
+    #[test]
+    fn clock_counts_emulated_time()
+    {
+        let mut cartridge = mbc3_with_timer();
+        cartridge.rtc_mut().unwrap().set_registers([58, 59, 23, 0xFF, RTC_DAY_MSB]);
+        run_seconds(&mut cartridge, 3);
+        assert_eq!(read_clock(&mut cartridge), [1, 0, 0, 0, RTC_DAY_CARRY]);
+    }
+
+    #[test]
+    fn reads_are_latched()
+    {
+        let mut cartridge = mbc3_with_timer();
+        assert_eq!(read_clock(&mut cartridge)[0], 0);
+        run_seconds(&mut cartridge, 2);
+        cartridge.write_rom(0x4000, RTC_SECONDS as u8);
+        assert_eq!(cartridge.read_ram(0xA000), 0);
+        assert_eq!(read_clock(&mut cartridge)[0], 2);
+    }
+
+    #[test]
+    fn halted_clock_stops_and_ram_stays_separate()
+    {
+        let mut cartridge = mbc3_with_timer();
+        cartridge.write_rom(0x4000, RTC_DAY_HIGH as u8);
+        cartridge.write_ram(0xA000, RTC_HALT);
+        run_seconds(&mut cartridge, 2);
+        assert_eq!(read_clock(&mut cartridge), [0, 0, 0, 0, RTC_HALT]);
+
+        cartridge.write_rom(0x4000, 0x00);
+        cartridge.write_ram(0xA000, 0x42);
+        assert_eq!(cartridge.read_ram(0xA000), 0x42);
+        cartridge.write_rom(0x4000, RTC_SECONDS as u8);
+        assert_eq!(cartridge.read_ram(0xA000), 0);
+    }
+
+    #[test]
+    fn clock_is_saved_in_states()
+    {
+        let mut cartridge = mbc3_with_timer();
+        run_seconds(&mut cartridge, 5);
+        let mut writer = StateWriter::new();
+        cartridge.save_state(&mut writer);
+        let state = writer.into_bytes();
+
+        let mut restored = mbc3_with_timer();
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.rtc(), cartridge.rtc());
+    }
+}
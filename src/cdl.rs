@@ -0,0 +1,103 @@
+use std::cell::Cell;
+use std::io;
+use std::path::Path;
+
+/// The first byte of an instruction, including the 0xCB prefix and the opcode after it
+pub const OPCODE: u8 = 0x01;
+/// An immediate value or address following an opcode
+pub const OPERAND: u8 = 0x02;
+/// Read by an instruction, such as a table, text or graphics copied by the CPU
+pub const DATA: u8 = 0x04;
+/// Copied into OAM or VRAM by DMA
+pub const DMA_SOURCE: u8 = 0x08;
+
+/// Code/data log, which marks how every byte of the ROM has been used, for telling code from
+/// data when disassembling a game.
+///
+/// The file holds one byte of flags for every byte of the ROM, so logs from different sessions
+/// are merged by or-ing them together. Bytes are marked as the game reads them, which only
+/// borrows the memory bus, so the flags are cells.
+pub struct CodeDataLog
+{
+    flags: Vec<Cell<u8>>,
+}
+
+impl CodeDataLog
+{
+    pub fn new(rom_size: usize) -> Self
+    {
+        CodeDataLog { flags: vec![Cell::new(0); rom_size] }
+    }
+
+    /// Continue the log saved for a ROM, or start an empty one if there isn't one yet
+    pub fn load(path: &Path, rom_size: usize) -> io::Result<Self>
+    {
+        let mut log = Self::new(rom_size);
+        match std::fs::read(path)
+        {
+            Ok(saved) => log.merge(&saved)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound =>
+            {}
+            Err(error) => return Err(error),
+        }
+        Ok(log)
+    }
+
+    /// Add the flags from another log of the same ROM
+    pub fn merge(&mut self, saved: &[u8]) -> io::Result<()>
+    {
+        if saved.len() != self.flags.len()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("log covers {} bytes but the ROM has {}", saved.len(), self.flags.len()),
+            ));
+        }
+        for (flags, &saved) in self.flags.iter().zip(saved)
+        {
+            flags.set(flags.get() | saved);
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>
+    {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        self.flags.iter().map(Cell::get).collect()
+    }
+
+    /// Mark a byte of the ROM, given as its offset in the file
+    pub fn mark(&self, offset: usize, flags: u8)
+    {
+        if let Some(byte) = self.flags.get(offset)
+        {
+            byte.set(byte.get() | flags);
+        }
+    }
+
+    pub fn flags(&self, offset: usize) -> u8
+    {
+        self.flags.get(offset).map_or(0, Cell::get)
+    }
+
+    /// How many bytes have been run as code, read as data, and used at all
+    pub fn coverage(&self) -> (usize, usize, usize)
+    {
+        let count = |mask: u8| self.flags.iter().filter(|flags| flags.get() & mask != 0).count();
+        (count(OPCODE | OPERAND), count(DATA | DMA_SOURCE), count(0xFF))
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.flags.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.flags.is_empty()
+    }
+}
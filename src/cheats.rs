@@ -0,0 +1,328 @@
+use std::io;
+use std::path::Path;
+
+// GameShark bank bytes with the top bit set pick the RAM bank in their low nibble
+const GAME_SHARK_BANKED: u8 = 0x80;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum CheatKind
+{
+    /// Replaces a byte of ROM as the game reads it, but only where the ROM holds `compare`, so
+    /// the code only affects the bank it was made for
+    GameGenie
+    {
+        address: u16, value: u8, compare: Option<u8>
+    },
+    /// Writes a byte of RAM at the start of every VBlank
+    GameShark
+    {
+        bank: u8, address: u16, value: u8
+    },
+}
+
+/// A Game Genie or GameShark code, which can be turned off without removing it
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cheat
+{
+    code: String,
+    pub description: String,
+    pub enabled: bool,
+    kind: CheatKind,
+}
+
+impl Cheat
+{
+    /// Decode a Game Genie code, `ABC-DEF` or `ABC-DEF-GHI`, or an 8 digit GameShark code.
+    ///
+    /// Game Genie codes replace ROM address `FCDE` with `AB`, where F is inverted. The last
+    /// group holds the byte to compare against in G and I, rotated and xored, and H is unused.
+    /// GameShark codes `ABCDEFGH` write `CD` to `GHEF` in RAM. `AB` is usually 01, which writes
+    /// to whatever bank is mapped there, while 8X and 9X write to bank X.
+    pub fn parse(code: &str, description: &str) -> Result<Self, String>
+    {
+        let code = code.trim().to_uppercase();
+        let digits: Vec<u8> = code
+            .chars()
+            .filter(|&character| character != '-')
+            .map(|character| character.to_digit(16).map(|digit| digit as u8))
+            .collect::<Option<_>>()
+            .ok_or_else(|| format!("{} isn't a Game Genie or GameShark code", code))?;
+        let byte = |index: usize| digits[index] << 4 | digits[index + 1];
+        let dashes = code.matches('-').count();
+
+        let kind = match digits.len()
+        {
+            6 | 9 if dashes == digits.len() / 3 - 1 =>
+            {
+                let address = ((digits[5] ^ 0xF) as u16) << 12
+                    | (digits[2] as u16) << 8
+                    | (digits[3] as u16) << 4
+                    | digits[4] as u16;
+                if address > 0x7FFF
+                {
+                    return Err(format!("{} doesn't patch ROM", code));
+                }
+                let compare = (digits.len() == 9)
+                    .then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+                CheatKind::GameGenie { address, value: byte(0), compare }
+            }
+            8 if dashes == 0 =>
+            {
+                let address = (byte(6) as u16) << 8 | byte(4) as u16;
+                if address < 0x8000
+                {
+                    return Err(format!("{} doesn't write to RAM", code));
+                }
+                CheatKind::GameShark { bank: byte(0), address, value: byte(2) }
+            }
+            _ => return Err(format!("{} isn't a Game Genie or GameShark code", code)),
+        };
+        Ok(Cheat { code, description: description.trim().to_string(), enabled: true, kind })
+    }
+
+    pub fn code(&self) -> &str
+    {
+        &self.code
+    }
+}
+
+/// The cheats for a game, which the memory bus applies while `active` is set.
+///
+/// Cheat list files have a cheat on each line, starting with `+` if it's enabled or `-` if not,
+/// then the code and a description. Lines starting with `#` are comments.
+pub struct Cheats
+{
+    cheats: Vec<Cheat>,
+    pub active: bool,
+    // Set when the list changes, so it only gets saved if there's something new
+    modified: bool,
+}
+
+impl Default for Cheats
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Cheats
+{
+    pub fn new() -> Self
+    {
+        Cheats { cheats: Vec::new(), active: true, modified: false }
+    }
+
+    /// Load a cheat list, or start an empty one if there isn't one yet
+    pub fn load(path: &Path) -> io::Result<Self>
+    {
+        match std::fs::read_to_string(path)
+        {
+            Ok(text) => Self::parse(&text)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String>
+    {
+        let mut cheats = Cheats::new();
+        for (number, line) in text.lines().enumerate()
+        {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#')
+            {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            let enabled = match line.chars().next()
+            {
+                Some('+') => true,
+                Some('-') => false,
+                _ => return Err(error("cheats must start with + or -".to_string())),
+            };
+            let line = line[1..].trim_start();
+            let (code, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let mut cheat = Cheat::parse(code, description).map_err(error)?;
+            cheat.enabled = enabled;
+            cheats.cheats.push(cheat);
+        }
+        Ok(cheats)
+    }
+
+    pub fn save(&mut self, path: &Path) -> io::Result<()>
+    {
+        let lines: Vec<String> = self
+            .cheats
+            .iter()
+            .map(|cheat| {
+                let enabled = if cheat.enabled { '+' } else { '-' };
+                format!("{} {} {}", enabled, cheat.code, cheat.description).trim_end().to_string()
+            })
+            .collect();
+        std::fs::write(path, lines.join("\n") + "\n")?;
+        self.modified = false;
+        Ok(())
+    }
+
+    pub fn is_modified(&self) -> bool
+    {
+        self.modified
+    }
+
+    pub fn cheats(&self) -> &[Cheat]
+    {
+        &self.cheats
+    }
+
+    /// Add a code, enabled. Returns its index.
+    pub fn add(&mut self, code: &str, description: &str) -> Result<usize, String>
+    {
+        self.cheats.push(Cheat::parse(code, description)?);
+        self.modified = true;
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat>
+    {
+        if index >= self.cheats.len()
+        {
+            return None;
+        }
+        self.modified = true;
+        Some(self.cheats.remove(index))
+    }
+
+    /// Turn a cheat on or off. Returns false if there's no cheat at the index.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool
+    {
+        let Some(cheat) = self.cheats.get_mut(index)
+        else
+        {
+            return false;
+        };
+        cheat.enabled = enabled;
+        self.modified = true;
+        true
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = &CheatKind>
+    {
+        self.cheats.iter().filter(|cheat| self.active && cheat.enabled).map(|cheat| &cheat.kind)
+    }
+
+    /// What the game reads from ROM, which is `value` unless a Game Genie code replaces it
+    pub fn read_rom(&self, address: u16, value: u8) -> u8
+    {
+        self.enabled()
+            .find_map(|kind| match *kind
+            {
+                CheatKind::GameGenie { address: patched, value: replacement, compare }
+                    if patched == address && compare.is_none_or(|compare| compare == value) =>
+                {
+                    Some(replacement)
+                }
+                _ => None,
+            })
+            .unwrap_or(value)
+    }
+
+    /// The RAM writes of the enabled GameShark codes, as an optional bank, address and value
+    pub fn ram_writes(&self) -> impl Iterator<Item = (Option<usize>, u16, u8)> + '_
+    {
+        self.enabled().filter_map(|kind| match *kind
+        {
+            CheatKind::GameShark { bank, address, value } =>
+            {
+                let bank = (bank & GAME_SHARK_BANKED != 0).then_some((bank & 0x0F) as usize);
+                Some((bank, address, value))
+            }
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn kind(code: &str) -> CheatKind
+    {
+        Cheat::parse(code, "").unwrap().kind
+    }
+
+    #[test]
+    fn game_genie_codes_decode()
+    {
+        // The address has its top nibble inverted, and the compare byte is rotated right by 2
+        // and xored with 0xBA
+        assert_eq!(kind("00A-17B-C49"), CheatKind::GameGenie {
+            address: 0x4A17,
+            value: 0x00,
+            compare: Some(0xC8)
+        });
+        assert_eq!(kind("00a-17b"), CheatKind::GameGenie {
+            address: 0x4A17,
+            value: 0x00,
+            compare: None
+        });
+        // F inverts to 0 here, which leaves the address in the first bank
+        assert_eq!(kind("3EB-0DF"), CheatKind::GameGenie {
+            address: 0x0B0D,
+            value: 0x3E,
+            compare: None
+        });
+    }
+
+    #[test]
+    fn game_shark_codes_decode()
+    {
+        // Pokemon Red and Blue's money
+        assert_eq!(kind("019947D3"), CheatKind::GameShark {
+            bank: 0x01,
+            address: 0xD347,
+            value: 0x99
+        });
+        assert_eq!(kind("92FF00D0"), CheatKind::GameShark {
+            bank: 0x92,
+            address: 0xD000,
+            value: 0xFF
+        });
+    }
+
+    #[test]
+    fn bad_codes_are_rejected()
+    {
+        // A high nibble of 0 inverts to F, outside ROM
+        assert!(Cheat::parse("000-000", "").is_err());
+        assert!(Cheat::parse("00A17B", "").is_err());
+        assert!(Cheat::parse("00A-17B-C4", "").is_err());
+        assert!(Cheat::parse("01994700", "").is_err());
+        assert!(Cheat::parse("0199G7D3", "").is_err());
+    }
+
+    #[test]
+    fn game_genie_only_replaces_the_compared_byte()
+    {
+        let mut cheats = Cheats::parse("+ 00A-17B-C49 Compared\n+ 3EB-0DF Always\n").unwrap();
+        assert_eq!(cheats.read_rom(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.read_rom(0x4A17, 0xC9), 0xC9);
+        assert_eq!(cheats.read_rom(0x4A18, 0xC8), 0xC8);
+        assert_eq!(cheats.read_rom(0x0B0D, 0x12), 0x3E);
+
+        cheats.set_enabled(1, false);
+        assert_eq!(cheats.read_rom(0x0B0D, 0x12), 0x12);
+        cheats.active = false;
+        assert_eq!(cheats.read_rom(0x4A17, 0xC8), 0xC8);
+    }
+
+    #[test]
+    fn game_shark_bank_byte_picks_the_bank()
+    {
+        let cheats = Cheats::parse("+019947D3\n+92FF00D0\n-913F01D0\n").unwrap();
+        let writes: Vec<_> = cheats.ram_writes().collect();
+        assert_eq!(writes, vec![(None, 0xD347, 0x99), (Some(2), 0xD000, 0xFF)]);
+    }
+}
@@ -1,17 +1,32 @@
-mod registers;
-use pixels::wgpu::TextureSampleType;
-
-use crate::cpu::registers::Registers;
-
-mod memorybus;
+pub mod registers;
+use std::io;
+
+use crate::cartridge::Cartridge;
+use crate::cpu::registers::{FlagsRegister, Registers};
+use crate::model::Model;
+use crate::profiler::Profiler;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub mod disassembler;
+mod hdma;
+pub mod memorybus;
 use crate::cpu::memorybus::MemoryBus;
 
-mod instruction;
+pub mod instruction;
 use crate::cpu::instruction::{
     ADDHLTarget, ArithmeticTarget, ArithmeticTarget16, IncDec16Target, Indirect, Instruction,
     JumpTest, LoadByteSource, LoadByteTarget, LoadType, LoadWordTarget, StackTarget,
 };
 
+const VBLANK_VECTOR: u16 = 0x40;
+const LCDSTAT_VECTOR: u16 = 0x48;
+const TIMER_VECTOR: u16 = 0x50;
+const SERIAL_VECTOR: u16 = 0x58;
+const JOYPAD_VECTOR: u16 = 0x60;
+
+// Dispatching an interrupt pushes pc and jumps, which takes 5 machine cycles
+const INTERRUPT_CYCLES: u8 = 20;
+
 pub struct CPU
 {
     pub registers: Registers,
@@ -21,38 +36,95 @@ pub struct CPU
     is_halted: bool,
     inst_count: u16,
     interrupts_enabled: bool,
+    branch_taken: bool,
+    pub profiler: Option<Profiler>,
 }
 
 impl CPU
 {
-    pub fn new(boot_rom: Vec<u8>) -> Self
+    pub fn new(model: Model, boot_rom: Vec<u8>, cartridge: Option<Cartridge>) -> Self
     {
         CPU {
             registers: Registers::new(0),
             pc: 0,
             sp: 0,
-            bus: MemoryBus::new(boot_rom),
+            bus: MemoryBus::new(model, boot_rom, cartridge),
             is_halted: false,
             inst_count: 0,
-            interrupts_enabled: true,
+            interrupts_enabled: false,
+            branch_taken: false,
+            profiler: None,
         }
     }
 
-    pub fn step(&mut self)
+    /// Start the game at 0x0100 with the registers the boot ROM leaves behind
+    pub fn skip_boot_rom(&mut self)
+    {
+        let model = self.bus.model();
+        let cgb_game = self.bus.cartridge().is_some_and(|cartridge| cartridge.supports_cgb());
+        // The DMG boot ROM finishes on a comparison that sets the half carry and carry flags
+        // unless the header checksum is 0
+        let header_checksum =
+            self.bus.cartridge().map_or(0, |cartridge| cartridge.header_checksum());
+        let dmg_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        // For a monochrome game from Nintendo the CGB boot ROM leaves the title checksum it
+        // picked the palette with in B, and HL pointing into the tile map
+        let (title_checksum, map_h, map_l) = match self.bus.cartridge()
+        {
+            Some(cartridge) if cartridge.licensed_by_nintendo() =>
+            {
+                (cartridge.title_checksum(), 0x99, 0x1A)
+            }
+            _ => (0x00, 0x00, 0x7C),
+        };
+        // The AGB boot ROM increments B before handing over, which sets the flags to match
+        let agb_b = title_checksum.wrapping_add(1);
+        let agb_flags = if agb_b == 0 { 0x80 } else { 0x00 }
+            | if title_checksum & 0xF == 0xF { 0x20 } else { 0x00 };
+
+        let [a, f, b, c, d, e, h, l] = match model
+        {
+            Model::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::Dmg => [0x01, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Mgb => [0xFF, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Sgb2 => [0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Cgb if cgb_game => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Cgb => [0x11, 0x80, title_checksum, 0x00, 0x00, 0x08, map_h, map_l],
+            Model::Agb if cgb_game => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Agb => [0x11, agb_flags, agb_b, 0x00, 0x00, 0x08, map_h, map_l],
+        };
+        self.registers = Registers { a, f: FlagsRegister::from(f), b, c, d, e, h, l };
+        self.pc = 0x0100;
+        self.sp = 0xFFFE;
+        self.bus.skip_boot_rom();
+    }
+
+    /// True if interrupts are enabled by IME
+    pub fn interrupts_enabled(&self) -> bool
+    {
+        self.interrupts_enabled
+    }
+
+    pub fn is_halted(&self) -> bool
+    {
+        self.is_halted
+    }
+
+    pub fn step(&mut self) -> u32
     {
-        let mut instruction_byte = self.bus.read_byte(self.pc);
-        println!(
-            "instruction_byte = 0x{:x}, instruction count {}, pc {}",
-            instruction_byte, self.inst_count, self.pc
-        );
+        let start = (self.bus.bank_at(self.pc), self.pc);
+        let start_sp = self.sp;
+        let mut instruction_byte = self.bus.fetch_opcode(self.pc);
         let prefixed = instruction_byte == 0xCB;
         if prefixed
         {
-            instruction_byte = self.bus.read_byte(self.pc + 1);
+            instruction_byte = self.bus.fetch_opcode(self.pc + 1);
             // Skip the next pc as it is read here
             self.pc += 1;
         }
 
+        self.branch_taken = false;
         let next_pc = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed)
         {
             self.execute(instruction)
@@ -65,38 +137,93 @@ impl CPU
         };
         self.inst_count = self.inst_count.wrapping_add(1);
 
+        let mut cycles = Instruction::cycles(instruction_byte, prefixed);
+        if !prefixed && self.branch_taken
+        {
+            cycles += Instruction::branch_taken_cycles(instruction_byte);
+        }
+
         //println!("The current pc is {}", self.pc);
         //println!("The next pc is {}", next_pc);
 
-        // To implement
-        //if self.bus.has_interrupt()
-        //{
-        //    self.is_halted = false;
-        //}
+        if self.bus.has_interrupt()
+        {
+            self.is_halted = false;
+        }
         if !self.is_halted
         {
             self.pc = next_pc;
         }
 
-        let mut interrupted = false;
+        self.bus.step(cycles);
+
+        if let Some(profiler) = self.profiler.as_mut()
+        {
+            // Only calls that pushed a return address can be returned from
+            let call = !prefixed
+                && (Instruction::is_call(instruction_byte)
+                    || Instruction::is_rst(instruction_byte))
+                && self.sp == start_sp.wrapping_sub(2);
+            let called = call.then(|| (self.bus.bank_at(self.pc), self.pc));
+            profiler.instruction(start, cycles as u32, self.sp, called);
+        }
+
         if self.interrupts_enabled
         {
-            //if self.bus.interrupt_enable.vblank && self.bus.interrupt_flag.vblank {
-            //    interrupted = true;
-            //    self.bus.interrupt_flag.vblank = false;
-            //    self.interrupt(VBLANK_VECTOR)
-            //}
-            //if self.bus.interrupt_enable.lcdstat && self.bus.interrupt_flag.lcdstat {
-            //    interrupted = true;
-            //    self.bus.interrupt_flag.lcdstat = false;
-            //    self.interrupt(LCDSTAT_VECTOR)
-            //}
-            //if self.bus.interrupt_enable.timer && self.bus.interrupt_flag.timer {
-            //    interrupted = true;
-            //    self.bus.interrupt_flag.timer = false;
-            //    self.interrupt(TIMER_VECTOR)
-            //}
+            let enabled = self.bus.interrupt_enable;
+            let requested = &mut self.bus.interrupt_flag;
+            let vector = if enabled.vblank && requested.vblank
+            {
+                requested.vblank = false;
+                Some(VBLANK_VECTOR)
+            }
+            else if enabled.lcdstat && requested.lcdstat
+            {
+                requested.lcdstat = false;
+                Some(LCDSTAT_VECTOR)
+            }
+            else if enabled.timer && requested.timer
+            {
+                requested.timer = false;
+                Some(TIMER_VECTOR)
+            }
+            else if enabled.serial && requested.serial
+            {
+                requested.serial = false;
+                Some(SERIAL_VECTOR)
+            }
+            else if enabled.joypad && requested.joypad
+            {
+                requested.joypad = false;
+                Some(JOYPAD_VECTOR)
+            }
+            else
+            {
+                None
+            };
+
+            if let Some(location) = vector
+            {
+                self.interrupt(location);
+                self.bus.step(INTERRUPT_CYCLES);
+                cycles += INTERRUPT_CYCLES;
+                if let Some(profiler) = self.profiler.as_mut()
+                {
+                    profiler.interrupt((0, location), self.sp);
+                    profiler.add_cycles(INTERRUPT_CYCLES as u32);
+                }
+            }
         }
+
+        // A VRAM DMA started by this instruction stalls the CPU while everything else runs on
+        cycles as u32 + self.bus.step_dma_stall()
+    }
+
+    fn interrupt(&mut self, location: u16)
+    {
+        self.interrupts_enabled = false;
+        self.push(self.pc);
+        self.pc = location;
     }
 
     fn get_arithmetic_target_value(&self, target: ArithmeticTarget) -> Option<u8>
@@ -159,6 +286,28 @@ impl CPU
             {
                 self.is_halted = true;
             }
+            Instruction::STOP() =>
+            {
+                // On the CGB STOP performs a speed switch if one has been armed through KEY1.
+                // Otherwise the CPU sleeps until it is woken up.
+                if self.bus.speed_switch_armed()
+                {
+                    self.bus.switch_speed();
+                }
+                else
+                {
+                    self.is_halted = true;
+                }
+                pc_increment = 2;
+            }
+            Instruction::DI() =>
+            {
+                self.interrupts_enabled = false;
+            }
+            Instruction::EI() =>
+            {
+                self.interrupts_enabled = true;
+            }
             Instruction::CALL(test) =>
             {
                 let jump_condition = match test
@@ -168,20 +317,17 @@ impl CPU
                     JumpTest::Zero => self.registers.f.zero,
                     JumpTest::Carry => self.registers.f.carry,
                     JumpTest::Always => true,
-                    _ =>
-                    {
-                        panic!("TODO: support more conditions")
-                    }
                 };
-                self.call(jump_condition);
+                return self.call(jump_condition);
             }
             Instruction::RETI() =>
             {
                 self.interrupts_enabled = true;
-                self.pop();
+                return self.pop();
             }
             Instruction::RST(location) =>
             {
+                self.push(self.pc.wrapping_add(1));
                 return location.to_hex();
             }
             Instruction::RET(test) =>
@@ -189,10 +335,10 @@ impl CPU
                 let jump_condition = match test
                 {
                     JumpTest::NotZero => !self.registers.f.zero,
-                    _ =>
-                    {
-                        panic!("TODO: support more conditions")
-                    }
+                    JumpTest::NotCarry => !self.registers.f.carry,
+                    JumpTest::Zero => self.registers.f.zero,
+                    JumpTest::Carry => self.registers.f.carry,
+                    JumpTest::Always => true,
                 };
                 return self.return_(jump_condition);
             }
@@ -306,6 +452,7 @@ impl CPU
                         Indirect::WordIndirect =>
                         {
                             let word = self.read_next_word();
+                            pc_increment = 3;
                             self.bus.read_byte(word)
                         }
                         Indirect::LastByteIndirect =>
@@ -345,7 +492,8 @@ impl CPU
                     Indirect::WordIndirect =>
                     {
                         let word = self.read_next_word();
-                        self.bus.write_byte(word, self.registers.a)
+                        self.bus.write_byte(word, self.registers.a);
+                        pc_increment = 3;
                     }
                     Indirect::LastByteIndirect =>
                     {
@@ -566,15 +714,10 @@ impl CPU
 
             Instruction::INC(target) =>
             {
-                if let Some(value) = self.get_arithmetic_target_mut(target)
+                if let Some(value) = self.get_arithmetic_target_value(target)
                 {
-                    let initial = *value;
-                    let result = initial + 1;
-                    *value = result;
-
-                    self.registers.f.zero = result == 0;
-                    self.registers.f.subtract = false;
-                    self.registers.f.half_carry = (initial & 0xF) + 1 > 0xF;
+                    let result = self.inc(value);
+                    *self.get_arithmetic_target_mut(target).unwrap() = result;
                 }
                 else
                 {
@@ -609,9 +752,11 @@ impl CPU
                     }
                     IncDec16Target::HLI =>
                     {
-                        initial = self.bus.read_byte(self.registers.get_hl()) as u16;
-                        result = initial + 1;
-                        self.bus.write_byte(self.registers.get_hl(), result as u8);
+                        // [HL] is a single byte, so it sets the flags like INC r
+                        let address = self.registers.get_hl();
+                        let value = self.inc(self.bus.read_byte(address));
+                        self.bus.write_byte(address, value);
+                        return self.pc.wrapping_add(1);
                     }
                     IncDec16Target::SP =>
                     {
@@ -621,6 +766,12 @@ impl CPU
                     }
                 }
 
+                // Register pairs pointing at OAM trigger the OAM bug on the DMG family
+                if !matches!(target, IncDec16Target::HLI | IncDec16Target::SP)
+                {
+                    self.bus.trigger_oam_bug(initial);
+                }
+
                 self.registers.f.zero = result == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = (initial & 0xF) + 1 > 0xF;
@@ -628,15 +779,10 @@ impl CPU
 
             Instruction::DEC(target) =>
             {
-                if let Some(value) = self.get_arithmetic_target_mut(target)
+                if let Some(value) = self.get_arithmetic_target_value(target)
                 {
-                    let initial = *value;
-                    let result = initial.wrapping_sub(1);
-                    *value = result;
-
-                    self.registers.f.zero = result == 0;
-                    self.registers.f.subtract = true;
-                    self.registers.f.half_carry = initial & 0xF == 0;
+                    let result = self.dec(value);
+                    *self.get_arithmetic_target_mut(target).unwrap() = result;
                 }
                 else
                 {
@@ -671,9 +817,10 @@ impl CPU
                     }
                     IncDec16Target::HLI =>
                     {
-                        initial = self.bus.read_byte(self.registers.get_hl()) as u16;
-                        result = initial.wrapping_sub(1);
-                        self.bus.write_byte(self.registers.get_hl(), result as u8);
+                        let address = self.registers.get_hl();
+                        let value = self.dec(self.bus.read_byte(address));
+                        self.bus.write_byte(address, value);
+                        return self.pc.wrapping_add(1);
                     }
                     IncDec16Target::SP =>
                     {
@@ -683,6 +830,12 @@ impl CPU
                     }
                 }
 
+                // Register pairs pointing at OAM trigger the OAM bug on the DMG family
+                if !matches!(target, IncDec16Target::HLI | IncDec16Target::SP)
+                {
+                    self.bus.trigger_oam_bug(initial);
+                }
+
                 self.registers.f.zero = result == 0;
                 self.registers.f.subtract = true;
                 self.registers.f.half_carry = initial & 0xF == 0;
@@ -1047,13 +1200,13 @@ impl CPU
 
     fn read_next_byte(&mut self) -> u8
     {
-        return self.bus.read_byte(self.pc + 1);
+        return self.bus.fetch_operand(self.pc + 1);
     }
 
     fn read_next_word(&mut self) -> u16
     {
-        return ((self.bus.read_byte(self.pc + 2) as u16) << 8)
-            | (self.bus.read_byte(self.pc + 1) as u16);
+        return ((self.bus.fetch_operand(self.pc + 2) as u16) << 8)
+            | (self.bus.fetch_operand(self.pc + 1) as u16);
     }
 
     fn add(&mut self, value: u8) -> u8
@@ -1069,6 +1222,25 @@ impl CPU
         result
     }
 
+    /// INC of a byte, which leaves the carry flag alone
+    fn inc(&mut self, value: u8) -> u8
+    {
+        let result = value.wrapping_add(1);
+        self.registers.f.zero = result == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = value & 0xF == 0xF;
+        result
+    }
+
+    fn dec(&mut self, value: u8) -> u8
+    {
+        let result = value.wrapping_sub(1);
+        self.registers.f.zero = result == 0;
+        self.registers.f.subtract = true;
+        self.registers.f.half_carry = value & 0xF == 0;
+        result
+    }
+
     fn addhl(&mut self, value: u16) -> u16
     {
         let (result, did_overflow) = self.registers.get_hl().overflowing_add(value);
@@ -1136,14 +1308,16 @@ impl CPU
         result
     }
 
-    fn jump(&self, should_jump: bool) -> u16
+    fn jump(&mut self, should_jump: bool) -> u16
     {
+        self.branch_taken = should_jump;
+        // The address is read whether or not the jump is taken
+        // Gameboy is little endian so read pc + 2 as most significant bit
+        // and pc + 1 as least significant bit
+        let least_significant_byte = self.bus.fetch_operand(self.pc + 1) as u16;
+        let most_significant_byte = self.bus.fetch_operand(self.pc + 2) as u16;
         if should_jump
         {
-            // Gameboy is little endian so read pc + 2 as most significant bit
-            // and pc + 1 as least significant bit
-            let least_significant_byte = self.bus.read_byte(self.pc + 1) as u16;
-            let most_significant_byte = self.bus.read_byte(self.pc + 2) as u16;
             (most_significant_byte << 8) | least_significant_byte
         }
         else
@@ -1158,9 +1332,10 @@ impl CPU
     fn jump_relative(&mut self, should_jump: bool) -> u16
     {
         let next_step = self.pc.wrapping_add(2);
+        self.branch_taken = should_jump;
+        let offset = self.read_next_byte() as i8;
         if should_jump
         {
-            let offset = self.read_next_byte() as i8;
             let pc = if offset >= 0
             {
                 next_step.wrapping_add(offset as u16)
@@ -1200,10 +1375,12 @@ impl CPU
     fn call(&mut self, should_jump: bool) -> u16
     {
         let next_pc = self.pc.wrapping_add(3);
+        self.branch_taken = should_jump;
+        let address = self.read_next_word();
         if should_jump
         {
             self.push(next_pc);
-            self.read_next_word()
+            address
         }
         else
         {
@@ -1213,6 +1390,7 @@ impl CPU
 
     fn return_(&mut self, should_jump: bool) -> u16
     {
+        self.branch_taken = should_jump;
         if should_jump
         {
             self.pop()
@@ -1223,3 +1401,174 @@ impl CPU
         }
     }
 }
+
+impl SaveState for CPU
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        let registers = &self.registers;
+        writer.bytes(&[
+            registers.a,
+            u8::from(registers.f),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+        ]);
+        writer.u16(self.pc);
+        writer.u16(self.sp);
+        writer.bool(self.is_halted);
+        writer.u16(self.inst_count);
+        writer.bool(self.interrupts_enabled);
+        self.bus.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        let mut bytes = [0; 8];
+        reader.bytes(&mut bytes)?;
+        let [a, f, b, c, d, e, h, l] = bytes;
+        self.registers = Registers { a, f: FlagsRegister::from(f), b, c, d, e, h, l };
+        self.pc = reader.u16()?;
+        self.sp = reader.u16()?;
+        self.is_halted = reader.bool()?;
+        self.inst_count = reader.u16()?;
+        self.interrupts_enabled = reader.bool()?;
+        self.bus.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::cartridge::Cartridge;
+    use crate::gameboy::test_gameboy;
+    use crate::model::Model;
+
+    fn stack_top(cpu: &super::CPU) -> u16
+    {
+        u16::from_le_bytes([cpu.bus.read_byte(cpu.sp), cpu.bus.read_byte(cpu.sp.wrapping_add(1))])
+    }
+
+    #[test]
+    fn call_jumps_and_ret_comes_back()
+    {
+        // CALL $0110, NOP, then RET at $0110
+        let mut program = vec![0xCD, 0x10, 0x01, 0x00];
+        program.resize(0x10, 0x00);
+        program.push(0xC9);
+        let mut gameboy = test_gameboy(&program);
+        let cpu = gameboy.cpu_mut();
+
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.sp), (0x0110, 0xFFFC));
+        assert_eq!(stack_top(cpu), 0x0103);
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.sp), (0x0103, 0xFFFE));
+    }
+
+    #[test]
+    fn conditional_calls_and_returns_follow_the_flags()
+    {
+        // XOR A sets Z, so CALL NZ and RET NZ fall through while RET Z returns
+        let mut program = vec![0xAF, 0xC4, 0x10, 0x01, 0xCC, 0x10, 0x01];
+        program.resize(0x10, 0x00);
+        program.extend([0xC0, 0xC8]);
+        let mut gameboy = test_gameboy(&program);
+        let cpu = gameboy.cpu_mut();
+
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.sp), (0x0104, 0xFFFE));
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.sp), (0x0110, 0xFFFC));
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0111);
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.sp), (0x0107, 0xFFFE));
+    }
+
+    #[test]
+    fn rst_pushes_the_next_address()
+    {
+        let mut gameboy = test_gameboy(&[0xCF]);
+        let cpu = gameboy.cpu_mut();
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.sp), (0x0008, 0xFFFC));
+        assert_eq!(stack_top(cpu), 0x0101);
+    }
+
+    #[test]
+    fn inc_and_dec_of_hl_indirect_change_one_byte()
+    {
+        // LD HL,$C000, LD [HL],$FF, INC [HL], LD [HL],$01, DEC [HL]
+        let program = [0x21, 0x00, 0xC0, 0x36, 0xFF, 0x34, 0x36, 0x01, 0x35];
+        let mut gameboy = test_gameboy(&program);
+        let cpu = gameboy.cpu_mut();
+
+        cpu.step();
+        cpu.step();
+        cpu.registers.f.carry = true;
+        cpu.step();
+        assert_eq!(cpu.bus.read_byte(0xC000), 0x00);
+        assert_eq!(cpu.bus.read_byte(0xC001), 0x00);
+        assert_eq!(cpu.registers.get_hl(), 0xC000);
+        let f = cpu.registers.f;
+        assert_eq!((f.zero, f.subtract, f.half_carry, f.carry), (true, false, true, true));
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.bus.read_byte(0xC000), 0x00);
+        let f = cpu.registers.f;
+        assert_eq!((f.zero, f.subtract, f.half_carry, f.carry), (true, true, false, true));
+        assert_eq!(cpu.pc, 0x0109);
+    }
+
+    #[test]
+    fn stores_from_a_go_to_memory()
+    {
+        // LD A,$42, LD C,$80, LDH [C],A, LD [$C001],A, LD A,$00, LD A,[$C001]
+        let program =
+            [0x3E, 0x42, 0x0E, 0x80, 0xE2, 0xEA, 0x01, 0xC0, 0x3E, 0x00, 0xFA, 0x01, 0xC0];
+        let mut gameboy = test_gameboy(&program);
+        let cpu = gameboy.cpu_mut();
+
+        for _ in 0..6
+        {
+            cpu.step();
+        }
+        assert_eq!(cpu.bus.read_byte(0xFF80), 0x42);
+        assert_eq!(cpu.bus.read_byte(0xC001), 0x42);
+        assert_eq!(cpu.registers.a, 0x42);
+        assert_eq!(cpu.pc, 0x010D);
+    }
+
+    /// Registers left by skipping the boot ROM of `model` for a monochrome game called TETRIS
+    fn registers_after_boot(model: Model, licensee: u8) -> super::Registers
+    {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13A].copy_from_slice(b"TETRIS");
+        rom[0x14B] = licensee;
+        let mut cpu = super::CPU::new(model, Vec::new(), Some(Cartridge::new(rom).unwrap()));
+        cpu.skip_boot_rom();
+        cpu.registers
+    }
+
+    #[test]
+    fn cgb_leaves_the_title_checksum_of_nintendo_games()
+    {
+        // The title bytes add up to 0xDB
+        let registers = registers_after_boot(Model::Cgb, 0x01);
+        assert_eq!((registers.b, registers.get_hl()), (0xDB, 0x991A));
+        let registers = registers_after_boot(Model::Agb, 0x01);
+        assert_eq!((registers.b, registers.get_hl()), (0xDC, 0x991A));
+        assert_eq!(u8::from(registers.f), 0x00);
+
+        let registers = registers_after_boot(Model::Cgb, 0x33);
+        assert_eq!((registers.b, registers.get_hl()), (0x00, 0x007C));
+        let registers = registers_after_boot(Model::Agb, 0x33);
+        assert_eq!((registers.b, registers.get_hl()), (0x01, 0x007C));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::cpu::instruction::{Instruction, Operand};
+
+/// One decoded instruction
+pub struct Disassembly
+{
+    pub text: String,
+    pub length: u16,
+}
+
+/// Decode the instruction at the start of `bytes`, which sits at `address` so that relative
+/// jumps can be shown with their destination. Instructions are decoded the same way the CPU
+/// decodes them, so opcodes it can't run yet, unused opcodes and instructions cut off by the
+/// end of `bytes` are shown as a `DB` of their first byte. `bytes` mustn't be empty.
+pub fn disassemble(bytes: &[u8], address: u16) -> Disassembly
+{
+    disassemble_with_labels(bytes, address, &|_| None)
+}
+
+/// Like `disassemble`, showing addresses in operands by the name `labels` gives them
+pub fn disassemble_with_labels(
+    bytes: &[u8],
+    address: u16,
+    labels: &dyn Fn(u16) -> Option<String>,
+) -> Disassembly
+{
+    let opcode = bytes[0];
+    let instruction = if opcode == 0xCB
+    {
+        bytes.get(1).and_then(|&opcode| Instruction::from_byte(opcode, true))
+    }
+    else
+    {
+        Instruction::from_byte(opcode, false)
+    };
+    let name = |address: u16| labels(address).unwrap_or_else(|| format!("${:04X}", address));
+
+    let resolved = instruction.and_then(|instruction| {
+        let length = instruction.length();
+        if bytes.len() < length as usize
+        {
+            return None;
+        }
+        let Some(operand) = instruction.operand()
+        else
+        {
+            return Some(Disassembly { text: instruction.text(""), length });
+        };
+        // The operand is always the last bytes of the instruction
+        let start = (length - operand.length()) as usize;
+        let data_byte = bytes[start];
+        let text = match operand
+        {
+            Operand::Byte => format!("${:02X}", data_byte),
+            Operand::HighAddress => name(0xFF00 | data_byte as u16),
+            Operand::Word | Operand::Address =>
+            {
+                name(u16::from_le_bytes([data_byte, bytes[start + 1]]))
+            }
+            Operand::Offset if matches!(instruction, Instruction::JR(_)) =>
+            {
+                name(address.wrapping_add(length).wrapping_add(data_byte as i8 as u16))
+            }
+            Operand::Offset =>
+            {
+                let offset = data_byte as i8;
+                let sign = if offset < 0 { '-' } else { '+' };
+                format!("{}${:02X}", sign, offset.unsigned_abs())
+            }
+        };
+        Some(Disassembly { text: instruction.text(&text), length })
+    });
+
+    resolved.unwrap_or_else(|| Disassembly { text: format!("DB ${:02X}", opcode), length: 1 })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn listing(bytes: &[u8], address: u16) -> Vec<(String, u16)>
+    {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len()
+        {
+            let instruction = disassemble(&bytes[offset..], address + offset as u16);
+            offset += instruction.length as usize;
+            lines.push((instruction.text, instruction.length));
+        }
+        lines
+    }
+
+    #[test]
+    fn boot_rom_clears_vram()
+    {
+        let bytes = [0x31, 0xFE, 0xFF, 0xAF, 0x21, 0xFF, 0x9F, 0x32, 0xCB, 0x7C, 0x20, 0xFB];
+        let expected = [
+            ("LD SP,$FFFE", 3),
+            ("XOR A", 1),
+            ("LD HL,$9FFF", 3),
+            ("LD [HL-],A", 1),
+            ("BIT 7,H", 2),
+            ("JR NZ,$0007", 2),
+        ];
+        let expected: Vec<_> =
+            expected.iter().map(|&(text, length)| (text.to_string(), length)).collect();
+        assert_eq!(listing(&bytes, 0x0000), expected);
+    }
+
+    #[test]
+    fn operands_are_shown_by_kind()
+    {
+        let text = |bytes: &[u8]| disassemble(bytes, 0x0200).text;
+        assert_eq!(text(&[0x3E, 0x05]), "LD A,$05");
+        assert_eq!(text(&[0xE0, 0x40]), "LDH [$FF40],A");
+        assert_eq!(text(&[0xFA, 0x00, 0xC0]), "LD A,[$C000]");
+        assert_eq!(text(&[0xF8, 0xFB]), "LD HL,SP-$05");
+        assert_eq!(text(&[0x18, 0x02]), "JR $0204");
+        assert_eq!(text(&[0xC4, 0x50, 0x01]), "CALL NZ,$0150");
+        assert_eq!(text(&[0xC8]), "RET Z");
+        assert_eq!(text(&[0xEF]), "RST $28");
+    }
+
+    #[test]
+    fn stores_and_hl_indirect_are_listed_as_the_cpu_runs_them()
+    {
+        let text = |bytes: &[u8]| disassemble(bytes, 0).text;
+        assert_eq!(text(&[0xE2]), "LDH [C],A");
+        assert_eq!(text(&[0xEA, 0x01, 0xC0]), "LD [$C001],A");
+        assert_eq!(text(&[0x34]), "INC [HL]");
+        assert_eq!(text(&[0x35]), "DEC [HL]");
+    }
+
+    #[test]
+    fn lengths_include_the_prefix_and_operand()
+    {
+        let length = |bytes: &[u8]| disassemble(bytes, 0).length;
+        assert_eq!(length(&[0x00]), 1);
+        assert_eq!(length(&[0xCB, 0x36]), 2);
+        assert_eq!(length(&[0x36, 0x00]), 2);
+        assert_eq!(length(&[0x08, 0x00, 0xC0]), 3);
+        // The byte after STOP is skipped
+        assert_eq!(length(&[0x10, 0x00]), 2);
+    }
+
+    #[test]
+    fn labels_replace_addresses()
+    {
+        let labels = |address: u16| (address == 0xC000).then(|| "wCounter".to_string());
+        let instruction = disassemble_with_labels(&[0xFA, 0x00, 0xC0], 0, &labels);
+        assert_eq!(instruction.text, "LD A,[wCounter]");
+        let instruction = disassemble_with_labels(&[0x20, 0xFE], 0xC000, &labels);
+        assert_eq!(instruction.text, "JR NZ,wCounter");
+    }
+
+    #[test]
+    fn undecoded_and_cut_off_opcodes_are_data()
+    {
+        let instruction = disassemble(&[0xD3], 0);
+        assert_eq!((instruction.text.as_str(), instruction.length), ("DB $D3", 1));
+        let instruction = disassemble(&[0x21, 0xFF], 0);
+        assert_eq!((instruction.text.as_str(), instruction.length), ("DB $21", 1));
+        let instruction = disassemble(&[0xCB], 0);
+        assert_eq!((instruction.text.as_str(), instruction.length), ("DB $CB", 1));
+    }
+}
@@ -0,0 +1,137 @@
+use std::io;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const HDMA1_ADDRESS: usize = 0xFF51;
+pub const HDMA2_ADDRESS: usize = 0xFF52;
+pub const HDMA3_ADDRESS: usize = 0xFF53;
+pub const HDMA4_ADDRESS: usize = 0xFF54;
+pub const HDMA5_ADDRESS: usize = 0xFF55;
+
+pub const HDMA_BLOCK_SIZE: u16 = 16;
+// Copying one block stalls the CPU for 8 machine cycles at single speed
+pub const HDMA_BLOCK_CYCLES: u32 = 32;
+
+/// CGB VRAM DMA registers.
+///
+/// A general purpose transfer copies everything at once while the CPU waits. An HBlank transfer
+/// copies one 16 byte block at the start of every HBlank until it is done or cancelled.
+pub struct Hdma
+{
+    source: u16,
+    destination: u16,
+    blocks_remaining: u8,
+    hblank_active: bool,
+}
+
+impl Default for Hdma
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Hdma
+{
+    pub fn new() -> Self
+    {
+        Hdma { source: 0, destination: 0, blocks_remaining: 0, hblank_active: false }
+    }
+
+    pub fn hblank_active(&self) -> bool
+    {
+        self.hblank_active
+    }
+
+    /// HDMA5 reads back the number of blocks left minus one, with bit 7 clear while an HBlank
+    /// transfer is still running. Once everything has been copied it reads 0xFF.
+    pub fn read_length(&self) -> u8
+    {
+        let inactive = if self.hblank_active { 0 } else { 0x80 };
+        inactive | (self.blocks_remaining.wrapping_sub(1) & 0x7F)
+    }
+
+    /// Set what HDMA5 reads back without starting or cancelling a transfer. Clearing bit 7 leaves
+    /// an HBlank transfer running.
+    pub fn poke_length(&mut self, value: u8)
+    {
+        self.hblank_active = value & 0x80 == 0;
+        self.blocks_remaining = (value & 0x7F).wrapping_add(1);
+    }
+
+    /// Returns true when the write starts a general purpose transfer that must run straight away
+    pub fn write_register(&mut self, address: usize, value: u8) -> bool
+    {
+        match address
+        {
+            HDMA1_ADDRESS => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+            // The low four bits of both addresses are ignored
+            HDMA2_ADDRESS => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            // The destination is always in VRAM
+            HDMA3_ADDRESS =>
+            {
+                self.destination = (self.destination & 0x00FF) | ((value & 0x1F) as u16) << 8;
+            }
+            HDMA4_ADDRESS =>
+            {
+                self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16;
+            }
+            HDMA5_ADDRESS =>
+            {
+                // Writing bit 7 = 0 during an HBlank transfer cancels it
+                if self.hblank_active && value & 0x80 == 0
+                {
+                    self.hblank_active = false;
+                    return false;
+                }
+
+                self.blocks_remaining = (value & 0x7F) + 1;
+                self.hblank_active = value & 0x80 != 0;
+                return !self.hblank_active;
+            }
+            _ =>
+            {}
+        }
+        false
+    }
+
+    /// Source address and VRAM offset of the next block to copy, or `None` once done
+    pub fn next_block(&mut self) -> Option<(u16, u16)>
+    {
+        if self.blocks_remaining == 0
+        {
+            return None;
+        }
+
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = (self.destination + HDMA_BLOCK_SIZE) & 0x1FF0;
+        self.blocks_remaining -= 1;
+        if self.blocks_remaining == 0
+        {
+            self.hblank_active = false;
+        }
+        Some(block)
+    }
+}
+
+impl SaveState for Hdma
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.u16(self.source);
+        writer.u16(self.destination);
+        writer.u8(self.blocks_remaining);
+        writer.bool(self.hblank_active);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.source = reader.u16()?;
+        self.destination = reader.u16()?;
+        self.blocks_remaining = reader.u8()?;
+        self.hblank_active = reader.bool()?;
+        Ok(())
+    }
+}
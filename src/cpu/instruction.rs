@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Copy, Clone)]
 pub enum ArithmeticTarget
 {
@@ -147,10 +149,51 @@ impl RSTLocation
     }
 }
 
+/// Kinds of immediate value following an opcode
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Operand
+{
+    Byte,
+    Word,
+    /// The low byte of an address in $FF00-$FFFF
+    HighAddress,
+    Address,
+    /// A signed offset, from the address after the instruction for relative jumps
+    Offset,
+}
+
+impl Operand
+{
+    pub fn length(self) -> u16
+    {
+        match self
+        {
+            Operand::Word | Operand::Address => 2,
+            _ => 1,
+        }
+    }
+
+    /// How the operand is written when its value isn't known
+    pub fn name(self) -> &'static str
+    {
+        match self
+        {
+            Operand::Byte => "n8",
+            Operand::Word => "n16",
+            Operand::HighAddress => "a8",
+            Operand::Address => "a16",
+            Operand::Offset => "+e8",
+        }
+    }
+}
+
 pub enum Instruction
 {
     NOP(),
     HALT(),
+    STOP(),
+    DI(),
+    EI(),
     CALL(JumpTest),
     RET(JumpTest),
     RETI(),
@@ -202,8 +245,81 @@ pub enum Instruction
     RST(RSTLocation),
 }
 
+// Clock cycles per unprefixed opcode. Conditional branches are listed with their not-taken cost.
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   A   B   C   D   E   F
+    4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x
+    4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 1x
+    8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 2x
+    8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 3x
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 4x
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 5x
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 6x
+    8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 7x
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 8x
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 9x
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Ax
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Bx
+    8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  4, 12, 24,  8, 16, // Cx
+    8, 12, 12,  4, 12, 16,  8, 16,  8, 16, 12,  4, 12,  4,  8, 16, // Dx
+   12, 12,  8,  4,  4, 16,  8, 16, 16,  4, 16,  4,  4,  4,  8, 16, // Ex
+   12, 12,  8,  4,  4, 16,  8, 16, 12,  8, 16,  4,  4,  4,  8, 16, // Fx
+];
+
 impl Instruction
 {
+    /// Number of clock cycles an opcode takes, assuming a conditional branch is not taken
+    pub fn cycles(byte: u8, prefixed: bool) -> u8
+    {
+        if prefixed
+        {
+            // Prefixed instructions operating on [HL] need extra memory accesses, and BIT only
+            // reads the value back without writing it
+            match (byte & 0x07, byte & 0xC0)
+            {
+                (0x06, 0x40) => 12,
+                (0x06, _) => 16,
+                _ => 8,
+            }
+        }
+        else
+        {
+            CYCLES[byte as usize]
+        }
+    }
+
+    /// Extra clock cycles a conditional branch opcode takes when the branch is taken
+    pub fn branch_taken_cycles(byte: u8) -> u8
+    {
+        match byte
+        {
+            0x20 | 0x28 | 0x30 | 0x38 => 4,  // JR cc
+            0xc2 | 0xca | 0xd2 | 0xda => 4,  // JP cc
+            0xc4 | 0xcc | 0xd4 | 0xdc => 12, // CALL cc
+            0xc0 | 0xc8 | 0xd0 | 0xd8 => 12, // RET cc
+            _ => 0,
+        }
+    }
+
+    /// Whether an unprefixed opcode is CALL or CALL cc
+    pub fn is_call(byte: u8) -> bool
+    {
+        matches!(byte, 0xcd | 0xc4 | 0xcc | 0xd4 | 0xdc)
+    }
+
+    /// Whether an unprefixed opcode is one of the RSTs
+    pub fn is_rst(byte: u8) -> bool
+    {
+        byte & 0xc7 == 0xc7
+    }
+
+    /// Whether an unprefixed opcode is RET, RET cc or RETI
+    pub fn is_return(byte: u8) -> bool
+    {
+        matches!(byte, 0xc9 | 0xd9 | 0xc0 | 0xc8 | 0xd0 | 0xd8)
+    }
+
     pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction>
     {
         if prefixed
@@ -218,7 +334,6 @@ impl Instruction
 
     fn from_byte_prefixed(byte: u8) -> Option<Instruction>
     {
-        println!("from_byte_prefixed {:x}", byte);
         match byte
         {
             0x00 => Some(Instruction::RLC(ArithmeticTarget::B)), // RLC B
@@ -250,11 +365,14 @@ impl Instruction
 
     fn from_byte_not_prefixed(byte: u8) -> Option<Instruction>
     {
-        println!("from_byte_not_prefixed");
         match byte
         {
             0x00 => Some(Instruction::NOP()),
 
+            0x10 => Some(Instruction::STOP()),
+            0xf3 => Some(Instruction::DI()),
+            0xfb => Some(Instruction::EI()),
+
             0x01 => Some(Instruction::LD(LoadType::Word(LoadWordTarget::BC))),
             0x11 => Some(Instruction::LD(LoadType::Word(LoadWordTarget::DE))),
             0x21 => Some(Instruction::LD(LoadType::Word(LoadWordTarget::HL))),
@@ -347,12 +465,6 @@ impl Instruction
             0x3a => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectMinus))),
             0xfa => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect))),
 
-            0xc4 => Some(Instruction::CALL(JumpTest::NotZero)),
-            0xd4 => Some(Instruction::CALL(JumpTest::NotCarry)),
-            0xcc => Some(Instruction::CALL(JumpTest::Zero)),
-            0xdc => Some(Instruction::CALL(JumpTest::Carry)),
-            0xcd => Some(Instruction::CALL(JumpTest::Always)),
-
             0xc0 => Some(Instruction::RET(JumpTest::NotZero)),
             0xd0 => Some(Instruction::RET(JumpTest::NotCarry)),
             0xc8 => Some(Instruction::RET(JumpTest::Zero)),
@@ -384,12 +496,8 @@ impl Instruction
             0x29 => Some(Instruction::ADDHL(ADDHLTarget::HL)),
             0x39 => Some(Instruction::ADDHL(ADDHLTarget::SP)),
 
-            0xe2 => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::LastByteIndirect))),
-            0x02 => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::BCIndirect))),
-            0x12 => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::DEIndirect))),
-            0x22 => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectPlus))),
-            0x32 => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectMinus))),
-            0xea => Some(Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect))),
+            0xe2 => Some(Instruction::LD(LoadType::IndirectFromA(Indirect::LastByteIndirect))),
+            0xea => Some(Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect))),
 
             0x3c => Some(Instruction::INC(ArithmeticTarget::A)),
             0x04 => Some(Instruction::INC(ArithmeticTarget::B)),
@@ -398,7 +506,7 @@ impl Instruction
             0x0c => Some(Instruction::INC(ArithmeticTarget::C)),
             0x1c => Some(Instruction::INC(ArithmeticTarget::E)),
             0x2c => Some(Instruction::INC(ArithmeticTarget::L)),
-            0x34 => Some(Instruction::INC16(IncDec16Target::HL)),
+            0x34 => Some(Instruction::INC16(IncDec16Target::HLI)),
             0x03 => Some(Instruction::INC16(IncDec16Target::BC)),
             0x13 => Some(Instruction::INC16(IncDec16Target::DE)),
             0x23 => Some(Instruction::INC16(IncDec16Target::HL)),
@@ -411,7 +519,7 @@ impl Instruction
             0x1d => Some(Instruction::DEC(ArithmeticTarget::E)),
             0x25 => Some(Instruction::DEC(ArithmeticTarget::H)),
             0x2d => Some(Instruction::DEC(ArithmeticTarget::L)),
-            0x35 => Some(Instruction::DEC16(IncDec16Target::HL)),
+            0x35 => Some(Instruction::DEC16(IncDec16Target::HLI)),
             0x0b => Some(Instruction::DEC16(IncDec16Target::BC)),
             0x1b => Some(Instruction::DEC16(IncDec16Target::DE)),
             0x2b => Some(Instruction::DEC16(IncDec16Target::HL)),
@@ -425,3 +533,298 @@ impl Instruction
         }
     }
 }
+
+impl Instruction
+{
+    /// The immediate value the instruction reads after its opcode, if any
+    pub fn operand(&self) -> Option<Operand>
+    {
+        match self
+        {
+            Instruction::CALL(_) | Instruction::JP(_) => Some(Operand::Word),
+            Instruction::JR(_) => Some(Operand::Offset),
+            Instruction::ADDD8() | Instruction::XORD8() | Instruction::CPD8() =>
+            {
+                Some(Operand::Byte)
+            }
+            Instruction::LD(load_type) => match load_type
+            {
+                LoadType::Byte(_, LoadByteSource::D8) => Some(Operand::Byte),
+                LoadType::Word(_) => Some(Operand::Word),
+                LoadType::AFromIndirect(Indirect::WordIndirect)
+                | LoadType::IndirectFromA(Indirect::WordIndirect)
+                | LoadType::IndirectFromSP() => Some(Operand::Address),
+                LoadType::AFromByteAddress() | LoadType::ByteAddressFromA() =>
+                {
+                    Some(Operand::HighAddress)
+                }
+                LoadType::HLFromSPN() => Some(Operand::Offset),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Number of bytes the instruction takes up, including the 0xCB prefix and its operand
+    pub fn length(&self) -> u16
+    {
+        let opcode = match self
+        {
+            Instruction::RLC(_)
+            | Instruction::RRC(_)
+            | Instruction::RL(_)
+            | Instruction::RR(_)
+            | Instruction::SLA(_)
+            | Instruction::SRA(_)
+            | Instruction::SWAP(_)
+            | Instruction::SWAP16(_)
+            | Instruction::SRL(_)
+            | Instruction::BIT(..)
+            | Instruction::BIT16(_)
+            | Instruction::RES(..)
+            | Instruction::RES16(_)
+            | Instruction::SET(..)
+            | Instruction::SET16(_) => 2,
+            // The byte after STOP is skipped
+            Instruction::STOP() => 2,
+            _ => 1,
+        };
+        opcode + self.operand().map_or(0, Operand::length)
+    }
+
+    /// The instruction in assembly, with `operand` written in place of its immediate value
+    pub fn text(&self, operand: &str) -> String
+    {
+        let conditional = |name: &str, test: &JumpTest, operand: &str| {
+            let condition = match test
+            {
+                JumpTest::NotZero => "NZ,",
+                JumpTest::Zero => "Z,",
+                JumpTest::NotCarry => "NC,",
+                JumpTest::Carry => "C,",
+                JumpTest::Always => "",
+            };
+            format!("{} {}{}", name, condition, operand).trim_end_matches([' ', ',']).to_string()
+        };
+        match self
+        {
+            Instruction::NOP() => "NOP".to_string(),
+            Instruction::HALT() => "HALT".to_string(),
+            Instruction::STOP() => "STOP".to_string(),
+            Instruction::DI() => "DI".to_string(),
+            Instruction::EI() => "EI".to_string(),
+            Instruction::CALL(test) => conditional("CALL", test, operand),
+            Instruction::RET(test) => conditional("RET", test, ""),
+            Instruction::RETI() => "RETI".to_string(),
+            Instruction::PUSH(source) => format!("PUSH {}", source),
+            Instruction::POP(target) => format!("POP {}", target),
+            Instruction::LD(load_type) => match load_type
+            {
+                LoadType::Byte(target, LoadByteSource::D8) => format!("LD {},{}", target, operand),
+                LoadType::Byte(target, source) => format!("LD {},{}", target, source),
+                LoadType::Word(target) => format!("LD {},{}", target, operand),
+                LoadType::AFromIndirect(Indirect::LastByteIndirect) => "LDH A,[C]".to_string(),
+                LoadType::IndirectFromA(Indirect::LastByteIndirect) => "LDH [C],A".to_string(),
+                LoadType::AFromIndirect(indirect) => format!("LD A,{}", indirect.text(operand)),
+                LoadType::IndirectFromA(indirect) => format!("LD {},A", indirect.text(operand)),
+                LoadType::AFromByteAddress() => format!("LDH A,[{}]", operand),
+                LoadType::ByteAddressFromA() => format!("LDH [{}],A", operand),
+                LoadType::SPFromHL() => "LD SP,HL".to_string(),
+                LoadType::HLFromSPN() => format!("LD HL,SP{}", operand),
+                LoadType::IndirectFromSP() => format!("LD [{}],SP", operand),
+            },
+            Instruction::ADD(target) => format!("ADD A,{}", target),
+            Instruction::ADD16(target) => format!("ADD A,[{}]", target),
+            Instruction::ADDD8() => format!("ADD A,{}", operand),
+            Instruction::ADDHL(target) => format!("ADD HL,{}", target),
+            Instruction::SUB(target) => format!("SUB {}", target),
+            Instruction::SBC(target) => format!("SBC A,{}", target),
+            Instruction::AND(target) => format!("AND {}", target),
+            Instruction::OR(target) => format!("OR {}", target),
+            Instruction::XOR(target) => format!("XOR {}", target),
+            Instruction::XOR16(target) => format!("XOR [{}]", target),
+            Instruction::XORD8() => format!("XOR {}", operand),
+            Instruction::CP(target) => format!("CP {}", target),
+            Instruction::CP16(target) => format!("CP [{}]", target),
+            Instruction::CPD8() => format!("CP {}", operand),
+            Instruction::INC(target) => format!("INC {}", target),
+            Instruction::INC16(target) => format!("INC {}", target),
+            Instruction::DEC(target) => format!("DEC {}", target),
+            Instruction::DEC16(target) => format!("DEC {}", target),
+            Instruction::CCF() => "CCF".to_string(),
+            Instruction::SCF() => "SCF".to_string(),
+            Instruction::RRA() => "RRA".to_string(),
+            Instruction::RR(target) => format!("RR {}", target),
+            Instruction::RLA() => "RLA".to_string(),
+            Instruction::RL(target) => format!("RL {}", target),
+            Instruction::RRCA() => "RRCA".to_string(),
+            Instruction::RRC(target) => format!("RRC {}", target),
+            Instruction::RLCA() => "RLCA".to_string(),
+            Instruction::RLC(target) => format!("RLC {}", target),
+            Instruction::CPL() => "CPL".to_string(),
+            Instruction::BIT(bit, target) => format!("BIT {},{}", bit, target),
+            Instruction::BIT16(bit) => format!("BIT {},[HL]", bit),
+            Instruction::RES(bit, target) => format!("RES {},{}", bit, target),
+            Instruction::RES16(bit) => format!("RES {},[HL]", bit),
+            Instruction::SET(bit, target) => format!("SET {},{}", bit, target),
+            Instruction::SET16(bit) => format!("SET {},[HL]", bit),
+            Instruction::SRL(target) => format!("SRL {}", target),
+            Instruction::SRA(target) => format!("SRA {}", target),
+            Instruction::SLA(target) => format!("SLA {}", target),
+            Instruction::SWAP(target) => format!("SWAP {}", target),
+            Instruction::SWAP16(target) => format!("SWAP [{}]", target),
+            Instruction::JP(test) => conditional("JP", test, operand),
+            Instruction::JR(test) => conditional("JR", test, operand),
+            Instruction::RST(location) => format!("RST ${:02X}", location.to_hex()),
+        }
+    }
+}
+
+impl fmt::Display for Instruction
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(&self.text(self.operand().map_or("", Operand::name)))
+    }
+}
+
+impl Indirect
+{
+    fn text(&self, operand: &str) -> String
+    {
+        match self
+        {
+            Indirect::BCIndirect => "[BC]".to_string(),
+            Indirect::DEIndirect => "[DE]".to_string(),
+            Indirect::HLIndirectMinus => "[HL-]".to_string(),
+            Indirect::HLIndirectPlus => "[HL+]".to_string(),
+            Indirect::WordIndirect => format!("[{}]", operand),
+            Indirect::LastByteIndirect => "[C]".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticTarget
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            ArithmeticTarget::A => "A",
+            ArithmeticTarget::B => "B",
+            ArithmeticTarget::C => "C",
+            ArithmeticTarget::D => "D",
+            ArithmeticTarget::E => "E",
+            ArithmeticTarget::H => "H",
+            ArithmeticTarget::L => "L",
+        })
+    }
+}
+
+impl fmt::Display for ArithmeticTarget16
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            ArithmeticTarget16::BC => "BC",
+            ArithmeticTarget16::DE => "DE",
+            ArithmeticTarget16::HL => "HL",
+        })
+    }
+}
+
+impl fmt::Display for IncDec16Target
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            IncDec16Target::BC => "BC",
+            IncDec16Target::DE => "DE",
+            IncDec16Target::HL => "HL",
+            IncDec16Target::HLI => "[HL]",
+            IncDec16Target::SP => "SP",
+        })
+    }
+}
+
+impl fmt::Display for StackTarget
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            StackTarget::AF => "AF",
+            StackTarget::BC => "BC",
+            StackTarget::DE => "DE",
+            StackTarget::HL => "HL",
+        })
+    }
+}
+
+impl fmt::Display for LoadByteTarget
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            LoadByteTarget::A => "A",
+            LoadByteTarget::B => "B",
+            LoadByteTarget::C => "C",
+            LoadByteTarget::D => "D",
+            LoadByteTarget::E => "E",
+            LoadByteTarget::H => "H",
+            LoadByteTarget::L => "L",
+            LoadByteTarget::HLI => "[HL]",
+        })
+    }
+}
+
+impl fmt::Display for LoadByteSource
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            LoadByteSource::A => "A",
+            LoadByteSource::B => "B",
+            LoadByteSource::C => "C",
+            LoadByteSource::D => "D",
+            LoadByteSource::E => "E",
+            LoadByteSource::H => "H",
+            LoadByteSource::L => "L",
+            LoadByteSource::D8 => "n8",
+            LoadByteSource::HLI => "[HL]",
+        })
+    }
+}
+
+impl fmt::Display for LoadWordTarget
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            LoadWordTarget::AF => "AF",
+            LoadWordTarget::BC => "BC",
+            LoadWordTarget::DE => "DE",
+            LoadWordTarget::HL => "HL",
+            LoadWordTarget::SP => "SP",
+        })
+    }
+}
+
+impl fmt::Display for ADDHLTarget
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            ADDHLTarget::BC => "BC",
+            ADDHLTarget::DE => "DE",
+            ADDHLTarget::HL => "HL",
+            ADDHLTarget::SP => "SP",
+        })
+    }
+}
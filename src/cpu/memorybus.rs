@@ -1,46 +1,914 @@
-use crate::gpu::GPU;
-use crate::gpu::VRAM_BEGIN;
-use crate::gpu::VRAM_END;
+use std::cell::Cell;
+use std::io;
+
+use crate::apu::{APU, NR10_ADDRESS, NR14_ADDRESS, NR52_ADDRESS, WAVE_RAM_BEGIN, WAVE_RAM_END};
+use crate::cartridge::{Cartridge, EXTERNAL_RAM_BEGIN, EXTERNAL_RAM_END, ROM_BEGIN, ROM_END};
+use crate::cdl::{self, CodeDataLog};
+use crate::cheats::Cheats;
+use crate::cpu::hdma::{Hdma, HDMA1_ADDRESS, HDMA5_ADDRESS, HDMA_BLOCK_CYCLES, HDMA_BLOCK_SIZE};
+use crate::gpu::compatibility::CompatibilityPalette;
+use crate::gpu::{Mode, FRAME_CYCLES, GPU};
+use crate::gpu::{BCPS_ADDRESS, BGP_ADDRESS, LCDC_ADDRESS, LY_ADDRESS, OCPD_ADDRESS, VBK_ADDRESS};
+use crate::gpu::{OAM_BEGIN, OAM_END, OAM_SIZE, VRAM_BEGIN, VRAM_END, WX_ADDRESS};
+use crate::joypad::{Joypad, JOYP_ADDRESS};
+use crate::model::Model;
+use crate::savestate::{invalid_state, SaveState, StateReader, StateWriter};
+use crate::serial::{Serial, SB_ADDRESS, SC_ADDRESS};
+use crate::sgb::Sgb;
+use crate::timer::{Timer, DIV_ADDRESS, TAC_ADDRESS};
 //use crate::gpu::VRAM_SIZE;
 
 pub const BOOT_ROM_BEGIN: usize = 0x00;
 pub const BOOT_ROM_END: usize = 0xFF;
-pub const BOOT_ROM_SIZE: usize = BOOT_ROM_END - BOOT_ROM_BEGIN + 1;
+// The CGB boot ROM is larger and continues after the cartridge header
+pub const CGB_BOOT_ROM_BEGIN: usize = 0x200;
+pub const CGB_BOOT_ROM_END: usize = 0x8FF;
+
+pub const WRAM_BEGIN: usize = 0xC000;
+pub const WRAM_END: usize = 0xDFFF;
+pub const ECHO_RAM_BEGIN: usize = 0xE000;
+pub const ECHO_RAM_END: usize = 0xFDFF;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
+
+pub const INTERRUPT_FLAG_ADDRESS: usize = 0xFF0F;
+pub const DMA_ADDRESS: usize = 0xFF46;
+pub const KEY1_ADDRESS: usize = 0xFF4D;
+pub const BOOT_ROM_DISABLE_ADDRESS: usize = 0xFF50;
+pub const SVBK_ADDRESS: usize = 0xFF70;
+pub const INTERRUPT_ENABLE_ADDRESS: usize = 0xFFFF;
+
+// Sound registers NR10 to NR51 as left by the boot ROM
+const POST_BOOT_SOUND_REGISTERS: [u8; 22] = [
+    0x80, 0xBF, 0xF3, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    0xFF, 0x00, 0x00, 0xBF, 0x77, 0xF3,
+];
+
+// The boot ROM draws the logo from the cartridge header as tiles 1-24, followed by a ® tile
+const LOGO_BEGIN: u16 = 0x104;
+const LOGO_SIZE: u16 = 48;
+const LOGO_TILES_OFFSET: usize = 0x10;
+const REGISTERED_TILE_OFFSET: usize = 0x190;
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+const LOGO_MAP_OFFSETS: [usize; 2] = [0x1904, 0x1924];
+const REGISTERED_MAP_OFFSET: usize = 0x1910;
+
+#[derive(Copy, Clone, Default)]
+pub struct InterruptFlags
+{
+    pub vblank: bool,
+    pub lcdstat: bool,
+    pub timer: bool,
+    pub serial: bool,
+    pub joypad: bool,
+}
+
+impl InterruptFlags
+{
+    pub fn from_byte(byte: u8) -> Self
+    {
+        InterruptFlags {
+            vblank: byte & 0x01 != 0,
+            lcdstat: byte & 0x02 != 0,
+            timer: byte & 0x04 != 0,
+            serial: byte & 0x08 != 0,
+            joypad: byte & 0x10 != 0,
+        }
+    }
+
+    pub fn to_byte(self) -> u8
+    {
+        (self.vblank as u8)
+            | (self.lcdstat as u8) << 1
+            | (self.timer as u8) << 2
+            | (self.serial as u8) << 3
+            | (self.joypad as u8) << 4
+    }
+}
+
+/// Stops the debugger when an address from `begin` to `end` is read or written
+#[derive(Copy, Clone)]
+pub struct Watchpoint
+{
+    pub begin: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct WatchHit
+{
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+/// The bank a bank number picks at an address. Like SVBK, bank 0 at 0xD000 to 0xDFFF picks
+/// bank 1, as bank 0 is always at 0xC000. RGBDS also gives labels there bank 0 when WRAM isn't
+/// banked, as on the DMG, which only ever has bank 1 there.
+pub fn mapped_bank(address: u16, bank: usize) -> usize
+{
+    match address as usize
+    {
+        WRAM_BEGIN..=WRAM_END if address as usize >= WRAM_BEGIN + WRAM_BANK_SIZE =>
+        {
+            (bank % WRAM_BANKS).max(1)
+        }
+        _ => bank,
+    }
+}
+
 pub struct MemoryBus
 {
-    memory: [u8; 0xFFFF],
+    memory: [u8; 0x10000],
+    boot_rom: Vec<u8>,
+    boot_rom_enabled: bool,
+    cartridge: Option<Cartridge>,
+    // Eight 4KB banks. Bank 0 is fixed at 0xC000, the CGB can switch banks 1-7 in at 0xD000.
+    wram: Vec<u8>,
+    wram_bank: usize,
     pub gpu: GPU,
+    pub apu: APU,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    // Only present when running a game with Super Game Boy functions
+    pub sgb: Option<Sgb>,
+    pub serial: Serial,
+    pub interrupt_enable: InterruptFlags,
+    pub interrupt_flag: InterruptFlags,
+    hdma: Hdma,
+    // Cycles the CPU still has to wait for a VRAM DMA to finish
+    dma_stall_cycles: u32,
+    model: Model,
+    // CGB features are only enabled for colour games running on a CGB
+    cgb_mode: bool,
+    // Set when a monochrome game is being colourised, so buttons held during boot can pick
+    // another palette
+    compatibility_palette: bool,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    // Cycles since the last frame ended, so frames still end while the LCD is off
+    frame_cycles: u32,
+    frame_completed: bool,
+    pub watchpoints: Vec<Watchpoint>,
+    // LY always reads as 0x90, which CPU trace logs from Gameboy Doctor rely on
+    pub stub_ly: bool,
+    // First watched access since the debugger last looked
+    watch_hit: Cell<Option<WatchHit>>,
+    // Marks how the game uses each byte of the ROM while it's being logged
+    pub code_data_log: Option<CodeDataLog>,
+    pub cheats: Cheats,
 }
 
 impl MemoryBus
 {
-    pub fn new(boot_rom: Vec<u8>) -> Self
+    pub fn new(model: Model, boot_rom: Vec<u8>, cartridge: Option<Cartridge>) -> Self
+    {
+        let cgb_mode =
+            model.is_cgb() && cartridge.as_ref().is_some_and(|cartridge| cartridge.supports_cgb());
+
+        Self {
+            memory: [0; 0x10000],
+            boot_rom,
+            boot_rom_enabled: true,
+            cartridge,
+            wram: vec![0; WRAM_BANK_SIZE * WRAM_BANKS],
+            wram_bank: 1,
+            gpu: GPU::new(model, cgb_mode),
+            apu: APU::new(model),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            sgb: if model.is_sgb() { Some(Sgb::new()) } else { None },
+            serial: Serial::new(),
+            interrupt_enable: InterruptFlags::default(),
+            interrupt_flag: InterruptFlags::default(),
+            hdma: Hdma::new(),
+            dma_stall_cycles: 0,
+            model,
+            cgb_mode,
+            compatibility_palette: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            frame_cycles: 0,
+            frame_completed: false,
+            watchpoints: Vec::new(),
+            stub_ly: false,
+            watch_hit: Cell::new(None),
+            code_data_log: None,
+            cheats: Cheats::new(),
+        }
+    }
+
+    /// Advance the peripherals by the number of cycles the last instruction took
+    pub fn step(&mut self, cycles: u8)
     {
-        let mut memory = [0; 0xFFFF];
+        // In double speed mode the CPU runs twice as fast as the LCD
+        let gpu_cycles = if self.double_speed { cycles / 2 } else { cycles };
+        let was_hblank = self.gpu.mode == Mode::HorizontalBlank;
+        let request = self.gpu.step(gpu_cycles);
+        self.frame_cycles += gpu_cycles as u32;
+        if request.vblank || self.frame_cycles >= FRAME_CYCLES
+        {
+            self.frame_cycles = 0;
+            self.frame_completed = true;
+        }
+        if request.vblank
+        {
+            self.interrupt_flag.vblank = true;
+            if let Some(sgb) = self.sgb.as_mut()
+            {
+                sgb.end_frame(&self.gpu.shades);
+            }
+            self.apply_ram_cheats();
+        }
+        if request.lcdstat
+        {
+            self.interrupt_flag.lcdstat = true;
+        }
+
+        self.apu.step(gpu_cycles);
+        if let Some(cartridge) = self.cartridge.as_mut()
+        {
+            cartridge.step(gpu_cycles);
+        }
+
+        if self.timer.step(cycles)
+        {
+            self.interrupt_flag.timer = true;
+        }
+
+        if self.joypad.take_interrupt()
+        {
+            self.interrupt_flag.joypad = true;
+        }
 
-        let len = boot_rom.len().min(BOOT_ROM_END);
-        memory[..len].copy_from_slice(&boot_rom[..len]);
+        if self.serial.step(cycles)
+        {
+            self.interrupt_flag.serial = true;
+        }
 
-        Self { memory, gpu: GPU::new() }
+        // HBlank DMA copies one block as each HBlank starts
+        if !was_hblank && self.gpu.mode == Mode::HorizontalBlank && self.hdma.hblank_active()
+        {
+            self.transfer_hdma_block();
+        }
+    }
+
+    /// Run the rest of the system while the CPU is stalled by a VRAM DMA. Returns the number
+    /// of cycles the CPU was stalled for.
+    pub fn step_dma_stall(&mut self) -> u32
+    {
+        let mut stalled = 0;
+        // HBlank transfers started while stalled extend the stall
+        while self.dma_stall_cycles > 0
+        {
+            let cycles = self.dma_stall_cycles.min(4);
+            self.dma_stall_cycles -= cycles;
+            self.step(cycles as u8);
+            stalled += cycles;
+        }
+        stalled
+    }
+
+    /// True once for every frame the LCD has finished
+    pub fn take_frame_completed(&mut self) -> bool
+    {
+        std::mem::take(&mut self.frame_completed)
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge>
+    {
+        self.cartridge.as_ref()
+    }
+
+    pub fn model(&self) -> Model
+    {
+        self.model
+    }
+
+    /// Set up the IO registers, DIV and VRAM the way the boot ROM leaves them
+    pub fn skip_boot_rom(&mut self)
+    {
+        let model = self.model;
+        self.boot_rom_enabled = false;
+
+        self.apu.write_register(NR52_ADDRESS, 0x80);
+        for (address, &value) in (NR10_ADDRESS..).zip(POST_BOOT_SOUND_REGISTERS.iter())
+        {
+            // The boot sound is still playing on the first square channel, except on the SGB
+            // which doesn't play one
+            let value =
+                if address == NR14_ADDRESS && model.is_sgb() { value & 0x7F } else { value };
+            self.apu.write_register(address, value);
+        }
+        self.memory[DMA_ADDRESS] = if model.is_cgb() { 0x00 } else { 0xFF };
+        self.interrupt_flag = InterruptFlags::from_byte(0xE1);
+
+        // Internal counter when the boot ROM hands over at 0x0100, DIV being its top byte
+        let counter = match model
+        {
+            Model::Dmg0 => 0x182C,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 => 0xD85C,
+            Model::Cgb | Model::Agb => 0x267C,
+        };
+        self.timer.set_counter(counter);
+
+        self.gpu.write_register(LCDC_ADDRESS, 0x91);
+        self.gpu.write_register(BGP_ADDRESS, 0xFC);
+
+        // The CGB boot ROM clears the logo away again before starting the game
+        if !model.is_cgb()
+        {
+            self.draw_logo();
+        }
+    }
+
+    /// INC and DEC of a register pair pointing at OAM corrupt it on the DMG family
+    pub fn trigger_oam_bug(&mut self, address: u16)
+    {
+        if (OAM_BEGIN..=0xFEFF).contains(&(address as usize))
+        {
+            self.gpu.corrupt_oam();
+        }
+    }
+
+    /// Colourise a monochrome game with one of the CGB compatibility palettes
+    pub fn set_compatibility_palette(&mut self, palette: &CompatibilityPalette)
+    {
+        self.gpu.set_compatibility_palette(palette);
+        self.compatibility_palette = true;
+    }
+
+    /// True if any enabled interrupt has been requested
+    pub fn has_interrupt(&self) -> bool
+    {
+        self.interrupt_enable.to_byte() & self.interrupt_flag.to_byte() != 0
+    }
+
+    /// True if the game has asked for a CGB speed switch on the next STOP
+    pub fn speed_switch_armed(&self) -> bool
+    {
+        self.speed_switch_armed
+    }
+
+    pub fn switch_speed(&mut self)
+    {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
     }
 
     pub fn read_byte(&self, address: u16) -> u8
+    {
+        self.read_as(address, cdl::DATA)
+    }
+
+    /// Read the first byte of an instruction, or the opcode after a 0xCB prefix
+    pub fn fetch_opcode(&self, address: u16) -> u8
+    {
+        self.read_as(address, cdl::OPCODE)
+    }
+
+    /// Read an immediate value or address that follows an opcode
+    pub fn fetch_operand(&self, address: u16) -> u8
+    {
+        self.read_as(address, cdl::OPERAND)
+    }
+
+    /// A read by the game, which the code/data log records as the kind of access given
+    fn read_as(&self, address: u16, access: u8) -> u8
+    {
+        let value = self.read_memory(address);
+        if !self.watchpoints.is_empty()
+        {
+            self.check_watchpoints(WatchHit { address, value, write: false });
+        }
+        if let Some(log) = self.code_data_log.as_ref()
+        {
+            self.log_access(log, address, access);
+        }
+        value
+    }
+
+    fn log_access(&self, log: &CodeDataLog, address: u16, access: u8)
+    {
+        let address = address as usize;
+        if address > ROM_END || self.boot_rom_mapped(address)
+        {
+            return;
+        }
+        if let Some(cartridge) = self.cartridge.as_ref()
+        {
+            log.mark(cartridge.rom_offset(address as u16), access);
+        }
+    }
+
+    /// Read memory for a debugger, without any of the side effects of the game reading it.
+    /// Watchpoints aren't set off, wave RAM reads as stored while the channel plays and LY
+    /// isn't stubbed for traces.
+    pub fn peek_byte(&self, address: u16) -> u8
+    {
+        match address as usize
+        {
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.apu.peek_wave_ram(address as usize),
+            LY_ADDRESS => self.gpu.read_register(LY_ADDRESS),
+            _ => self.read_memory(address),
+        }
+    }
+
+    /// What the game reads from an address
+    fn read_memory(&self, address: u16) -> u8
     {
         let address = address as usize;
         match address
         {
+            BOOT_ROM_BEGIN..=BOOT_ROM_END if self.boot_rom_mapped(address) =>
+            {
+                self.boot_rom[address]
+            }
+            CGB_BOOT_ROM_BEGIN..=CGB_BOOT_ROM_END if self.boot_rom_mapped(address) =>
+            {
+                self.boot_rom[address]
+            }
+            ROM_BEGIN..=ROM_END => match &self.cartridge
+            {
+                Some(cartridge) =>
+                {
+                    self.cheats.read_rom(address as u16, cartridge.read_rom(address as u16))
+                }
+                None => 0xFF,
+            },
             VRAM_BEGIN..=VRAM_END => self.gpu.read_vram(address - VRAM_BEGIN),
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => match &self.cartridge
+            {
+                Some(cartridge) => cartridge.read_ram(address as u16),
+                None => 0xFF,
+            },
+            WRAM_BEGIN..=WRAM_END => self.wram[self.wram_offset(address)],
+            ECHO_RAM_BEGIN..=ECHO_RAM_END => self.wram[self.wram_offset(address - 0x2000)],
+            OAM_BEGIN..=OAM_END => self.gpu.oam[address - OAM_BEGIN],
+            JOYP_ADDRESS => self.joypad.read(),
+            SB_ADDRESS => self.serial.read_data(),
+            DIV_ADDRESS..=TAC_ADDRESS => self.timer.read_register(address),
+            SC_ADDRESS => self.serial.read_control(),
+            // The top three bits of IF are unused and read as 1
+            INTERRUPT_FLAG_ADDRESS => 0xE0 | self.interrupt_flag.to_byte(),
+            NR10_ADDRESS..=NR52_ADDRESS => self.apu.read_register(address),
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.apu.read_wave_ram(address),
+            DMA_ADDRESS => self.memory[address],
+            LY_ADDRESS if self.stub_ly => 0x90,
+            LCDC_ADDRESS..=WX_ADDRESS => self.gpu.read_register(address),
+            KEY1_ADDRESS if self.cgb_mode =>
+            {
+                let current_speed = if self.double_speed { 0x80 } else { 0 };
+                current_speed | 0x7E | self.speed_switch_armed as u8
+            }
+            VBK_ADDRESS | BCPS_ADDRESS..=OCPD_ADDRESS => self.gpu.read_register(address),
+            // The source and destination registers are write only
+            HDMA1_ADDRESS..HDMA5_ADDRESS => 0xFF,
+            HDMA5_ADDRESS if self.cgb_mode => self.hdma.read_length(),
+            SVBK_ADDRESS if self.cgb_mode => 0xF8 | self.wram_bank as u8,
+            // CGB registers are unmapped outside of CGB mode
+            KEY1_ADDRESS | HDMA5_ADDRESS | SVBK_ADDRESS => 0xFF,
+            INTERRUPT_ENABLE_ADDRESS => self.interrupt_enable.to_byte(),
             _ => self.memory[address],
         }
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8)
     {
+        if !self.watchpoints.is_empty()
+        {
+            self.check_watchpoints(WatchHit { address, value, write: true });
+        }
+
         let address = address as usize;
         match address
         {
+            ROM_BEGIN..=ROM_END =>
+            {
+                if let Some(cartridge) = self.cartridge.as_mut()
+                {
+                    cartridge.write_rom(address as u16, value);
+                }
+            }
             VRAM_BEGIN..=VRAM_END => self.gpu.write_vram(address - VRAM_BEGIN, value),
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END =>
+            {
+                if let Some(cartridge) = self.cartridge.as_mut()
+                {
+                    cartridge.write_ram(address as u16, value);
+                }
+            }
+            WRAM_BEGIN..=WRAM_END =>
+            {
+                let offset = self.wram_offset(address);
+                self.wram[offset] = value;
+            }
+            ECHO_RAM_BEGIN..=ECHO_RAM_END =>
+            {
+                let offset = self.wram_offset(address - 0x2000);
+                self.wram[offset] = value;
+            }
+            OAM_BEGIN..=OAM_END => self.gpu.oam[address - OAM_BEGIN] = value,
+            JOYP_ADDRESS =>
+            {
+                self.joypad.write(value);
+                if let Some(sgb) = self.sgb.as_mut()
+                {
+                    sgb.write_joypad(value);
+                    self.joypad.set_current_player(sgb.current_player());
+                }
+            }
+            SB_ADDRESS => self.serial.write_data(value),
+            DIV_ADDRESS..=TAC_ADDRESS =>
+            {
+                if self.timer.write_register(address, value)
+                {
+                    self.interrupt_flag.timer = true;
+                }
+            }
+            SC_ADDRESS => self.serial.write_control(value),
+            INTERRUPT_FLAG_ADDRESS => self.interrupt_flag = InterruptFlags::from_byte(value),
+            NR10_ADDRESS..=NR52_ADDRESS => self.apu.write_register(address, value),
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.apu.write_wave_ram(address, value),
+            DMA_ADDRESS =>
+            {
+                self.memory[address] = value;
+                self.dma_transfer(value);
+            }
+            LCDC_ADDRESS..=WX_ADDRESS => self.gpu.write_register(address, value),
+            KEY1_ADDRESS if self.cgb_mode => self.speed_switch_armed = value & 0x01 != 0,
+            VBK_ADDRESS | BCPS_ADDRESS..=OCPD_ADDRESS => self.gpu.write_register(address, value),
+            HDMA1_ADDRESS..=HDMA5_ADDRESS if self.cgb_mode =>
+            {
+                if self.hdma.write_register(address, value)
+                {
+                    while self.transfer_hdma_block()
+                    {}
+                }
+                else if address == HDMA5_ADDRESS
+                    && self.hdma.hblank_active()
+                    && self.gpu.mode == Mode::HorizontalBlank
+                {
+                    // An HBlank transfer started during HBlank, or with the LCD off, copies its
+                    // first block straight away
+                    self.transfer_hdma_block();
+                }
+            }
+            BOOT_ROM_DISABLE_ADDRESS if value != 0 && self.boot_rom_enabled =>
+            {
+                self.boot_rom_enabled = false;
+                self.apply_palette_override();
+            }
+            // Selecting bank 0 selects bank 1 instead
+            SVBK_ADDRESS if self.cgb_mode => self.wram_bank = ((value & 0x07) as usize).max(1),
+            INTERRUPT_ENABLE_ADDRESS => self.interrupt_enable = InterruptFlags::from_byte(value),
+            _ => self.memory[address] = value,
+        }
+    }
+
+    /// Write memory for a debugger, without any of the side effects of the game writing it.
+    /// Writes to ROM patch the mapped bank instead of programming the memory bank controller,
+    /// external RAM is written even while disabled and registers are set as they read back,
+    /// without starting DMA, serial transfers or sound.
+    pub fn poke_byte(&mut self, address: u16, value: u8)
+    {
+        let address = address as usize;
+        match address
+        {
+            BOOT_ROM_BEGIN..=BOOT_ROM_END if self.boot_rom_mapped(address) =>
+            {
+                self.boot_rom[address] = value;
+            }
+            CGB_BOOT_ROM_BEGIN..=CGB_BOOT_ROM_END if self.boot_rom_mapped(address) =>
+            {
+                self.boot_rom[address] = value;
+            }
+            ROM_BEGIN..=ROM_END | EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END | VRAM_BEGIN..=VRAM_END =>
+            {
+                let bank = self.bank_at(address as u16);
+                self.poke_bank(bank, address as u16, value);
+            }
+            WRAM_BEGIN..=WRAM_END =>
+            {
+                let offset = self.wram_offset(address);
+                self.wram[offset] = value;
+            }
+            ECHO_RAM_BEGIN..=ECHO_RAM_END =>
+            {
+                let offset = self.wram_offset(address - 0x2000);
+                self.wram[offset] = value;
+            }
+            OAM_BEGIN..=OAM_END => self.gpu.oam[address - OAM_BEGIN] = value,
+            JOYP_ADDRESS => self.joypad.write(value),
+            SB_ADDRESS => self.serial.write_data(value),
+            DIV_ADDRESS..=TAC_ADDRESS => self.timer.poke_register(address, value),
+            SC_ADDRESS => self.serial.poke_control(value),
+            INTERRUPT_FLAG_ADDRESS => self.interrupt_flag = InterruptFlags::from_byte(value),
+            NR10_ADDRESS..=NR52_ADDRESS => self.apu.poke_register(address, value),
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.apu.poke_wave_ram(address, value),
+            LCDC_ADDRESS..=WX_ADDRESS if address != DMA_ADDRESS =>
+            {
+                self.gpu.poke_register(address, value);
+            }
+            KEY1_ADDRESS if self.cgb_mode => self.speed_switch_armed = value & 0x01 != 0,
+            VBK_ADDRESS | BCPS_ADDRESS..=OCPD_ADDRESS => self.gpu.poke_register(address, value),
+            HDMA5_ADDRESS if self.cgb_mode => self.hdma.poke_length(value),
+            HDMA1_ADDRESS..HDMA5_ADDRESS if self.cgb_mode =>
+            {
+                self.hdma.write_register(address, value);
+            }
+            // The boot ROM can only be unmapped, as on the real hardware
+            BOOT_ROM_DISABLE_ADDRESS if value != 0 => self.boot_rom_enabled = false,
+            SVBK_ADDRESS if self.cgb_mode => self.wram_bank = ((value & 0x07) as usize).max(1),
+            INTERRUPT_ENABLE_ADDRESS => self.interrupt_enable = InterruptFlags::from_byte(value),
             _ => self.memory[address] = value,
         }
     }
+
+    /// `poke_byte` into a bank of the memory at an address, whether or not that bank is mapped
+    pub fn poke_bank(&mut self, bank: usize, address: u16, value: u8)
+    {
+        match address as usize
+        {
+            ROM_BEGIN..=ROM_END =>
+            {
+                if let Some(cartridge) = self.cartridge.as_mut()
+                {
+                    cartridge.poke_rom_bank(bank, address, value);
+                }
+            }
+            VRAM_BEGIN..=VRAM_END =>
+            {
+                self.gpu.poke_vram(bank & 1, address as usize - VRAM_BEGIN, value)
+            }
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END =>
+            {
+                if let Some(cartridge) = self.cartridge.as_mut()
+                {
+                    cartridge.poke_ram_bank(bank, address, value);
+                }
+            }
+            WRAM_BEGIN..=WRAM_END if address as usize >= WRAM_BEGIN + WRAM_BANK_SIZE =>
+            {
+                let offset = address as usize - WRAM_BEGIN - WRAM_BANK_SIZE;
+                self.wram[mapped_bank(address, bank) * WRAM_BANK_SIZE + offset] = value;
+            }
+            _ => self.poke_byte(address, value),
+        }
+    }
+
+    /// Write the values of GameShark codes into RAM, as the GameShark does during VBlank
+    fn apply_ram_cheats(&mut self)
+    {
+        let writes: Vec<_> = self.cheats.ram_writes().collect();
+        for (bank, address, value) in writes
+        {
+            match bank
+            {
+                Some(bank) => self.poke_bank(bank, address, value),
+                None => self.poke_byte(address, value),
+            }
+        }
+    }
+
+    fn check_watchpoints(&self, access: WatchHit)
+    {
+        let watched = self.watchpoints.iter().any(|watchpoint| {
+            (watchpoint.begin..=watchpoint.end).contains(&access.address)
+                && if access.write { watchpoint.write } else { watchpoint.read }
+        });
+        if watched && self.watch_hit.get().is_none()
+        {
+            self.watch_hit.set(Some(access));
+        }
+    }
+
+    /// The first watched access since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit>
+    {
+        self.watch_hit.take()
+    }
+
+    /// Like the CGB boot ROM, let a direction held with A or B replace the palette picked for
+    /// a monochrome game
+    fn apply_palette_override(&mut self)
+    {
+        if !self.compatibility_palette
+        {
+            return;
+        }
+        if let Some(palette) = CompatibilityPalette::for_buttons(&self.joypad)
+        {
+            self.gpu.set_compatibility_palette(&palette);
+        }
+    }
+
+    fn draw_logo(&mut self)
+    {
+        // Each bit of the logo becomes a 2x2 block, so every byte fills four rows of a tile
+        for index in 0..LOGO_SIZE
+        {
+            let byte = self.read_byte(LOGO_BEGIN + index);
+            for (half, nibble) in [byte >> 4, byte & 0x0F].into_iter().enumerate()
+            {
+                let row = (0..4).fold(0u8, |row, bit| {
+                    let doubled = if nibble & (0x08 >> bit) != 0 { 0x03 } else { 0x00 };
+                    row | doubled << (6 - bit * 2)
+                });
+                let offset = LOGO_TILES_OFFSET + index as usize * 8 + half * 4;
+                self.gpu.write_vram(offset, row);
+                self.gpu.write_vram(offset + 2, row);
+            }
+        }
+
+        for (index, &row) in REGISTERED_TILE.iter().enumerate()
+        {
+            self.gpu.write_vram(REGISTERED_TILE_OFFSET + index * 2, row);
+        }
+
+        for (line, &offset) in LOGO_MAP_OFFSETS.iter().enumerate()
+        {
+            for column in 0..12
+            {
+                self.gpu.write_vram(offset + column, (line * 12 + column + 1) as u8);
+            }
+        }
+        self.gpu.write_vram(REGISTERED_MAP_OFFSET, 0x19);
+    }
+
+    fn boot_rom_mapped(&self, address: usize) -> bool
+    {
+        self.boot_rom_enabled && address < self.boot_rom.len()
+    }
+
+    /// The bank mapped at an address, for telling apart symbols at the same address in different
+    /// banks. Memory without banks is bank 0.
+    pub fn bank_at(&self, address: u16) -> usize
+    {
+        match address as usize
+        {
+            ROM_BEGIN..=ROM_END =>
+            {
+                self.cartridge.as_ref().map_or(0, |cartridge| cartridge.rom_bank_at(address))
+            }
+            VRAM_BEGIN..=VRAM_END => self.gpu.vram_bank(),
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END =>
+            {
+                self.cartridge.as_ref().map_or(0, Cartridge::ram_bank)
+            }
+            WRAM_BEGIN..=WRAM_END if address as usize >= WRAM_BEGIN + WRAM_BANK_SIZE =>
+            {
+                self.wram_bank
+            }
+            _ => 0,
+        }
+    }
+
+    /// `peek_byte` from a bank of the memory at an address, whether or not that bank is mapped.
+    /// Memory without banks is read as usual.
+    pub fn peek_bank(&self, bank: usize, address: u16) -> u8
+    {
+        match address as usize
+        {
+            ROM_BEGIN..=ROM_END => match &self.cartridge
+            {
+                Some(cartridge) => cartridge.peek_rom_bank(bank, address),
+                None => 0xFF,
+            },
+            VRAM_BEGIN..=VRAM_END if bank == 1 => self.gpu.vram1[address as usize - VRAM_BEGIN],
+            VRAM_BEGIN..=VRAM_END => self.gpu.vram[address as usize - VRAM_BEGIN],
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => match &self.cartridge
+            {
+                Some(cartridge) => cartridge.peek_ram_bank(bank, address),
+                None => 0xFF,
+            },
+            WRAM_BEGIN..=WRAM_END if address as usize >= WRAM_BEGIN + WRAM_BANK_SIZE =>
+            {
+                let offset = address as usize - WRAM_BEGIN - WRAM_BANK_SIZE;
+                self.wram[mapped_bank(address, bank) * WRAM_BANK_SIZE + offset]
+            }
+            _ => self.peek_byte(address),
+        }
+    }
+
+    fn wram_offset(&self, address: usize) -> usize
+    {
+        let offset = address - WRAM_BEGIN;
+        if offset < WRAM_BANK_SIZE
+        {
+            offset
+        }
+        else
+        {
+            self.wram_bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    /// Copy the next 16 byte block of a VRAM DMA and stall the CPU for it. Returns false once
+    /// there is nothing left to copy.
+    fn transfer_hdma_block(&mut self) -> bool
+    {
+        let Some((source, destination)) = self.hdma.next_block()
+        else
+        {
+            return false;
+        };
+
+        for offset in 0..HDMA_BLOCK_SIZE
+        {
+            let value = self.read_as(source.wrapping_add(offset), cdl::DMA_SOURCE);
+            self.gpu.write_vram((destination + offset) as usize, value);
+        }
+
+        // The transfer takes the same time at both speeds, so twice as many CPU cycles when
+        // running at double speed
+        self.dma_stall_cycles +=
+            if self.double_speed { HDMA_BLOCK_CYCLES * 2 } else { HDMA_BLOCK_CYCLES };
+        true
+    }
+
+    /// Copy 160 bytes from `value << 8` into OAM
+    fn dma_transfer(&mut self, value: u8)
+    {
+        let source = (value as u16) << 8;
+        for offset in 0..OAM_SIZE
+        {
+            self.gpu.oam[offset] = self.read_as(source + offset as u16, cdl::DMA_SOURCE);
+        }
+    }
+}
+
+// The boot ROM and the ROM itself aren't part of a state, just whether the boot ROM is mapped
+impl SaveState for MemoryBus
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bytes(&self.memory);
+        writer.bool(self.boot_rom_enabled);
+        writer.bool(self.cartridge.is_some());
+        if let Some(cartridge) = self.cartridge.as_ref()
+        {
+            cartridge.save_state(writer);
+        }
+        writer.bytes(&self.wram);
+        writer.usize(self.wram_bank);
+        self.gpu.save_state(writer);
+        self.apu.save_state(writer);
+        self.timer.save_state(writer);
+        self.joypad.save_state(writer);
+        if let Some(sgb) = self.sgb.as_ref()
+        {
+            sgb.save_state(writer);
+        }
+        self.serial.save_state(writer);
+        writer.u8(self.interrupt_enable.to_byte());
+        writer.u8(self.interrupt_flag.to_byte());
+        self.hdma.save_state(writer);
+        writer.u32(self.dma_stall_cycles);
+        writer.bool(self.cgb_mode);
+        writer.bool(self.compatibility_palette);
+        writer.bool(self.double_speed);
+        writer.bool(self.speed_switch_armed);
+        writer.u32(self.frame_cycles);
+        writer.bool(self.frame_completed);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        reader.bytes(&mut self.memory)?;
+        self.boot_rom_enabled = reader.bool()?;
+        if reader.bool()? != self.cartridge.is_some()
+        {
+            return Err(invalid_state("state doesn't match the cartridge slot"));
+        }
+        if let Some(cartridge) = self.cartridge.as_mut()
+        {
+            cartridge.load_state(reader)?;
+        }
+        reader.bytes(&mut self.wram)?;
+        self.wram_bank = reader.usize()?.clamp(1, WRAM_BANKS - 1);
+        self.gpu.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        if let Some(sgb) = self.sgb.as_mut()
+        {
+            sgb.load_state(reader)?;
+        }
+        self.serial.load_state(reader)?;
+        self.interrupt_enable = InterruptFlags::from_byte(reader.u8()?);
+        self.interrupt_flag = InterruptFlags::from_byte(reader.u8()?);
+        self.hdma.load_state(reader)?;
+        self.dma_stall_cycles = reader.u32()?;
+        self.cgb_mode = reader.bool()?;
+        self.compatibility_palette = reader.bool()?;
+        self.double_speed = reader.bool()?;
+        self.speed_switch_armed = reader.bool()?;
+        self.frame_cycles = reader.u32()?;
+        self.frame_completed = reader.bool()?;
+        Ok(())
+    }
 }
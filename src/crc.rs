@@ -0,0 +1,46 @@
+/// CRC-32 as used by zip and PNG, for telling ROMs apart and comparing frames
+pub struct Crc32
+{
+    crc: u32,
+}
+
+impl Default for Crc32
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Crc32
+{
+    pub fn new() -> Self
+    {
+        Crc32 { crc: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8])
+    {
+        for &byte in data
+        {
+            self.crc ^= byte as u32;
+            for _ in 0..8
+            {
+                let mask = (self.crc & 0x1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32
+    {
+        !self.crc
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
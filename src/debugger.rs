@@ -0,0 +1,801 @@
+pub mod expression;
+pub mod memory;
+
+use crate::cpu::disassembler::disassemble_with_labels;
+use crate::cpu::instruction::Instruction;
+use crate::cpu::memorybus::{mapped_bank, Watchpoint};
+use crate::cpu::CPU;
+use crate::gameboy::GameBoy;
+use crate::symbols::Symbols;
+use expression::Expression;
+use memory::{MemorySearch, SearchFilter};
+
+// Stack words searched for return addresses
+const BACKTRACE_DEPTH: u32 = 64;
+// Addresses listed by find and search, past which only the count is shown
+const MAX_RESULTS: usize = 16;
+
+const HELP: &str = "\
+break <addr> [if <cond>]   stop at an address, optionally only when a condition holds. A
+                           label or bank:addr only stops while that bank is mapped.
+watch <addr>[-<addr>] [r|w|rw]   stop when memory is read or written, writes by default
+delete [id]                remove a breakpoint or watchpoint, or all of them
+list                       show breakpoints and watchpoints
+continue                   run until something stops the game
+step [n]                   run n instructions, 1 by default
+next                       run an instruction, running CALLs and RSTs to completion
+finish                     run until the current function returns
+until <addr>               run to an address
+regs                       show registers, flags and the stack
+backtrace                  show the calls whose return addresses are on the stack
+x <addr> [len]             show memory, 40 bytes by default. A label or bank:addr shows that
+                           bank whether or not it's mapped.
+poke <addr> <byte>...      write bytes to memory without side effects, so writing ROM patches
+                           it and writing registers doesn't start anything. A label or
+                           bank:addr writes to that bank.
+find <byte>...             find a pattern of bytes, ?? matching any byte
+search [changed|unchanged|increased|decreased|<byte>]   start a RAM search, then narrow it
+                           down by how bytes changed since the last search
+cheat [add <code> [description]|on <n>|off <n>|delete <n>]   list cheats, add a Game Genie
+                           or GameShark code, turn a cheat on or off or remove it
+pause                      stop straight away
+Addresses and conditions are expressions of hexadecimal numbers, registers, flags (zf, nf, hf,
+cf), labels, [addr] for memory and the operators || && == != < <= > >= | & + - !";
+
+struct Breakpoint
+{
+    id: usize,
+    address: u16,
+    // Only stops while this bank is mapped at the address
+    bank: Option<usize>,
+    condition: Option<(String, Expression)>,
+}
+
+enum RunMode
+{
+    Continue,
+    Steps(u32),
+    // Run until the CALL returns to `return_pc` with the stack back where it was
+    StepOver
+    {
+        return_pc: u16,
+        sp: u16,
+    },
+    // Run until a RET leaves the stack above `sp`
+    StepOut
+    {
+        sp: u16,
+    },
+    RunTo(u16),
+}
+
+/// An interactive debugger driven by text commands, see `HELP`. The game only runs through
+/// `run_frame`, which stops at breakpoints, watchpoints and the end of steps.
+pub struct Debugger
+{
+    breakpoints: Vec<Breakpoint>,
+    // Ids of the watchpoints on the memory bus, in the same order
+    watchpoint_ids: Vec<usize>,
+    next_id: usize,
+    paused: bool,
+    mode: RunMode,
+    // Resuming from a breakpoint mustn't stop at it again straight away
+    skip_breakpoint_at: Option<u16>,
+    symbols: Symbols,
+    search: Option<MemorySearch>,
+}
+
+impl Default for Debugger
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Debugger
+{
+    pub fn new() -> Self
+    {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoint_ids: Vec::new(),
+            next_id: 1,
+            paused: false,
+            mode: RunMode::Continue,
+            skip_breakpoint_at: None,
+            symbols: Symbols::new(),
+            search: None,
+        }
+    }
+
+    /// Show addresses by their labels and allow labels in commands
+    pub fn set_symbols(&mut self, symbols: Symbols)
+    {
+        self.symbols = symbols;
+    }
+
+    pub fn is_paused(&self) -> bool
+    {
+        self.paused
+    }
+
+    pub fn pause(&mut self)
+    {
+        self.paused = true;
+        self.mode = RunMode::Continue;
+    }
+
+    fn resume(&mut self, gameboy: &GameBoy, mode: RunMode)
+    {
+        self.paused = false;
+        self.mode = mode;
+        self.skip_breakpoint_at = Some(gameboy.cpu().pc);
+    }
+
+    /// Run the rest of a frame unless paused. Returns why the debugger stopped, if it did.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> Option<String>
+    {
+        if self.paused
+        {
+            return None;
+        }
+        loop
+        {
+            let cpu = gameboy.cpu();
+            let pc = cpu.pc;
+            if self.skip_breakpoint_at != Some(pc) && !cpu.is_halted()
+            {
+                if let Some(breakpoint) = self.breakpoint_hit(cpu)
+                {
+                    let message = format!("Breakpoint {} at {:04X}", breakpoint, pc);
+                    return Some(self.stop(gameboy, message));
+                }
+            }
+            self.skip_breakpoint_at = None;
+
+            let opcode = cpu.bus.peek_byte(pc);
+            let frame_completed = gameboy.step_instruction();
+
+            if let Some(hit) = gameboy.cpu_mut().bus.take_watch_hit()
+            {
+                let message = format!(
+                    "Watchpoint: {} {:02X} {} {:04X}",
+                    if hit.write { "wrote" } else { "read" },
+                    hit.value,
+                    if hit.write { "to" } else { "from" },
+                    hit.address
+                );
+                return Some(self.stop(gameboy, message));
+            }
+            if self.mode_finished(gameboy.cpu(), opcode)
+            {
+                return Some(self.stop(gameboy, String::new()));
+            }
+            if frame_completed
+            {
+                return None;
+            }
+        }
+    }
+
+    fn breakpoint_hit(&self, cpu: &CPU) -> Option<usize>
+    {
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| {
+                breakpoint.address == cpu.pc
+                    && breakpoint.bank.is_none_or(|bank| cpu.bus.bank_at(cpu.pc) == bank)
+                    && breakpoint
+                        .condition
+                        .as_ref()
+                        .is_none_or(|(_, condition)| condition.evaluate(cpu) != 0)
+            })
+            .map(|breakpoint| breakpoint.id)
+    }
+
+    /// Whether a step has finished after running `opcode`
+    fn mode_finished(&mut self, cpu: &CPU, opcode: u8) -> bool
+    {
+        match self.mode
+        {
+            RunMode::Continue => false,
+            RunMode::Steps(ref mut count) =>
+            {
+                *count -= 1;
+                *count == 0
+            }
+            RunMode::StepOver { return_pc, sp } => cpu.pc == return_pc && cpu.sp >= sp,
+            RunMode::StepOut { sp } => Instruction::is_return(opcode) && cpu.sp > sp,
+            RunMode::RunTo(address) => cpu.pc == address,
+        }
+    }
+
+    fn stop(&mut self, gameboy: &GameBoy, message: String) -> String
+    {
+        self.pause();
+        let location = current_instruction(gameboy.cpu(), &self.symbols);
+        if message.is_empty()
+        {
+            location
+        }
+        else
+        {
+            format!("{}\n{}", message, location)
+        }
+    }
+
+    /// Run a command typed by the user and return what to show them
+    pub fn execute(&mut self, gameboy: &mut GameBoy, line: &str) -> String
+    {
+        let line = line.trim();
+        let (command, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let arguments = arguments.trim();
+        let result = match command
+        {
+            "" => Ok(String::new()),
+            "break" | "b" => self.add_breakpoint(gameboy.cpu(), arguments),
+            "watch" | "w" => self.add_watchpoint(gameboy, arguments),
+            "delete" | "d" => self.delete(gameboy, arguments),
+            "list" | "l" => Ok(self.list(gameboy)),
+            "continue" | "c" =>
+            {
+                self.resume(gameboy, RunMode::Continue);
+                Ok(String::new())
+            }
+            "step" | "s" => parse_count(arguments).map(|count| {
+                self.resume(gameboy, RunMode::Steps(count));
+                String::new()
+            }),
+            "next" | "n" =>
+            {
+                let cpu = gameboy.cpu();
+                let opcode = cpu.bus.peek_byte(cpu.pc);
+                let mode = if Instruction::is_call(opcode)
+                {
+                    RunMode::StepOver { return_pc: cpu.pc.wrapping_add(3), sp: cpu.sp }
+                }
+                else if Instruction::is_rst(opcode)
+                {
+                    RunMode::StepOver { return_pc: cpu.pc.wrapping_add(1), sp: cpu.sp }
+                }
+                else
+                {
+                    RunMode::Steps(1)
+                };
+                self.resume(gameboy, mode);
+                Ok(String::new())
+            }
+            "finish" | "f" =>
+            {
+                self.resume(gameboy, RunMode::StepOut { sp: gameboy.cpu().sp });
+                Ok(String::new())
+            }
+            "until" | "u" => self.parse_address(gameboy.cpu(), arguments).map(|address| {
+                self.resume(gameboy, RunMode::RunTo(address));
+                String::new()
+            }),
+            "regs" | "r" => Ok(registers(gameboy.cpu(), &self.symbols)),
+            "backtrace" | "bt" => Ok(backtrace(gameboy.cpu(), &self.symbols)),
+            "x" => self.examine(gameboy.cpu(), arguments),
+            "poke" => self.poke(gameboy, arguments),
+            "find" => find(gameboy.cpu(), arguments),
+            "search" => self.search(gameboy.cpu(), arguments),
+            "cheat" => cheat(gameboy, arguments),
+            "pause" | "p" =>
+            {
+                self.pause();
+                Ok(current_instruction(gameboy.cpu(), &self.symbols))
+            }
+            "help" | "h" | "?" => Ok(HELP.to_string()),
+            _ => Err(format!("Unknown command {}, try help", command)),
+        };
+        result.unwrap_or_else(|error| format!("Error: {}", error))
+    }
+
+    fn add_breakpoint(&mut self, cpu: &CPU, arguments: &str) -> Result<String, String>
+    {
+        let (address, condition) = match arguments.split_once(" if ")
+        {
+            Some((address, condition)) =>
+            {
+                let condition = condition.trim();
+                let expression = Expression::parse(condition, &self.symbols)?;
+                (address, Some((condition.to_string(), expression)))
+            }
+            None => (arguments, None),
+        };
+        let (bank, address) = self.parse_location(cpu, address.trim())?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, address, bank, condition });
+        Ok(format!("Breakpoint {} at {}", id, self.describe_location(bank, address)))
+    }
+
+    /// A label or `bank:address` for a particular bank, otherwise an address in any bank
+    fn parse_location(&self, cpu: &CPU, text: &str) -> Result<(Option<usize>, u16), String>
+    {
+        if let Some((bank, address)) = self.symbols.find(text)
+        {
+            return Ok((Some(bank), address));
+        }
+        if let Some((bank, address)) = text.split_once(':')
+        {
+            if let (Ok(bank), Ok(address)) =
+                (usize::from_str_radix(bank, 16), u16::from_str_radix(address, 16))
+            {
+                return Ok((Some(mapped_bank(address, bank)), address));
+            }
+        }
+        Ok((None, self.parse_address(cpu, text)?))
+    }
+
+    fn describe_location(&self, bank: Option<usize>, address: u16) -> String
+    {
+        let Some(bank) = bank
+        else
+        {
+            return format!("{:04X}", address);
+        };
+        match self.symbols.describe_in(bank, address)
+        {
+            Some(name) => format!("{:02X}:{:04X} <{}>", bank, address, name),
+            None => format!("{:02X}:{:04X}", bank, address),
+        }
+    }
+
+    fn parse_address(&self, cpu: &CPU, text: &str) -> Result<u16, String>
+    {
+        if text.trim().is_empty()
+        {
+            return Err("missing address".to_string());
+        }
+        Ok(Expression::parse(text, &self.symbols)?.evaluate(cpu) as u16)
+    }
+
+    /// Show `len` bytes from an address, 16 to a line
+    fn examine(&self, cpu: &CPU, arguments: &str) -> Result<String, String>
+    {
+        let (address, length) = match arguments.split_once(char::is_whitespace)
+        {
+            Some((address, length)) =>
+            {
+                let length = u16::from_str_radix(length.trim(), 16)
+                    .map_err(|_| format!("{} isn't a hexadecimal length", length.trim()))?;
+                (address, length)
+            }
+            None => (arguments, 0x40),
+        };
+        let (bank, start) = self.parse_location(cpu, address.trim())?;
+        Ok(memory::dump(&cpu.bus, bank, start, length))
+    }
+
+    fn poke(&self, gameboy: &mut GameBoy, arguments: &str) -> Result<String, String>
+    {
+        let mut words = arguments.split_whitespace();
+        let location = words.next().ok_or("missing address")?;
+        let (bank, address) = self.parse_location(gameboy.cpu(), location)?;
+        let bytes = words
+            .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("{} isn't a byte", byte)))
+            .collect::<Result<Vec<u8>, String>>()?;
+        if bytes.is_empty()
+        {
+            return Err("missing bytes to write".to_string());
+        }
+        let bus = &mut gameboy.cpu_mut().bus;
+        for (offset, &byte) in bytes.iter().enumerate()
+        {
+            let address = address.wrapping_add(offset as u16);
+            match bank
+            {
+                Some(bank) => bus.poke_bank(bank, address, byte),
+                None => bus.poke_byte(address, byte),
+            }
+        }
+        Ok(memory::dump(bus, bank, address, bytes.len() as u16))
+    }
+
+    fn search(&mut self, cpu: &CPU, arguments: &str) -> Result<String, String>
+    {
+        let filter = match arguments.trim()
+        {
+            "" =>
+            {
+                self.search = Some(MemorySearch::new(&cpu.bus));
+                None
+            }
+            "changed" => Some(SearchFilter::Changed),
+            "unchanged" => Some(SearchFilter::Unchanged),
+            "increased" => Some(SearchFilter::Increased),
+            "decreased" => Some(SearchFilter::Decreased),
+            value => match u8::from_str_radix(value, 16)
+            {
+                Ok(value) => Some(SearchFilter::Equal(value)),
+                Err(_) => return Err(format!("can't search for {}", value)),
+            },
+        };
+        let search = self.search.as_mut().ok_or("start a search with search first")?;
+        if let Some(filter) = filter
+        {
+            search.filter(&cpu.bus, filter);
+        }
+        let candidates = search.candidates();
+        let results = candidates
+            .iter()
+            .map(|&address| format!("{:04X}={:02X}", address, cpu.bus.peek_byte(address)));
+        Ok(list_results(candidates.len(), results))
+    }
+
+    fn add_watchpoint(&mut self, gameboy: &mut GameBoy, arguments: &str) -> Result<String, String>
+    {
+        let (range, access) = arguments.split_once(char::is_whitespace).unwrap_or((arguments, "w"));
+        let (read, write) = match access.trim()
+        {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            access => return Err(format!("access must be r, w or rw, not {}", access)),
+        };
+        let (begin, end) = match range.split_once('-')
+        {
+            Some((begin, end)) =>
+            {
+                let cpu = gameboy.cpu();
+                (self.parse_address(cpu, begin)?, self.parse_address(cpu, end)?)
+            }
+            None =>
+            {
+                let address = self.parse_address(gameboy.cpu(), range)?;
+                (address, address)
+            }
+        };
+        if end < begin
+        {
+            return Err("range ends before it begins".to_string());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoint_ids.push(id);
+        let watchpoint = Watchpoint { begin, end, read, write };
+        gameboy.cpu_mut().bus.watchpoints.push(watchpoint);
+        Ok(format!("Watchpoint {} on {}", id, describe_watchpoint(&watchpoint)))
+    }
+
+    fn delete(&mut self, gameboy: &mut GameBoy, arguments: &str) -> Result<String, String>
+    {
+        let watchpoints = &mut gameboy.cpu_mut().bus.watchpoints;
+        if arguments.is_empty()
+        {
+            self.breakpoints.clear();
+            self.watchpoint_ids.clear();
+            watchpoints.clear();
+            return Ok("Deleted everything".to_string());
+        }
+
+        let id = arguments.parse().map_err(|_| format!("{} isn't an id", arguments))?;
+        if let Some(index) = self.breakpoints.iter().position(|breakpoint| breakpoint.id == id)
+        {
+            self.breakpoints.remove(index);
+        }
+        else if let Some(index) = self.watchpoint_ids.iter().position(|&other| other == id)
+        {
+            self.watchpoint_ids.remove(index);
+            watchpoints.remove(index);
+        }
+        else
+        {
+            return Err(format!("no breakpoint or watchpoint {}", id));
+        }
+        Ok(format!("Deleted {}", id))
+    }
+
+    fn list(&self, gameboy: &GameBoy) -> String
+    {
+        let mut lines = Vec::new();
+        for breakpoint in self.breakpoints.iter()
+        {
+            let location = self.describe_location(breakpoint.bank, breakpoint.address);
+            let mut line = format!("{}: break {}", breakpoint.id, location);
+            if let Some((text, _)) = breakpoint.condition.as_ref()
+            {
+                line += &format!(" if {}", text);
+            }
+            lines.push(line);
+        }
+        for (id, watchpoint) in self.watchpoint_ids.iter().zip(gameboy.cpu().bus.watchpoints.iter())
+        {
+            lines.push(format!("{}: watch {}", id, describe_watchpoint(watchpoint)));
+        }
+        if lines.is_empty()
+        {
+            return "No breakpoints or watchpoints".to_string();
+        }
+        lines.join("\n")
+    }
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String
+{
+    let access = match (watchpoint.read, watchpoint.write)
+    {
+        (true, true) => "rw",
+        (true, false) => "r",
+        _ => "w",
+    };
+    if watchpoint.begin == watchpoint.end
+    {
+        format!("{:04X} {}", watchpoint.begin, access)
+    }
+    else
+    {
+        format!("{:04X}-{:04X} {}", watchpoint.begin, watchpoint.end, access)
+    }
+}
+
+fn parse_count(text: &str) -> Result<u32, String>
+{
+    if text.is_empty()
+    {
+        return Ok(1);
+    }
+    match text.parse()
+    {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("{} isn't a number of steps", text)),
+    }
+}
+
+fn find(cpu: &CPU, arguments: &str) -> Result<String, String>
+{
+    let pattern = arguments
+        .split_whitespace()
+        .map(|byte| match byte
+        {
+            "??" => Ok(None),
+            byte =>
+            {
+                u8::from_str_radix(byte, 16).map(Some).map_err(|_| format!("{} isn't a byte", byte))
+            }
+        })
+        .collect::<Result<Vec<Option<u8>>, String>>()?;
+    if pattern.is_empty()
+    {
+        return Err("missing bytes to find".to_string());
+    }
+    let addresses = memory::find(&cpu.bus, &pattern);
+    let results = addresses.iter().map(|address| format!("{:04X}", address));
+    Ok(list_results(addresses.len(), results))
+}
+
+fn cheat(gameboy: &mut GameBoy, arguments: &str) -> Result<String, String>
+{
+    let (action, rest) = arguments.split_once(char::is_whitespace).unwrap_or((arguments, ""));
+    let cheats = gameboy.cheats_mut();
+    if action == "add"
+    {
+        let (code, description) = rest.trim().split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let index = cheats.add(code, description)?;
+        return Ok(format!("Cheat {} added", index + 1));
+    }
+    if action.is_empty()
+    {
+        let lines: Vec<String> = cheats
+            .cheats()
+            .iter()
+            .enumerate()
+            .map(|(index, cheat)| {
+                let state = if cheat.enabled { "on" } else { "off" };
+                format!("{}: {:<3} {:<11} {}", index + 1, state, cheat.code(), cheat.description)
+            })
+            .collect();
+        if lines.is_empty()
+        {
+            return Ok("No cheats".to_string());
+        }
+        return Ok(lines.join("\n"));
+    }
+
+    let index = rest
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|&number| (1..=cheats.cheats().len()).contains(&number))
+        .ok_or_else(|| format!("{} isn't a cheat", rest.trim()))?
+        - 1;
+    match action
+    {
+        "on" | "off" =>
+        {
+            cheats.set_enabled(index, action == "on");
+            Ok(format!("Cheat {} {}", index + 1, action))
+        }
+        "delete" =>
+        {
+            cheats.remove(index);
+            Ok(format!("Deleted cheat {}", index + 1))
+        }
+        _ => Err(format!("unknown cheat command {}", action)),
+    }
+}
+
+/// How many results there are, then the first few of them
+fn list_results(count: usize, results: impl Iterator<Item = String>) -> String
+{
+    let shown: Vec<String> = results.take(MAX_RESULTS).collect();
+    if shown.is_empty()
+    {
+        return "Nothing found".to_string();
+    }
+    let more = if count > MAX_RESULTS { " ..." } else { "" };
+    format!("{} found\n{}{}", count, shown.join(" "), more)
+}
+
+/// The address, bytes and disassembly of the next instruction
+fn current_instruction(cpu: &CPU, symbols: &Symbols) -> String
+{
+    let bytes: Vec<u8> =
+        (0..3).map(|offset| cpu.bus.peek_byte(cpu.pc.wrapping_add(offset))).collect();
+    let labels = |address| symbols.label(&cpu.bus, address).map(str::to_string);
+    let instruction = disassemble_with_labels(&bytes, cpu.pc, &labels);
+    let hex: Vec<String> =
+        bytes[..instruction.length as usize].iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!("{}: {:<9} {}", location(cpu, symbols, cpu.pc), hex.join(" "), instruction.text)
+}
+
+/// An address with the label it falls under in the bank mapped now
+fn location(cpu: &CPU, symbols: &Symbols, address: u16) -> String
+{
+    match symbols.describe(&cpu.bus, address)
+    {
+        Some(name) => format!("{:04X} <{}>", address, name),
+        None => format!("{:04X}", address),
+    }
+}
+
+/// The current location, then every call that left its return address on the stack. Stack
+/// words that point just after a CALL or RST are taken to be return addresses, so anything the
+/// game pushed itself can show up too.
+fn backtrace(cpu: &CPU, symbols: &Symbols) -> String
+{
+    let mut lines = vec![format!("#0 {}", location(cpu, symbols, cpu.pc))];
+    let stack_top = cpu.sp as u32 + 2 * BACKTRACE_DEPTH;
+    for address in (cpu.sp as u32..stack_top.min(0xFFFE)).step_by(2)
+    {
+        let low = cpu.bus.peek_byte(address as u16);
+        let high = cpu.bus.peek_byte(address as u16 + 1);
+        let return_address = u16::from_le_bytes([low, high]);
+        let call = return_address.wrapping_sub(3);
+        let rst = return_address.wrapping_sub(1);
+        let call_site = if Instruction::is_call(cpu.bus.peek_byte(call))
+        {
+            call
+        }
+        else if Instruction::is_rst(cpu.bus.peek_byte(rst))
+        {
+            rst
+        }
+        else
+        {
+            continue;
+        };
+        lines.push(format!("#{} {}", lines.len(), location(cpu, symbols, call_site)));
+    }
+    lines.join("\n")
+}
+
+fn registers(cpu: &CPU, symbols: &Symbols) -> String
+{
+    let registers = &cpu.registers;
+    let flags = registers.f;
+    let flag = |set: bool, name: char| if set { name } else { '-' };
+    let stack: Vec<String> = (0..4)
+        .map(|index| {
+            let address = cpu.sp.wrapping_add(index * 2);
+            let low = cpu.bus.peek_byte(address) as u16;
+            let high = cpu.bus.peek_byte(address.wrapping_add(1)) as u16;
+            format!("{:04X}", high << 8 | low)
+        })
+        .collect();
+    format!(
+        "AF {:04X}  BC {:04X}  DE {:04X}  HL {:04X}\n\
+         SP {:04X}  PC {:04X}  Flags {}{}{}{}  IME {}  HALT {}\n\
+         Stack {}\n\
+         {}",
+        registers.get_af(),
+        registers.get_bc(),
+        registers.get_de(),
+        registers.get_hl(),
+        cpu.sp,
+        cpu.pc,
+        flag(flags.zero, 'Z'),
+        flag(flags.subtract, 'N'),
+        flag(flags.half_carry, 'H'),
+        flag(flags.carry, 'C'),
+        cpu.interrupts_enabled() as u8,
+        cpu.is_halted() as u8,
+        stack.join(" "),
+        current_instruction(cpu, symbols)
+    )
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::gameboy::test_gameboy;
+
+    // 0100 LD B,5; CALL $0110; LD A,B; LD HL,$C000; LD [HL],A; JR -2
+    // 0110 INC B; INC B; RET
+    const PROGRAM: [u8; 0x13] = [
+        0x06, 0x05, 0xCD, 0x10, 0x01, 0x78, 0x21, 0x00, 0xC0, 0x77, 0x18, 0xFE, 0x00, 0x00, 0x00,
+        0x00, 0x04, 0x04, 0xC9,
+    ];
+
+    /// Run `command` and then the game until the debugger stops, returning where it did
+    fn run(debugger: &mut Debugger, gameboy: &mut GameBoy, command: &str) -> Option<String>
+    {
+        assert_eq!(debugger.execute(gameboy, command), "");
+        debugger.run_frame(gameboy)
+    }
+
+    #[test]
+    fn steps_follow_calls_and_returns()
+    {
+        let mut gameboy = test_gameboy(&PROGRAM);
+        let mut debugger = Debugger::new();
+
+        run(&mut debugger, &mut gameboy, "step 2").unwrap();
+        assert_eq!(gameboy.cpu().pc, 0x0110);
+        run(&mut debugger, &mut gameboy, "finish").unwrap();
+        assert_eq!(gameboy.cpu().pc, 0x0105);
+        assert_eq!(gameboy.cpu().registers.b, 7);
+
+        let mut gameboy = test_gameboy(&PROGRAM);
+        run(&mut debugger, &mut gameboy, "step").unwrap();
+        assert_eq!(gameboy.cpu().pc, 0x0102);
+        let stop = run(&mut debugger, &mut gameboy, "next").unwrap();
+        assert!(stop.starts_with("0105: 78"), "{}", stop);
+        assert_eq!(gameboy.cpu().registers.b, 7);
+
+        let mut gameboy = test_gameboy(&PROGRAM);
+        run(&mut debugger, &mut gameboy, "until 111").unwrap();
+        assert_eq!(gameboy.cpu().pc, 0x0111);
+        assert_eq!(gameboy.cpu().registers.b, 6);
+    }
+
+    #[test]
+    fn breakpoints_stop_when_their_condition_holds()
+    {
+        let mut gameboy = test_gameboy(&PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut gameboy, "break 111 if b == 7");
+        assert_eq!(debugger.execute(&mut gameboy, "break 110"), "Breakpoint 2 at 0110");
+
+        let stop = run(&mut debugger, &mut gameboy, "continue").unwrap();
+        assert!(stop.starts_with("Breakpoint 2 at 0110"), "{}", stop);
+        assert!(debugger.is_paused());
+        assert_eq!(debugger.run_frame(&mut gameboy), None);
+
+        // B is 6 at 0111 so the game runs on into its loop until the frame ends
+        assert_eq!(run(&mut debugger, &mut gameboy, "continue"), None);
+        assert!(!debugger.is_paused());
+        assert_eq!(gameboy.cpu().pc, 0x010A);
+    }
+
+    #[test]
+    fn watchpoints_stop_on_their_access()
+    {
+        let mut gameboy = test_gameboy(&PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut gameboy, "watch c000 r");
+        debugger.execute(&mut gameboy, "watch bfff-c001 w");
+        assert_eq!(debugger.execute(&mut gameboy, "list"), "1: watch C000 r\n2: watch BFFF-C001 w");
+
+        let stop = run(&mut debugger, &mut gameboy, "continue").unwrap();
+        assert!(stop.starts_with("Watchpoint: wrote 07 to C000\n010A"), "{}", stop);
+
+        debugger.execute(&mut gameboy, "delete 2");
+        assert_eq!(run(&mut debugger, &mut gameboy, "continue"), None);
+    }
+}
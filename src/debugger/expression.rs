@@ -0,0 +1,417 @@
+use crate::cpu::CPU;
+use crate::symbols::Symbols;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Register
+{
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    ZeroFlag,
+    SubtractFlag,
+    HalfCarryFlag,
+    CarryFlag,
+}
+
+impl Register
+{
+    fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "a" => Some(Register::A),
+            "b" => Some(Register::B),
+            "c" => Some(Register::C),
+            "d" => Some(Register::D),
+            "e" => Some(Register::E),
+            "f" => Some(Register::F),
+            "h" => Some(Register::H),
+            "l" => Some(Register::L),
+            "af" => Some(Register::AF),
+            "bc" => Some(Register::BC),
+            "de" => Some(Register::DE),
+            "hl" => Some(Register::HL),
+            "sp" => Some(Register::SP),
+            "pc" => Some(Register::PC),
+            "zf" => Some(Register::ZeroFlag),
+            "nf" => Some(Register::SubtractFlag),
+            "hf" => Some(Register::HalfCarryFlag),
+            "cf" => Some(Register::CarryFlag),
+            _ => None,
+        }
+    }
+
+    fn value(self, cpu: &CPU) -> u32
+    {
+        let registers = &cpu.registers;
+        let value = match self
+        {
+            Register::A => registers.a as u16,
+            Register::B => registers.b as u16,
+            Register::C => registers.c as u16,
+            Register::D => registers.d as u16,
+            Register::E => registers.e as u16,
+            Register::F => u8::from(registers.f) as u16,
+            Register::H => registers.h as u16,
+            Register::L => registers.l as u16,
+            Register::AF => registers.get_af(),
+            Register::BC => registers.get_bc(),
+            Register::DE => registers.get_de(),
+            Register::HL => registers.get_hl(),
+            Register::SP => cpu.sp,
+            Register::PC => cpu.pc,
+            Register::ZeroFlag => registers.f.zero as u16,
+            Register::SubtractFlag => registers.f.subtract as u16,
+            Register::HalfCarryFlag => registers.f.half_carry as u16,
+            Register::CarryFlag => registers.f.carry as u16,
+        };
+        value as u32
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Operator
+{
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    BitOr,
+    BitAnd,
+    Add,
+    Subtract,
+}
+
+impl Operator
+{
+    // Operators are matched longest first so `<=` isn't read as `<`
+    const SYMBOLS: [(&'static str, Operator); 12] = [
+        ("||", Operator::Or),
+        ("&&", Operator::And),
+        ("==", Operator::Equal),
+        ("!=", Operator::NotEqual),
+        ("<=", Operator::LessOrEqual),
+        (">=", Operator::GreaterOrEqual),
+        ("<", Operator::Less),
+        (">", Operator::Greater),
+        ("|", Operator::BitOr),
+        ("&", Operator::BitAnd),
+        ("+", Operator::Add),
+        ("-", Operator::Subtract),
+    ];
+
+    /// Higher binds tighter
+    fn precedence(self) -> u8
+    {
+        match self
+        {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::Equal
+            | Operator::NotEqual
+            | Operator::Less
+            | Operator::LessOrEqual
+            | Operator::Greater
+            | Operator::GreaterOrEqual => 3,
+            Operator::BitOr => 4,
+            Operator::BitAnd => 5,
+            Operator::Add | Operator::Subtract => 6,
+        }
+    }
+
+    fn apply(self, left: u32, right: u32) -> u32
+    {
+        match self
+        {
+            Operator::Or => (left != 0 || right != 0) as u32,
+            Operator::And => (left != 0 && right != 0) as u32,
+            Operator::Equal => (left == right) as u32,
+            Operator::NotEqual => (left != right) as u32,
+            Operator::Less => (left < right) as u32,
+            Operator::LessOrEqual => (left <= right) as u32,
+            Operator::Greater => (left > right) as u32,
+            Operator::GreaterOrEqual => (left >= right) as u32,
+            Operator::BitOr => left | right,
+            Operator::BitAnd => left & right,
+            Operator::Add => left.wrapping_add(right),
+            Operator::Subtract => left.wrapping_sub(right),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token
+{
+    Number(u32),
+    Register(Register),
+    Operator(Operator),
+    Not,
+    OpenBracket,
+    CloseBracket,
+    OpenParen,
+    CloseParen,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Node
+{
+    Number(u32),
+    Register(Register),
+    Memory(Box<Node>),
+    Not(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+}
+
+/// A condition for a breakpoint such as `a == 3 && [ff44] >= 90`.
+///
+/// Numbers are hexadecimal and may start with `$` or `0x`. Register names and `zf`, `nf`, `hf`
+/// and `cf` for the flags take priority, then labels, so the hexadecimal number `a` has to be
+/// written as `$a`. `[address]` reads a byte of memory. Comparisons give 1 or 0 and anything
+/// other than 0 is true.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Expression
+{
+    root: Node,
+}
+
+impl Expression
+{
+    /// Labels are looked up in `symbols` straight away
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Self, String>
+    {
+        let tokens = tokenize(text, symbols)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let root = parser.binary(0)?;
+        if parser.position != tokens.len()
+        {
+            return Err(format!("unexpected {:?}", tokens[parser.position]));
+        }
+        Ok(Expression { root })
+    }
+
+    pub fn evaluate(&self, cpu: &CPU) -> u32
+    {
+        self.root.evaluate(cpu)
+    }
+}
+
+impl Node
+{
+    fn evaluate(&self, cpu: &CPU) -> u32
+    {
+        match self
+        {
+            Node::Number(value) => *value,
+            Node::Register(register) => register.value(cpu),
+            Node::Memory(address) => cpu.bus.peek_byte(address.evaluate(cpu) as u16) as u32,
+            Node::Not(operand) => (operand.evaluate(cpu) == 0) as u32,
+            Node::Binary(operator, left, right) =>
+            {
+                operator.apply(left.evaluate(cpu), right.evaluate(cpu))
+            }
+        }
+    }
+}
+
+fn tokenize(text: &str, symbols: &Symbols) -> Result<Vec<Token>, String>
+{
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(character) = rest.chars().next()
+    {
+        let (token, length) = if is_word_character(character)
+        {
+            let length = rest.find(|character| !is_word_character(character)).unwrap_or(rest.len());
+            (word_token(&rest[..length], symbols)?, length)
+        }
+        else if let Some((symbol, operator)) =
+            Operator::SYMBOLS.iter().find(|(symbol, _)| rest.starts_with(symbol))
+        {
+            (Token::Operator(*operator), symbol.len())
+        }
+        else
+        {
+            let token = match character
+            {
+                '!' => Token::Not,
+                '[' => Token::OpenBracket,
+                ']' => Token::CloseBracket,
+                '(' => Token::OpenParen,
+                ')' => Token::CloseParen,
+                _ => return Err(format!("unexpected '{}'", character)),
+            };
+            (token, 1)
+        };
+        tokens.push(token);
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// Numbers, registers and labels, which can hold `.` for local labels
+fn is_word_character(character: char) -> bool
+{
+    character.is_ascii_alphanumeric() || "$_.@".contains(character)
+}
+
+fn word_token(word: &str, symbols: &Symbols) -> Result<Token, String>
+{
+    if let Some(register) = Register::from_name(&word.to_lowercase())
+    {
+        return Ok(Token::Register(register));
+    }
+    if let Some((_, address)) = symbols.find(word)
+    {
+        return Ok(Token::Number(address as u32));
+    }
+    let digits = word.strip_prefix('$').or_else(|| word.strip_prefix("0x")).unwrap_or(word);
+    u32::from_str_radix(digits, 16)
+        .map(Token::Number)
+        .map_err(|_| format!("'{}' is neither a register, a label nor a hexadecimal number", word))
+}
+
+struct Parser<'a>
+{
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_>
+{
+    fn next(&mut self) -> Option<&Token>
+    {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String>
+    {
+        match self.next()
+        {
+            Some(token) if *token == expected => Ok(()),
+            _ => Err(format!("expected {:?}", expected)),
+        }
+    }
+
+    /// Parse operators binding at least as tightly as `minimum_precedence`
+    fn binary(&mut self, minimum_precedence: u8) -> Result<Node, String>
+    {
+        let mut left = self.unary()?;
+        while let Some(Token::Operator(operator)) = self.tokens.get(self.position)
+        {
+            let operator = *operator;
+            if operator.precedence() < minimum_precedence
+            {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(operator.precedence() + 1)?;
+            left = Node::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String>
+    {
+        match self.next().cloned()
+        {
+            Some(Token::Number(value)) => Ok(Node::Number(value)),
+            Some(Token::Register(register)) => Ok(Node::Register(register)),
+            Some(Token::Not) => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(Token::OpenBracket) =>
+            {
+                let address = self.binary(0)?;
+                self.expect(Token::CloseBracket)?;
+                Ok(Node::Memory(Box::new(address)))
+            }
+            Some(Token::OpenParen) =>
+            {
+                let expression = self.binary(0)?;
+                self.expect(Token::CloseParen)?;
+                Ok(expression)
+            }
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("expression ends too early".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::gameboy::test_gameboy;
+
+    fn evaluate(text: &str, cpu: &CPU) -> u32
+    {
+        Expression::parse(text, &Symbols::new()).unwrap().evaluate(cpu)
+    }
+
+    #[test]
+    fn operators_bind_by_precedence()
+    {
+        let gameboy = test_gameboy(&[]);
+        let cpu = gameboy.cpu();
+        assert_eq!(evaluate("2 + 3 & 6", cpu), 4);
+        assert_eq!(evaluate("1 | 2 == 3", cpu), 1);
+        assert_eq!(evaluate("1 || 0 && 0", cpu), 1);
+        assert_eq!(evaluate("(1 || 0) && 0", cpu), 0);
+        assert_eq!(evaluate("4 - 1 - 1", cpu), 2);
+        assert_eq!(evaluate("!(1 == 2) && 3 >= 3 && 2 < 1 == 0", cpu), 1);
+        assert_eq!(evaluate("$10 + 0x10 + ff", cpu), 0x11F);
+    }
+
+    #[test]
+    fn registers_and_memory_are_read_from_the_cpu()
+    {
+        let mut gameboy = test_gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        cpu.registers.a = 3;
+        cpu.registers.set_hl(0xC000);
+        cpu.registers.f.zero = true;
+        cpu.bus.write_byte(0xC001, 0x42);
+
+        let cpu = gameboy.cpu();
+        assert_eq!(evaluate("a == 3 && zf && !cf", cpu), 1);
+        assert_eq!(evaluate("$a", cpu), 0xA);
+        assert_eq!(evaluate("HL + 1", cpu), 0xC001);
+        assert_eq!(evaluate("[hl + 1]", cpu), 0x42);
+        assert_eq!(evaluate("[c001] == 42", cpu), 1);
+    }
+
+    #[test]
+    fn labels_are_addresses()
+    {
+        let gameboy = test_gameboy(&[]);
+        let symbols = Symbols::parse_sym("00:0150 Main\n00:0158 Main.loop\n");
+        let cpu = gameboy.cpu();
+        let expression = Expression::parse("pc < Main.loop && pc >= Main - 50", &symbols).unwrap();
+        assert_eq!(expression.evaluate(cpu), 1);
+    }
+
+    #[test]
+    fn malformed_expressions_are_refused()
+    {
+        let symbols = Symbols::new();
+        for text in ["", "1 +", "(1", "[c000", "1 2", "a ? 1", "nowhere", ")"]
+        {
+            assert!(Expression::parse(text, &symbols).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::cpu::memorybus::MemoryBus;
+
+// Names of the IO registers, shown next to their values in memory dumps
+const REGISTER_NAMES: [(u16, &str); 59] = [
+    (0xFF00, "P1"),
+    (0xFF01, "SB"),
+    (0xFF02, "SC"),
+    (0xFF04, "DIV"),
+    (0xFF05, "TIMA"),
+    (0xFF06, "TMA"),
+    (0xFF07, "TAC"),
+    (0xFF0F, "IF"),
+    (0xFF10, "NR10"),
+    (0xFF11, "NR11"),
+    (0xFF12, "NR12"),
+    (0xFF13, "NR13"),
+    (0xFF14, "NR14"),
+    (0xFF16, "NR21"),
+    (0xFF17, "NR22"),
+    (0xFF18, "NR23"),
+    (0xFF19, "NR24"),
+    (0xFF1A, "NR30"),
+    (0xFF1B, "NR31"),
+    (0xFF1C, "NR32"),
+    (0xFF1D, "NR33"),
+    (0xFF1E, "NR34"),
+    (0xFF20, "NR41"),
+    (0xFF21, "NR42"),
+    (0xFF22, "NR43"),
+    (0xFF23, "NR44"),
+    (0xFF24, "NR50"),
+    (0xFF25, "NR51"),
+    (0xFF26, "NR52"),
+    (0xFF40, "LCDC"),
+    (0xFF41, "STAT"),
+    (0xFF42, "SCY"),
+    (0xFF43, "SCX"),
+    (0xFF44, "LY"),
+    (0xFF45, "LYC"),
+    (0xFF46, "DMA"),
+    (0xFF47, "BGP"),
+    (0xFF48, "OBP0"),
+    (0xFF49, "OBP1"),
+    (0xFF4A, "WY"),
+    (0xFF4B, "WX"),
+    (0xFF4D, "KEY1"),
+    (0xFF4F, "VBK"),
+    (0xFF50, "BOOT"),
+    (0xFF51, "HDMA1"),
+    (0xFF52, "HDMA2"),
+    (0xFF53, "HDMA3"),
+    (0xFF54, "HDMA4"),
+    (0xFF55, "HDMA5"),
+    (0xFF56, "RP"),
+    (0xFF68, "BCPS"),
+    (0xFF69, "BCPD"),
+    (0xFF6A, "OCPS"),
+    (0xFF6B, "OCPD"),
+    (0xFF6C, "OPRI"),
+    (0xFF70, "SVBK"),
+    (0xFF76, "PCM12"),
+    (0xFF77, "PCM34"),
+    (0xFFFF, "IE"),
+];
+
+// Memory the game can change, which is where RAM searches look
+const SEARCH_RANGES: [(u16, u16); 3] = [(0xA000, 0xBFFF), (0xC000, 0xDFFF), (0xFF80, 0xFFFE)];
+
+pub fn register_name(address: u16) -> Option<&'static str>
+{
+    REGISTER_NAMES.iter().find(|&&(register, _)| register == address).map(|&(_, name)| name)
+}
+
+/// Hex dump of `length` bytes from `start`, 16 to a line, with IO registers named after the
+/// line holding them. With a bank the memory comes from that bank whether or not it's mapped.
+pub fn dump(bus: &MemoryBus, bank: Option<usize>, start: u16, length: u16) -> String
+{
+    let peek = |address: u16| match bank
+    {
+        Some(bank) => bus.peek_bank(bank, address),
+        None => bus.peek_byte(address),
+    };
+    let lines: Vec<String> = (0..length as u32)
+        .step_by(16)
+        .map(|offset| {
+            let line_start = start.wrapping_add(offset as u16);
+            let addresses: Vec<u16> = (0..16.min(length as u32 - offset))
+                .map(|index| line_start.wrapping_add(index as u16))
+                .collect();
+            let bytes: Vec<String> =
+                addresses.iter().map(|&address| format!("{:02X}", peek(address))).collect();
+            let registers: Vec<String> = addresses
+                .iter()
+                .filter_map(|&address| {
+                    register_name(address).map(|name| format!("{}={:02X}", name, peek(address)))
+                })
+                .collect();
+            let location = match bank
+            {
+                Some(bank) => format!("{:02X}:{:04X}", bank, line_start),
+                None => format!("{:04X}", line_start),
+            };
+            let mut line = format!("{}: {}", location, bytes.join(" "));
+            if !registers.is_empty()
+            {
+                line = format!("{:<54} {}", line, registers.join(" "));
+            }
+            line
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Every address where a pattern of bytes starts. `None` in the pattern matches any byte.
+pub fn find(bus: &MemoryBus, pattern: &[Option<u8>]) -> Vec<u16>
+{
+    if pattern.is_empty()
+    {
+        return Vec::new();
+    }
+    let memory: Vec<u8> = (0..=0xFFFF).map(|address| bus.peek_byte(address)).collect();
+    memory
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, bytes)| {
+            bytes
+                .iter()
+                .zip(pattern)
+                .all(|(byte, expected)| expected.is_none_or(|expected| expected == *byte))
+        })
+        .map(|(address, _)| address as u16)
+        .collect()
+}
+
+/// How a RAM search narrows down its candidates
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SearchFilter
+{
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    Equal(u8),
+}
+
+/// RAM search, which finds where a game keeps a value by comparing snapshots of its memory.
+/// Every byte of RAM starts as a candidate and each filter keeps the ones that changed the way
+/// it asks since the last snapshot.
+pub struct MemorySearch
+{
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl MemorySearch
+{
+    pub fn new(bus: &MemoryBus) -> Self
+    {
+        let candidates = SEARCH_RANGES.iter().flat_map(|&(begin, end)| begin..=end).collect();
+        let mut search = MemorySearch { snapshot: vec![0; 0x10000], candidates };
+        search.take_snapshot(bus);
+        search
+    }
+
+    fn take_snapshot(&mut self, bus: &MemoryBus)
+    {
+        for &address in self.candidates.iter()
+        {
+            self.snapshot[address as usize] = bus.peek_byte(address);
+        }
+    }
+
+    /// Keep the candidates that pass a filter, then snapshot them for the next one
+    pub fn filter(&mut self, bus: &MemoryBus, filter: SearchFilter)
+    {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&address| {
+            let before = snapshot[address as usize];
+            let now = bus.peek_byte(address);
+            match filter
+            {
+                SearchFilter::Changed => now != before,
+                SearchFilter::Unchanged => now == before,
+                SearchFilter::Increased => now > before,
+                SearchFilter::Decreased => now < before,
+                SearchFilter::Equal(value) => now == value,
+            }
+        });
+        self.take_snapshot(bus);
+    }
+
+    pub fn candidates(&self) -> &[u16]
+    {
+        &self.candidates
+    }
+}
@@ -0,0 +1,393 @@
+use std::io;
+
+use crate::cartridge::Cartridge;
+use crate::cdl::CodeDataLog;
+use crate::cheats::Cheats;
+use crate::cpu::CPU;
+use crate::gpu::compatibility::CompatibilityPalette;
+use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::joypad::Button;
+use crate::model::Model;
+use crate::profiler::Profiler;
+use crate::savestate::{invalid_state, SaveState, StateHeader, StateReader, StateWriter};
+use crate::serial::LinkPartner;
+use crate::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+use crate::trace::Trace;
+
+/// A complete Game Boy which runs a frame at a time, independent of any window or audio
+/// device. Frames are RGBA, audio is interleaved stereo at `apu::SAMPLE_RATE`.
+pub struct GameBoy
+{
+    cpu: CPU,
+    model: Model,
+    boot_rom: Option<Vec<u8>>,
+    // The palette chosen with `set_compatibility_palette`, kept over cartridge swaps
+    palette: Option<CompatibilityPalette>,
+    // Set once a frame has finished, so the next instruction starts collecting a new one
+    frame_completed: bool,
+    trace: Option<Trace>,
+}
+
+impl GameBoy
+{
+    /// Without a boot ROM the game starts with the state the boot ROM would have left behind
+    pub fn new(model: Model, boot_rom: Option<Vec<u8>>, cartridge: Option<Cartridge>) -> Self
+    {
+        let cpu = Self::start(model, boot_rom.clone(), cartridge);
+        GameBoy { cpu, model, boot_rom, palette: None, frame_completed: true, trace: None }
+    }
+
+    fn start(model: Model, boot_rom: Option<Vec<u8>>, cartridge: Option<Cartridge>) -> CPU
+    {
+        // The CGB picks a palette for monochrome games by itself
+        let palette = cartridge
+            .as_ref()
+            .filter(|cartridge| model.is_cgb() && !cartridge.supports_cgb())
+            .map(CompatibilityPalette::for_cartridge);
+
+        let skip_boot_rom = boot_rom.is_none();
+        let mut cpu = CPU::new(model, boot_rom.unwrap_or_default(), cartridge);
+        if skip_boot_rom
+        {
+            cpu.skip_boot_rom();
+        }
+        if let Some(palette) = palette
+        {
+            cpu.bus.set_compatibility_palette(&palette);
+        }
+        cpu
+    }
+
+    /// Swap the cartridge, which restarts the Game Boy with the same model and boot ROM. The
+    /// link port device, palette, trace and profiler stay attached. The old game's cheats and
+    /// code/data log are handed back, as they only apply to its ROM.
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) -> (Cheats, Option<CodeDataLog>)
+    {
+        let mut cpu = Self::start(self.model, self.boot_rom.clone(), Some(cartridge));
+        if let Some(partner) = self.cpu.bus.serial.disconnect()
+        {
+            cpu.bus.serial.connect(partner);
+        }
+        if let Some(palette) = self.palette
+        {
+            cpu.bus.set_compatibility_palette(&palette);
+        }
+        cpu.bus.stub_ly = self.trace.is_some();
+        cpu.profiler = self.cpu.profiler.take();
+
+        let old = std::mem::replace(&mut self.cpu, cpu);
+        self.frame_completed = true;
+        (old.bus.cheats, old.bus.code_data_log)
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge>
+    {
+        self.cpu.bus.cartridge()
+    }
+
+    pub fn model(&self) -> Model
+    {
+        self.model
+    }
+
+    /// Run until the LCD finishes a frame and return it
+    pub fn run_frame(&mut self) -> &[u8]
+    {
+        while !self.step_instruction()
+        {}
+        self.framebuffer()
+    }
+
+    /// Run a single instruction. Returns true if it finished a frame.
+    pub fn step_instruction(&mut self) -> bool
+    {
+        if self.frame_completed
+        {
+            self.cpu.bus.apu.clear_samples();
+        }
+        // Nothing is logged while halted, as no instructions run
+        if let (Some(trace), false) = (self.trace.as_mut(), self.cpu.is_halted())
+        {
+            if let Err(error) = trace.write(&self.cpu)
+            {
+                println!("Failed to write trace: {}", error);
+                self.trace = None;
+            }
+        }
+        self.cpu.step();
+        self.frame_completed = self.cpu.bus.take_frame_completed();
+        self.frame_completed
+    }
+
+    /// Log every instruction from now on. LY reads as 0x90 while tracing, as it did when the
+    /// Gameboy Doctor reference logs were made.
+    pub fn start_trace(&mut self, trace: Trace)
+    {
+        self.trace = Some(trace);
+        self.cpu.bus.stub_ly = true;
+    }
+
+    pub fn stop_trace(&mut self) -> io::Result<()>
+    {
+        self.cpu.bus.stub_ly = false;
+        match self.trace.take()
+        {
+            Some(mut trace) => trace.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Count the cycles spent at every address and in every function from now on
+    pub fn start_profiling(&mut self)
+    {
+        self.cpu.profiler = Some(Profiler::new());
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profiler>
+    {
+        self.cpu.profiler.take()
+    }
+
+    /// Mark how every byte of the ROM is used from now on, adding to the flags already in `log`
+    pub fn start_code_data_log(&mut self, log: CodeDataLog)
+    {
+        self.cpu.bus.code_data_log = Some(log);
+    }
+
+    pub fn stop_code_data_log(&mut self) -> Option<CodeDataLog>
+    {
+        self.cpu.bus.code_data_log.take()
+    }
+
+    pub fn cheats(&self) -> &Cheats
+    {
+        &self.cpu.bus.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats
+    {
+        &mut self.cpu.bus.cheats
+    }
+
+    pub fn cpu(&self) -> &CPU
+    {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU
+    {
+        &mut self.cpu
+    }
+
+    /// The last frame as RGBA. The Super Game Boy screen includes its border.
+    pub fn framebuffer(&self) -> &[u8]
+    {
+        match self.cpu.bus.sgb.as_ref()
+        {
+            Some(sgb) => &sgb.framebuffer,
+            None => &self.cpu.bus.gpu.framebuffer,
+        }
+    }
+
+    /// Width and height of the framebuffer
+    pub fn screen_size(&self) -> (usize, usize)
+    {
+        if self.cpu.bus.sgb.is_some()
+        {
+            (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+        }
+        else
+        {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        }
+    }
+
+    /// Audio produced during the last frame
+    pub fn audio_samples(&self) -> &[f32]
+    {
+        self.cpu.bus.apu.samples()
+    }
+
+    /// Hold down exactly the buttons in `pressed` on a controller. Only the Super Game Boy has
+    /// more than one.
+    pub fn set_buttons(&mut self, player: usize, pressed: &[Button])
+    {
+        for button in Button::ALL
+        {
+            self.set_button(player, button, pressed.contains(&button));
+        }
+    }
+
+    /// Buttons a controller is holding, as a mask of `Button::bit`
+    pub fn buttons(&self, player: usize) -> u8
+    {
+        self.cpu.bus.joypad.buttons(player)
+    }
+
+    pub fn set_button(&mut self, player: usize, button: Button, pressed: bool)
+    {
+        self.cpu.bus.joypad.set_player_button(player, button, pressed);
+    }
+
+    /// Colourise a monochrome game with one of the CGB compatibility palettes
+    pub fn set_compatibility_palette(&mut self, palette: &CompatibilityPalette)
+    {
+        self.cpu.bus.set_compatibility_palette(palette);
+        self.palette = Some(*palette);
+    }
+
+    /// Snapshot the whole machine, headed by the game's checksum and a thumbnail of the screen
+    pub fn save_state(&self) -> Vec<u8>
+    {
+        let (width, _) = self.screen_size();
+        let header = StateHeader::new(self.model, self.rom_checksum(), self.framebuffer(), width);
+
+        let mut writer = StateWriter::new();
+        header.write(&mut writer);
+        self.cpu.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// Restore a snapshot from `save_state`. States from another game or model are refused and
+    /// the machine is left as it was if the state can't be loaded.
+    pub fn load_state(&mut self, state: &[u8]) -> io::Result<()>
+    {
+        let mut reader = StateReader::new(state);
+        let header = StateHeader::read(&mut reader)?;
+        if header.rom_checksum != self.rom_checksum()
+        {
+            return Err(invalid_state("state was saved from a different game"));
+        }
+        if header.model != self.model
+        {
+            return Err(invalid_state(&format!(
+                "state was saved on {:?} but this is {:?}",
+                header.model, self.model
+            )));
+        }
+
+        let backup = self.save_state();
+        if let Err(error) = self.cpu.load_state(&mut reader)
+        {
+            let mut reader = StateReader::new(&backup);
+            StateHeader::read(&mut reader)?;
+            self.cpu.load_state(&mut reader).expect("Failed to restore machine state");
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// CRC-32 of the ROM, or 0 with no cartridge
+    pub fn rom_checksum(&self) -> u32
+    {
+        self.cartridge().map_or(0, |cartridge| cartridge.rom_checksum())
+    }
+
+    /// Plug a device into the link port
+    pub fn connect_serial(&mut self, partner: Box<dyn LinkPartner>)
+    {
+        self.cpu.bus.serial.connect(partner);
+    }
+}
+
+/// A 32 KiB ROM without a memory bank controller holding `program` at 0x0100, for tests
+#[cfg(test)]
+pub(crate) fn test_cartridge(program: &[u8]) -> Cartridge
+{
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+    Cartridge::new(rom).unwrap()
+}
+
+/// A DMG started without a boot ROM on `test_cartridge`
+#[cfg(test)]
+pub(crate) fn test_gameboy(program: &[u8]) -> GameBoy
+{
+    GameBoy::new(Model::Dmg, None, Some(test_cartridge(program)))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::serial::CapturePartner;
+
+    #[test]
+    fn swapping_cartridges_keeps_the_attached_devices()
+    {
+        let mut gameboy = test_gameboy(&[0x18, 0xFE]);
+        let capture = CapturePartner::new();
+        let log = capture.log();
+        gameboy.connect_serial(Box::new(capture));
+        gameboy.start_profiling();
+        gameboy.cheats_mut().add("019947D3", "").unwrap();
+
+        // LD A,$41; LDH [SB],A; LD A,$81; LDH [SC],A; JR -2
+        let program = [0x3E, 0x41, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE];
+        let (cheats, code_data_log) = gameboy.load_cartridge(test_cartridge(&program));
+        assert_eq!(cheats.cheats().len(), 1);
+        assert!(code_data_log.is_none());
+        assert!(gameboy.cheats().cheats().is_empty());
+
+        gameboy.run_frame();
+        assert_eq!(log.text(), "A");
+        assert!(gameboy.stop_profiling().is_some());
+    }
+
+    /// PC, SP and the register pairs
+    fn registers(gameboy: &GameBoy) -> [u16; 6]
+    {
+        let cpu = &gameboy.cpu;
+        let registers = &cpu.registers;
+        [
+            cpu.pc,
+            cpu.sp,
+            registers.get_af(),
+            registers.get_bc(),
+            registers.get_de(),
+            registers.get_hl(),
+        ]
+    }
+
+    #[test]
+    fn states_restore_the_machine()
+    {
+        // An MBC3 cartridge with a clock
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x03;
+        let mut gameboy = GameBoy::new(Model::Dmg, None, Some(Cartridge::new(rom).unwrap()));
+        gameboy.cpu.bus.write_byte(0xC123, 0x45);
+        gameboy.run_frame();
+
+        let state = gameboy.save_state();
+        let saved_registers = registers(&gameboy);
+        let saved_clock = gameboy.cartridge().unwrap().rtc().cloned();
+        for _ in 0..70
+        {
+            gameboy.run_frame();
+        }
+        gameboy.cpu.registers.set_bc(0x1234);
+        gameboy.cpu.bus.write_byte(0xC123, 0x67);
+        assert_ne!(gameboy.cartridge().unwrap().rtc().unwrap().registers()[0], 0);
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(registers(&gameboy), saved_registers);
+        assert_eq!(gameboy.cpu.bus.read_byte(0xC123), 0x45);
+        assert_eq!(gameboy.cartridge().unwrap().rtc().cloned(), saved_clock);
+    }
+
+    #[test]
+    fn states_from_another_game_are_refused()
+    {
+        let state = test_gameboy(&[0x18, 0xFE]).save_state();
+        let mut gameboy = test_gameboy(&[0x00, 0x18, 0xFD]);
+        gameboy.run_frame();
+        let before = registers(&gameboy);
+
+        let error = gameboy.load_state(&state).unwrap_err();
+        assert!(error.to_string().contains("different game"), "{}", error);
+        assert_eq!(registers(&gameboy), before);
+    }
+}
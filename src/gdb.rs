@@ -0,0 +1,556 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::CPU;
+use crate::gameboy::GameBoy;
+
+// Sent by the client to stop the game while it runs
+const INTERRUPT: u8 = 0x03;
+// Signals reported when the game stops
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const REGISTER_COUNT: usize = 6;
+// Largest packet the client may send or be sent, including the framing
+const PACKET_SIZE: usize = 0x4000;
+// Bytes of memory that fit in a reply, two hex digits each after `$` and before `#` and the
+// checksum
+const MAX_READ_LENGTH: usize = (PACKET_SIZE - 4) / 2;
+
+/// Server for the GDB remote serial protocol, letting GDB, LLDB or any other client speaking
+/// it debug the running game over TCP.
+///
+/// The registers are the pairs AF, BC, DE, HL, SP and PC, 16 bits each and little endian, which
+/// matches the first registers of GDB's z80 target. Memory is read and written with the memory
+/// bus's peek and poke, so the client can't switch banks or start DMA by accident. Software
+/// breakpoints, single stepping and continuing are supported.
+///
+/// Nothing blocks: the stub is polled once a frame so the window keeps drawing while the game is
+/// stopped. The game stops when a client attaches and runs freely again once it detaches.
+pub struct GdbStub
+{
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+    breakpoints: Vec<u16>,
+    stopped: bool,
+    // Continuing from a breakpoint mustn't stop at it again straight away
+    skip_breakpoint_at: Option<u16>,
+}
+
+impl GdbStub
+{
+    pub fn listen<A: ToSocketAddrs>(address: A) -> io::Result<Self>
+    {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            breakpoints: Vec::new(),
+            stopped: false,
+            skip_breakpoint_at: None,
+        })
+    }
+
+    pub fn is_stopped(&self) -> bool
+    {
+        self.stopped
+    }
+
+    /// Answer the client, then run the rest of a frame unless the game is stopped. Returns true
+    /// if a whole frame ran.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> bool
+    {
+        self.accept();
+        self.receive(gameboy);
+        if self.stopped
+        {
+            return false;
+        }
+
+        loop
+        {
+            let cpu = gameboy.cpu();
+            if self.skip_breakpoint_at != Some(cpu.pc)
+                && !cpu.is_halted()
+                && self.breakpoints.contains(&cpu.pc)
+            {
+                self.stop(SIGTRAP);
+                return false;
+            }
+            self.skip_breakpoint_at = None;
+            if gameboy.step_instruction()
+            {
+                return true;
+            }
+        }
+    }
+
+    fn accept(&mut self)
+    {
+        if self.client.is_some()
+        {
+            return;
+        }
+        match self.listener.accept()
+        {
+            Ok((stream, address)) =>
+            {
+                if let Err(error) = stream.set_nonblocking(true).and(stream.set_nodelay(true))
+                {
+                    println!("GDB connection failed: {}", error);
+                    return;
+                }
+                println!("GDB attached from {}", address);
+                self.client = Some(stream);
+                self.buffer.clear();
+                self.stopped = true;
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock =>
+            {}
+            Err(error) => println!("GDB connection failed: {}", error),
+        }
+    }
+
+    fn detach(&mut self, reason: &str)
+    {
+        println!("GDB detached: {}", reason);
+        self.client = None;
+        self.breakpoints.clear();
+        self.stopped = false;
+    }
+
+    /// Read whatever the client has sent and answer each packet
+    fn receive(&mut self, gameboy: &mut GameBoy)
+    {
+        let Some(client) = self.client.as_mut()
+        else
+        {
+            return;
+        };
+        let mut bytes = [0; 4096];
+        // No more than a packet is buffered, anything after it is read once it's been handled
+        while self.buffer.len() < PACKET_SIZE
+        {
+            let space = bytes.len().min(PACKET_SIZE - self.buffer.len());
+            match client.read(&mut bytes[..space])
+            {
+                Ok(0) => return self.detach("connection closed"),
+                Ok(read) => self.buffer.extend_from_slice(&bytes[..read]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return self.detach(&error.to_string()),
+            }
+        }
+
+        while let Some(&first) = self.buffer.first()
+        {
+            match first
+            {
+                b'$' =>
+                {
+                    let Some((packet, length)) = split_packet(&self.buffer)
+                    else
+                    {
+                        if self.buffer.len() >= PACKET_SIZE
+                        {
+                            self.detach("packet too long");
+                        }
+                        return;
+                    };
+                    self.buffer.drain(..length);
+
+                    let Some(packet) = packet
+                    else
+                    {
+                        self.send_raw(b"-");
+                        continue;
+                    };
+                    self.send_raw(b"+");
+                    let packet = String::from_utf8_lossy(&packet).into_owned();
+                    if let Some(reply) = self.handle(gameboy, &packet)
+                    {
+                        self.send(&reply);
+                    }
+                }
+                INTERRUPT =>
+                {
+                    self.buffer.remove(0);
+                    if !self.stopped
+                    {
+                        self.stop(SIGINT);
+                    }
+                }
+                // Acknowledgements and stray bytes
+                _ =>
+                {
+                    self.buffer.remove(0);
+                }
+            }
+            if self.client.is_none()
+            {
+                return;
+            }
+        }
+    }
+
+    fn stop(&mut self, signal: u8)
+    {
+        self.stopped = true;
+        self.send(&format!("S{:02x}", signal));
+    }
+
+    fn resume(&mut self, cpu: &mut CPU, arguments: &str)
+    {
+        if let Ok(address) = u16::from_str_radix(arguments, 16)
+        {
+            cpu.pc = address;
+        }
+        self.skip_breakpoint_at = Some(cpu.pc);
+        self.stopped = false;
+    }
+
+    /// Answer a packet. Returns `None` when the answer comes later, once the game stops.
+    fn handle(&mut self, gameboy: &mut GameBoy, packet: &str) -> Option<String>
+    {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command
+        {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" =>
+            {
+                let cpu = gameboy.cpu();
+                (0..REGISTER_COUNT)
+                    .map(|index| encode_register(read_register(cpu, index)))
+                    .collect()
+            }
+            "G" =>
+            {
+                let values: Option<Vec<u16>> = (0..REGISTER_COUNT)
+                    .map(|index| decode_register(arguments.get(index * 4..index * 4 + 4)?))
+                    .collect();
+                match values
+                {
+                    Some(values) =>
+                    {
+                        for (index, value) in values.into_iter().enumerate()
+                        {
+                            write_register(gameboy.cpu_mut(), index, value);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "p" => match usize::from_str_radix(arguments, 16)
+            {
+                Ok(index) if index < REGISTER_COUNT =>
+                {
+                    encode_register(read_register(gameboy.cpu(), index))
+                }
+                _ => "E01".to_string(),
+            },
+            "P" =>
+            {
+                let register = arguments.split_once('=').and_then(|(index, value)| {
+                    Some((usize::from_str_radix(index, 16).ok()?, decode_register(value)?))
+                });
+                match register
+                {
+                    Some((index, value)) if index < REGISTER_COUNT =>
+                    {
+                        write_register(gameboy.cpu_mut(), index, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(arguments)
+            {
+                Some((address, length)) => (0..length.min(MAX_READ_LENGTH))
+                    .map(|offset| {
+                        let address = address.wrapping_add(offset as u16);
+                        format!("{:02x}", gameboy.cpu().bus.peek_byte(address))
+                    })
+                    .collect(),
+                None => "E01".to_string(),
+            },
+            "M" =>
+            {
+                let write = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    let bytes = decode_hex(data)?;
+                    (bytes.len() == length).then_some((address, bytes))
+                });
+                match write
+                {
+                    Some((address, bytes)) =>
+                    {
+                        for (offset, byte) in bytes.into_iter().enumerate()
+                        {
+                            gameboy
+                                .cpu_mut()
+                                .bus
+                                .poke_byte(address.wrapping_add(offset as u16), byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            // Software and hardware breakpoints work the same way
+            "Z" | "z" => match parse_breakpoint(arguments)
+            {
+                Some(address) =>
+                {
+                    if command == "Z"
+                    {
+                        if !self.breakpoints.contains(&address)
+                        {
+                            self.breakpoints.push(address);
+                        }
+                    }
+                    else
+                    {
+                        self.breakpoints.retain(|&breakpoint| breakpoint != address);
+                    }
+                    "OK".to_string()
+                }
+                None => String::new(),
+            },
+            "c" =>
+            {
+                self.resume(gameboy.cpu_mut(), arguments);
+                return None;
+            }
+            "s" =>
+            {
+                self.resume(gameboy.cpu_mut(), arguments);
+                gameboy.step_instruction();
+                self.stopped = true;
+                format!("S{:02x}", SIGTRAP)
+            }
+            "D" =>
+            {
+                self.send("OK");
+                self.detach("client detached");
+                return None;
+            }
+            "k" =>
+            {
+                self.detach("client killed the session");
+                return None;
+            }
+            "H" => "OK".to_string(),
+            "q" => match arguments
+            {
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                _ if arguments.starts_with("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+                _ => String::new(),
+            },
+            // An empty reply tells the client the packet isn't supported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn send(&mut self, data: &str)
+    {
+        self.send_raw(frame(data).as_bytes());
+    }
+
+    fn send_raw(&mut self, bytes: &[u8])
+    {
+        let Some(client) = self.client.as_mut()
+        else
+        {
+            return;
+        };
+        // The socket blocks while writing so a full send buffer can't cut the reply short
+        let written = client
+            .set_nonblocking(false)
+            .and_then(|_| client.write_all(bytes))
+            .and_then(|_| client.set_nonblocking(true));
+        if let Err(error) = written
+        {
+            self.detach(&error.to_string());
+        }
+    }
+}
+
+/// Wrap data in a packet, `$data#cc` where `cc` is the checksum
+fn frame(data: &str) -> String
+{
+    format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+}
+
+/// The packet at the start of `buffer`, which must start with `$`. Gives its data, or None if
+/// the checksum is wrong, and how many bytes it took up. Returns None if it hasn't all arrived.
+fn split_packet(buffer: &[u8]) -> Option<(Option<Vec<u8>>, usize)>
+{
+    let end = buffer.iter().position(|&byte| byte == b'#')?;
+    // Two hex digits of checksum follow the packet
+    let digits = buffer.get(end + 1..end + 3)?;
+    let checksum =
+        std::str::from_utf8(digits).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    let data = &buffer[1..end];
+    let packet = (checksum == Some(checksum_of(data))).then(|| data.to_vec());
+    Some((packet, end + 3))
+}
+
+fn checksum_of(data: &[u8]) -> u8
+{
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+fn read_register(cpu: &CPU, index: usize) -> u16
+{
+    match index
+    {
+        0 => cpu.registers.get_af(),
+        1 => cpu.registers.get_bc(),
+        2 => cpu.registers.get_de(),
+        3 => cpu.registers.get_hl(),
+        4 => cpu.sp,
+        _ => cpu.pc,
+    }
+}
+
+fn write_register(cpu: &mut CPU, index: usize, value: u16)
+{
+    match index
+    {
+        0 => cpu.registers.set_af(value),
+        1 => cpu.registers.set_bc(value),
+        2 => cpu.registers.set_de(value),
+        3 => cpu.registers.set_hl(value),
+        4 => cpu.sp = value,
+        _ => cpu.pc = value,
+    }
+}
+
+fn encode_register(value: u16) -> String
+{
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+fn decode_register(text: &str) -> Option<u16>
+{
+    match decode_hex(text)?.as_slice()
+    {
+        [low, high] => Some(u16::from_le_bytes([*low, *high])),
+        _ => None,
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>>
+{
+    if !text.len().is_multiple_of(2)
+    {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+/// `address,length` in hex
+fn parse_range(text: &str) -> Option<(u16, usize)>
+{
+    let (address, length) = text.split_once(',')?;
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    // Reads and writes can't run past the end of memory
+    Some((address, length.min(0x10000 - address as usize)))
+}
+
+/// `type,address,kind` for breakpoint types 0 and 1. Watchpoints aren't supported.
+fn parse_breakpoint(text: &str) -> Option<u16>
+{
+    let mut fields = text.split(',');
+    let kind = fields.next()?;
+    if kind != "0" && kind != "1"
+    {
+        return None;
+    }
+    u16::from_str_radix(fields.next()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn packets_are_framed_with_their_checksum()
+    {
+        assert_eq!(checksum_of(b""), 0x00);
+        assert_eq!(checksum_of(b"OK"), 0x9A);
+        // The sum wraps around
+        assert_eq!(checksum_of(&[0xFF, 0x02]), 0x01);
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame(""), "$#00");
+    }
+
+    #[test]
+    fn packets_are_split_from_the_buffer()
+    {
+        assert_eq!(split_packet(b"$m0,4#fd+"), Some((Some(b"m0,4".to_vec()), 8)));
+        assert_eq!(split_packet(b"$m0,4#FD"), Some((Some(b"m0,4".to_vec()), 8)));
+        // A wrong checksum still takes the whole packet out of the buffer
+        assert_eq!(split_packet(b"$m0,4#00$g#67"), Some((None, 8)));
+        assert_eq!(split_packet(b"$m0,4#f"), None);
+        assert_eq!(split_packet(b"$m0,4"), None);
+    }
+
+    #[test]
+    fn ranges_stop_at_the_end_of_memory()
+    {
+        assert_eq!(parse_range("c000,10"), Some((0xC000, 0x10)));
+        assert_eq!(parse_range("fff0,100"), Some((0xFFF0, 0x10)));
+        assert_eq!(parse_range("0,ffffffff"), Some((0, 0x10000)));
+        assert_eq!(parse_range("c000"), None);
+        assert_eq!(parse_range("10000,1"), None);
+        assert_eq!(parse_range("c000,x"), None);
+    }
+
+    #[test]
+    fn memory_reads_fit_in_a_packet()
+    {
+        assert!(frame(&"00".repeat(MAX_READ_LENGTH)).len() <= PACKET_SIZE);
+        assert!(frame(&"00".repeat(MAX_READ_LENGTH + 1)).len() > PACKET_SIZE);
+    }
+
+    #[test]
+    fn packets_drive_the_game()
+    {
+        // NOP; NOP; JR -4
+        let mut gameboy = crate::gameboy::test_gameboy(&[0x00, 0x00, 0x18, 0xFC]);
+        let mut stub = GdbStub::listen("127.0.0.1:0").unwrap();
+
+        let registers = stub.handle(&mut gameboy, "g").unwrap();
+        assert_eq!(registers, "80011300d8004d01feff0001");
+        assert_eq!(stub.handle(&mut gameboy, "m101,3").unwrap(), "0018fc");
+        assert_eq!(stub.handle(&mut gameboy, "m,3").unwrap(), "E01");
+        assert_eq!(stub.handle(&mut gameboy, "Z0,102,1").unwrap(), "OK");
+
+        assert_eq!(stub.handle(&mut gameboy, "c"), None);
+        assert!(!stub.is_stopped());
+        assert!(!stub.run_frame(&mut gameboy));
+        assert!(stub.is_stopped());
+        assert_eq!(gameboy.cpu().pc, 0x0102);
+
+        // Continuing from the breakpoint runs through it until the game comes round again
+        stub.handle(&mut gameboy, "c");
+        assert!(!stub.run_frame(&mut gameboy));
+        assert_eq!(gameboy.cpu().pc, 0x0102);
+        assert_eq!(stub.handle(&mut gameboy, "z0,102,1").unwrap(), "OK");
+        stub.handle(&mut gameboy, "c");
+        assert!(stub.run_frame(&mut gameboy));
+    }
+}
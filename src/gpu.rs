@@ -1,7 +1,79 @@
+pub mod compatibility;
+pub mod viewer;
+
+use std::io;
+
+use crate::model::Model;
+use crate::savestate::{invalid_state, SaveState, StateReader, StateWriter};
+use compatibility::CompatibilityPalette;
+
 pub const VRAM_BEGIN: usize = 0x8000;
 pub const VRAM_END: usize = 0x9FFF;
 pub const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
 
+pub const OAM_BEGIN: usize = 0xFE00;
+pub const OAM_END: usize = 0xFE9F;
+pub const OAM_SIZE: usize = OAM_END - OAM_BEGIN + 1;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const LCDC_ADDRESS: usize = 0xFF40;
+pub const STAT_ADDRESS: usize = 0xFF41;
+pub const SCY_ADDRESS: usize = 0xFF42;
+pub const SCX_ADDRESS: usize = 0xFF43;
+pub const LY_ADDRESS: usize = 0xFF44;
+pub const LYC_ADDRESS: usize = 0xFF45;
+pub const BGP_ADDRESS: usize = 0xFF47;
+pub const OBP0_ADDRESS: usize = 0xFF48;
+pub const OBP1_ADDRESS: usize = 0xFF49;
+pub const WY_ADDRESS: usize = 0xFF4A;
+pub const WX_ADDRESS: usize = 0xFF4B;
+pub const VBK_ADDRESS: usize = 0xFF4F;
+pub const BCPS_ADDRESS: usize = 0xFF68;
+pub const BCPD_ADDRESS: usize = 0xFF69;
+pub const OCPS_ADDRESS: usize = 0xFF6A;
+pub const OCPD_ADDRESS: usize = 0xFF6B;
+
+// LCDC bits
+const LCDC_BG_ENABLE: u8 = 0x01;
+const LCDC_OBJ_ENABLE: u8 = 0x02;
+const LCDC_OBJ_SIZE: u8 = 0x04;
+const LCDC_BG_TILE_MAP: u8 = 0x08;
+const LCDC_TILE_DATA: u8 = 0x10;
+const LCDC_WINDOW_ENABLE: u8 = 0x20;
+const LCDC_WINDOW_TILE_MAP: u8 = 0x40;
+const LCDC_LCD_ENABLE: u8 = 0x80;
+
+// STAT interrupt sources
+const STAT_HBLANK_INTERRUPT: u8 = 0x08;
+const STAT_VBLANK_INTERRUPT: u8 = 0x10;
+const STAT_OAM_INTERRUPT: u8 = 0x20;
+const STAT_LYC_INTERRUPT: u8 = 0x40;
+
+// Sprite and CGB background attribute bits
+const ATTRIBUTE_PALETTE: u8 = 0x07;
+const ATTRIBUTE_VRAM_BANK: u8 = 0x08;
+const ATTRIBUTE_DMG_PALETTE: u8 = 0x10;
+const ATTRIBUTE_X_FLIP: u8 = 0x20;
+const ATTRIBUTE_Y_FLIP: u8 = 0x40;
+const ATTRIBUTE_PRIORITY: u8 = 0x80;
+
+// Cycles spent in each mode of a visible line. A full line always takes 456 cycles.
+const OAM_SCAN_CYCLES: u16 = 80;
+const PIXEL_TRANSFER_CYCLES: u16 = 172;
+const HORIZONTAL_BLANK_CYCLES: u16 = 204;
+const LINE_CYCLES: u16 = 456;
+const LINES_PER_FRAME: u8 = 154;
+pub const FRAME_CYCLES: u32 = LINE_CYCLES as u32 * LINES_PER_FRAME as u32;
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+const PALETTE_RAM_SIZE: usize = 64;
+
+// RGBA shades for the four DMG colours, from lightest to darkest
+const DMG_SHADES: [[u8; 4]; 4] =
+    [[255, 255, 255, 255], [192, 192, 192, 255], [96, 96, 96, 255], [0, 0, 0, 255]];
+
 #[derive(Copy, Clone)]
 enum TilePixelValue
 {
@@ -18,26 +90,167 @@ fn empty_tile() -> Tile
     [[TilePixelValue::Zero; 8]; 8]
 }
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mode
+{
+    HorizontalBlank = 0,
+    VerticalBlank = 1,
+    OamScan = 2,
+    PixelTransfer = 3,
+}
+
+/// Interrupts raised by the GPU during a step
+#[derive(Default)]
+pub struct InterruptRequest
+{
+    pub vblank: bool,
+    pub lcdstat: bool,
+}
+
 pub struct GPU
 {
     pub vram: [u8; VRAM_SIZE],
+    // Second VRAM bank, only present on the Game Boy Color
+    pub vram1: [u8; VRAM_SIZE],
     tile_set: [Tile; 384],
+    pub oam: [u8; OAM_SIZE],
+    pub framebuffer: Vec<u8>,
+    // Shade of every pixel before colouring, which the Super Game Boy colours in its own way
+    pub shades: Vec<u8>,
+    pub mode: Mode,
+    cycles: u16,
+    lcdc: u8,
+    stat_interrupts: u8,
+    stat_line: bool,
+    scy: u8,
+    scx: u8,
+    pub ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    // The window keeps its own line counter which only advances on lines where it was drawn
+    window_line: u8,
+    // Colours of the four shades for the background, OBJ0 and OBJ1 outside of CGB mode
+    dmg_colours: [[[u8; 4]; 4]; 3],
+    model: Model,
+    cgb_mode: bool,
+    vram_bank: usize,
+    bg_palettes: [u8; PALETTE_RAM_SIZE],
+    obj_palettes: [u8; PALETTE_RAM_SIZE],
+    bg_palette_index: u8,
+    obj_palette_index: u8,
 }
 
 impl GPU
 {
-    pub fn new() -> Self
+    pub fn new(model: Model, cgb_mode: bool) -> Self
+    {
+        Self {
+            vram: [0; VRAM_SIZE],
+            vram1: [0; VRAM_SIZE],
+            tile_set: std::array::from_fn(|_| empty_tile()),
+            oam: [0; OAM_SIZE],
+            framebuffer: vec![255; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            mode: Mode::HorizontalBlank,
+            cycles: 0,
+            lcdc: 0,
+            stat_interrupts: 0,
+            stat_line: false,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            window_line: 0,
+            dmg_colours: [DMG_SHADES; 3],
+            model,
+            cgb_mode,
+            vram_bank: 0,
+            // The CGB boot ROM leaves every colour white
+            bg_palettes: [0xFF; PALETTE_RAM_SIZE],
+            obj_palettes: [0xFF; PALETTE_RAM_SIZE],
+            bg_palette_index: 0,
+            obj_palette_index: 0,
+        }
+    }
+
+    /// Colourise monochrome games the way the CGB does
+    pub fn set_compatibility_palette(&mut self, palette: &CompatibilityPalette)
     {
-        Self { vram: [0; VRAM_SIZE], tile_set: std::array::from_fn(|_| empty_tile()) }
+        self.dmg_colours = [palette.background, palette.obj0, palette.obj1]
+            .map(|colours| colours.map(rgb555_colour));
+    }
+
+    /// The DMG family corrupts the row of OAM being scanned when the CPU puts an OAM address on
+    /// the bus during OAM scan. The first word of the row is mixed with the previous row, which
+    /// also gets copied over the rest of it.
+    pub fn corrupt_oam(&mut self)
+    {
+        if self.model.is_cgb() || !self.lcd_enabled() || self.mode != Mode::OamScan
+        {
+            return;
+        }
+
+        // One 8 byte row is read every machine cycle
+        let row = (self.cycles / 4) as usize * 8;
+        if row == 0 || row >= OAM_SIZE
+        {
+            return;
+        }
+        let previous = row - 8;
+
+        let word = |oam: &[u8; OAM_SIZE], offset: usize| {
+            oam[offset] as u16 | (oam[offset + 1] as u16) << 8
+        };
+        let a = word(&self.oam, row);
+        let b = word(&self.oam, previous);
+        let c = word(&self.oam, previous + 4);
+        let corrupted = ((a ^ c) & (b ^ c)) ^ c;
+
+        self.oam[row] = corrupted as u8;
+        self.oam[row + 1] = (corrupted >> 8) as u8;
+        self.oam.copy_within(previous + 2..previous + 8, row + 2);
+    }
+
+    pub fn vram_bank(&self) -> usize
+    {
+        self.vram_bank
     }
 
     pub fn read_vram(&self, address: usize) -> u8
     {
-        self.vram[address]
+        if self.vram_bank == 1
+        {
+            self.vram1[address]
+        }
+        else
+        {
+            self.vram[address]
+        }
     }
 
     pub fn write_vram(&mut self, index: usize, value: u8)
     {
+        self.poke_vram(self.vram_bank, index, value);
+    }
+
+    /// Write to either VRAM bank, whichever one is selected
+    pub fn poke_vram(&mut self, bank: usize, index: usize, value: u8)
+    {
+        if bank == 1
+        {
+            self.vram1[index] = value;
+            return;
+        }
+
         self.vram[index] = value;
         // If our index is greater than 0x1800, we're not writing to the tile set storage
         // so we can just return.
@@ -102,4 +315,549 @@ impl GPU
             self.tile_set[tile_index][row_index][pixel_index] = value;
         }
     }
+
+    pub fn read_register(&self, address: usize) -> u8
+    {
+        match address
+        {
+            LCDC_ADDRESS => self.lcdc,
+            STAT_ADDRESS =>
+            {
+                let coincidence = if self.ly == self.lyc { 0x04 } else { 0 };
+                // The mode bits read as 0 while the LCD is off
+                let mode = if self.lcd_enabled() { self.mode as u8 } else { 0 };
+                0x80 | self.stat_interrupts | coincidence | mode
+            }
+            SCY_ADDRESS => self.scy,
+            SCX_ADDRESS => self.scx,
+            LY_ADDRESS => self.ly,
+            LYC_ADDRESS => self.lyc,
+            BGP_ADDRESS => self.bgp,
+            OBP0_ADDRESS => self.obp0,
+            OBP1_ADDRESS => self.obp1,
+            WY_ADDRESS => self.wy,
+            WX_ADDRESS => self.wx,
+            VBK_ADDRESS if self.cgb_mode => 0xFE | self.vram_bank as u8,
+            BCPS_ADDRESS if self.cgb_mode => 0x40 | self.bg_palette_index,
+            BCPD_ADDRESS if self.cgb_mode =>
+            {
+                self.bg_palettes[(self.bg_palette_index & 0x3F) as usize]
+            }
+            OCPS_ADDRESS if self.cgb_mode => 0x40 | self.obj_palette_index,
+            OCPD_ADDRESS if self.cgb_mode =>
+            {
+                self.obj_palettes[(self.obj_palette_index & 0x3F) as usize]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            LCDC_ADDRESS =>
+            {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = value;
+                if was_enabled && !self.lcd_enabled()
+                {
+                    // Turning the LCD off resets the current line
+                    self.ly = 0;
+                    self.cycles = 0;
+                    self.mode = Mode::HorizontalBlank;
+                    self.stat_line = false;
+                }
+                else if !was_enabled && self.lcd_enabled()
+                {
+                    self.cycles = 0;
+                    self.window_line = 0;
+                    self.mode = Mode::OamScan;
+                }
+            }
+            STAT_ADDRESS => self.stat_interrupts = value & 0x78,
+            SCY_ADDRESS => self.scy = value,
+            SCX_ADDRESS => self.scx = value,
+            LYC_ADDRESS => self.lyc = value,
+            BGP_ADDRESS => self.bgp = value,
+            OBP0_ADDRESS => self.obp0 = value,
+            OBP1_ADDRESS => self.obp1 = value,
+            WY_ADDRESS => self.wy = value,
+            WX_ADDRESS => self.wx = value,
+            VBK_ADDRESS if self.cgb_mode => self.vram_bank = (value & 0x01) as usize,
+            BCPS_ADDRESS if self.cgb_mode => self.bg_palette_index = value & 0xBF,
+            BCPD_ADDRESS if self.cgb_mode =>
+            {
+                self.bg_palettes[(self.bg_palette_index & 0x3F) as usize] = value;
+                self.bg_palette_index = next_palette_index(self.bg_palette_index);
+            }
+            OCPS_ADDRESS if self.cgb_mode => self.obj_palette_index = value & 0xBF,
+            OCPD_ADDRESS if self.cgb_mode =>
+            {
+                self.obj_palettes[(self.obj_palette_index & 0x3F) as usize] = value;
+                self.obj_palette_index = next_palette_index(self.obj_palette_index);
+            }
+            // LY is read only
+            _ =>
+            {}
+        }
+    }
+
+    /// Set a register without the side effects of writing it: switching the LCD on or off
+    /// doesn't reset it, LY can be changed and the palette index doesn't advance
+    pub fn poke_register(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            LCDC_ADDRESS => self.lcdc = value,
+            LY_ADDRESS => self.ly = value,
+            BCPD_ADDRESS if self.cgb_mode =>
+            {
+                self.bg_palettes[(self.bg_palette_index & 0x3F) as usize] = value;
+            }
+            OCPD_ADDRESS if self.cgb_mode =>
+            {
+                self.obj_palettes[(self.obj_palette_index & 0x3F) as usize] = value;
+            }
+            _ => self.write_register(address, value),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u8) -> InterruptRequest
+    {
+        let mut request = InterruptRequest::default();
+        if !self.lcd_enabled()
+        {
+            return request;
+        }
+
+        self.cycles += cycles as u16;
+        loop
+        {
+            let mode_cycles = match self.mode
+            {
+                Mode::OamScan => OAM_SCAN_CYCLES,
+                Mode::PixelTransfer => PIXEL_TRANSFER_CYCLES,
+                Mode::HorizontalBlank => HORIZONTAL_BLANK_CYCLES,
+                Mode::VerticalBlank => LINE_CYCLES,
+            };
+            if self.cycles < mode_cycles
+            {
+                break;
+            }
+            self.cycles -= mode_cycles;
+
+            match self.mode
+            {
+                Mode::OamScan => self.mode = Mode::PixelTransfer,
+                Mode::PixelTransfer =>
+                {
+                    self.render_scanline();
+                    self.mode = Mode::HorizontalBlank;
+                }
+                Mode::HorizontalBlank =>
+                {
+                    self.ly += 1;
+                    if self.ly as usize == SCREEN_HEIGHT
+                    {
+                        self.mode = Mode::VerticalBlank;
+                        self.window_line = 0;
+                        request.vblank = true;
+                    }
+                    else
+                    {
+                        self.mode = Mode::OamScan;
+                    }
+                }
+                Mode::VerticalBlank =>
+                {
+                    self.ly += 1;
+                    if self.ly == LINES_PER_FRAME
+                    {
+                        self.ly = 0;
+                        self.mode = Mode::OamScan;
+                    }
+                }
+            }
+
+            if self.update_stat_line()
+            {
+                request.lcdstat = true;
+            }
+        }
+
+        request
+    }
+
+    fn lcd_enabled(&self) -> bool
+    {
+        self.lcdc & LCDC_LCD_ENABLE != 0
+    }
+
+    /// Recalculate the STAT interrupt line. Returns true on a rising edge, which is the only
+    /// time the STAT interrupt is requested.
+    fn update_stat_line(&mut self) -> bool
+    {
+        let mode_source = match self.mode
+        {
+            Mode::HorizontalBlank => STAT_HBLANK_INTERRUPT,
+            Mode::VerticalBlank => STAT_VBLANK_INTERRUPT,
+            Mode::OamScan => STAT_OAM_INTERRUPT,
+            Mode::PixelTransfer => 0,
+        };
+        let line = self.stat_interrupts & mode_source != 0
+            || (self.stat_interrupts & STAT_LYC_INTERRUPT != 0 && self.ly == self.lyc);
+
+        let rising_edge = line && !self.stat_line;
+        self.stat_line = line;
+        rising_edge
+    }
+
+    fn render_scanline(&mut self)
+    {
+        // Colour numbers of the background and window are needed to decide sprite priority
+        let mut bg_colours = [0u8; SCREEN_WIDTH];
+        let mut bg_priority = [false; SCREEN_WIDTH];
+
+        self.render_background(&mut bg_colours, &mut bg_priority);
+        if self.lcdc & LCDC_OBJ_ENABLE != 0
+        {
+            self.render_sprites(&bg_colours, &bg_priority);
+        }
+    }
+
+    fn render_background(
+        &mut self,
+        bg_colours: &mut [u8; SCREEN_WIDTH],
+        bg_priority: &mut [bool; SCREEN_WIDTH],
+    )
+    {
+        // On the DMG clearing LCDC bit 0 blanks the background and window. On the CGB it only
+        // takes away their priority over sprites.
+        if !self.cgb_mode && self.lcdc & LCDC_BG_ENABLE == 0
+        {
+            for x in 0..SCREEN_WIDTH
+            {
+                self.set_dmg_pixel(x, 0, 0);
+            }
+            return;
+        }
+
+        let line = self.ly as usize;
+        let window_x = self.wx as i16 - 7;
+        let window_visible = self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.ly >= self.wy;
+        let mut window_drawn = false;
+
+        for x in 0..SCREEN_WIDTH
+        {
+            let (map_base, map_x, map_y) = if window_visible && x as i16 >= window_x
+            {
+                window_drawn = true;
+                let map_base = if self.lcdc & LCDC_WINDOW_TILE_MAP != 0 { 0x1C00 } else { 0x1800 };
+                (map_base, (x as i16 - window_x) as usize, self.window_line as usize)
+            }
+            else
+            {
+                let map_base = if self.lcdc & LCDC_BG_TILE_MAP != 0 { 0x1C00 } else { 0x1800 };
+                (map_base, (x + self.scx as usize) & 0xFF, (line + self.scy as usize) & 0xFF)
+            };
+
+            // On the CGB the second VRAM bank holds an attribute byte for every map entry
+            let map_index = map_base + (map_y / 8) * 32 + map_x / 8;
+            let tile_number = self.vram[map_index];
+            let attributes = if self.cgb_mode { self.vram1[map_index] } else { 0 };
+
+            let mut row = map_y % 8;
+            if attributes & ATTRIBUTE_Y_FLIP != 0
+            {
+                row = 7 - row;
+            }
+            let mut column = map_x % 8;
+            if attributes & ATTRIBUTE_X_FLIP != 0
+            {
+                column = 7 - column;
+            }
+
+            let address = self.tile_data_address(tile_number);
+            let colour =
+                self.tile_pixel(address, attributes & ATTRIBUTE_VRAM_BANK != 0, row, column);
+            bg_colours[x] = colour;
+            bg_priority[x] = attributes & ATTRIBUTE_PRIORITY != 0;
+
+            if self.cgb_mode
+            {
+                let rgba = cgb_colour(&self.bg_palettes, attributes & ATTRIBUTE_PALETTE, colour);
+                self.set_pixel(x, rgba);
+            }
+            else
+            {
+                self.set_dmg_pixel(x, 0, dmg_shade(self.bgp, colour));
+            }
+        }
+
+        if window_drawn
+        {
+            self.window_line += 1;
+        }
+    }
+
+    fn render_sprites(
+        &mut self,
+        bg_colours: &[u8; SCREEN_WIDTH],
+        bg_priority: &[bool; SCREEN_WIDTH],
+    )
+    {
+        let height = if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 };
+        let line = self.ly as i16;
+
+        // Only the first ten sprites in OAM that overlap the line are drawn
+        let mut sprites: Vec<usize> = (0..OAM_SIZE / 4)
+            .filter(|&sprite| {
+                let y = self.oam[sprite * 4] as i16 - 16;
+                line >= y && line < y + height
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+
+        // On the DMG the sprite with the smallest X coordinate is drawn on top, with ties going
+        // to the earlier OAM entry. The CGB only uses the OAM order.
+        if !self.cgb_mode
+        {
+            sprites.sort_by_key(|&sprite| self.oam[sprite * 4 + 1]);
+        }
+
+        for x in 0..SCREEN_WIDTH
+        {
+            for &sprite in sprites.iter()
+            {
+                let sprite_y = self.oam[sprite * 4] as i16 - 16;
+                let sprite_x = self.oam[sprite * 4 + 1] as i16 - 8;
+                let mut tile_number = self.oam[sprite * 4 + 2];
+                let attributes = self.oam[sprite * 4 + 3];
+
+                let column = x as i16 - sprite_x;
+                if !(0..8).contains(&column)
+                {
+                    continue;
+                }
+                let column = if attributes & ATTRIBUTE_X_FLIP != 0 { 7 - column } else { column };
+
+                let mut row = (line - sprite_y) as usize;
+                if attributes & ATTRIBUTE_Y_FLIP != 0
+                {
+                    row = height as usize - 1 - row;
+                }
+                if height == 16
+                {
+                    tile_number &= 0xFE;
+                }
+
+                let bank1 = self.cgb_mode && attributes & ATTRIBUTE_VRAM_BANK != 0;
+                let colour =
+                    self.tile_pixel(tile_number as usize * 16, bank1, row, column as usize);
+                // Colour 0 is transparent so a lower priority sprite may still show through
+                if colour == 0
+                {
+                    continue;
+                }
+
+                let bg_wins = bg_colours[x] != 0
+                    && if self.cgb_mode
+                    {
+                        self.lcdc & LCDC_BG_ENABLE != 0
+                            && (bg_priority[x] || attributes & ATTRIBUTE_PRIORITY != 0)
+                    }
+                    else
+                    {
+                        attributes & ATTRIBUTE_PRIORITY != 0
+                    };
+
+                if !bg_wins
+                {
+                    if self.cgb_mode
+                    {
+                        let palette = attributes & ATTRIBUTE_PALETTE;
+                        self.set_pixel(x, cgb_colour(&self.obj_palettes, palette, colour));
+                    }
+                    else if attributes & ATTRIBUTE_DMG_PALETTE != 0
+                    {
+                        self.set_dmg_pixel(x, 2, dmg_shade(self.obp1, colour));
+                    }
+                    else
+                    {
+                        self.set_dmg_pixel(x, 1, dmg_shade(self.obp0, colour));
+                    }
+                }
+                break;
+            }
+        }
+    }
+
+    /// Offset into VRAM of a background or window tile, following the addressing mode in LCDC
+    fn tile_data_address(&self, tile_number: u8) -> usize
+    {
+        if self.lcdc & LCDC_TILE_DATA != 0
+        {
+            tile_number as usize * 16
+        }
+        else
+        {
+            // Tiles are numbered -128 to 127 around 0x9000
+            (0x1000 + tile_number as i8 as i32 * 16) as usize
+        }
+    }
+
+    fn tile_pixel(&self, address: usize, bank1: bool, row: usize, column: usize) -> u8
+    {
+        let vram = if bank1 { &self.vram1 } else { &self.vram };
+        let low = vram[address + row * 2];
+        let high = vram[address + row * 2 + 1];
+        let bit = 7 - column;
+        ((high >> bit) & 0x1) << 1 | ((low >> bit) & 0x1)
+    }
+
+    fn set_pixel(&mut self, x: usize, rgba: [u8; 4])
+    {
+        let index = (self.ly as usize * SCREEN_WIDTH + x) * 4;
+        self.framebuffer[index..index + 4].copy_from_slice(&rgba);
+    }
+
+    /// Draw a DMG shade using the colours of the background, OBJ0 or OBJ1
+    fn set_dmg_pixel(&mut self, x: usize, colours: usize, shade: u8)
+    {
+        self.shades[self.ly as usize * SCREEN_WIDTH + x] = shade;
+        self.set_pixel(x, self.dmg_colours[colours][shade as usize]);
+    }
+}
+
+fn dmg_shade(palette: u8, colour: u8) -> u8
+{
+    (palette >> (colour * 2)) & 0x3
+}
+
+/// Look up a colour in CGB palette memory. Each palette has four little endian RGB555 colours.
+fn cgb_colour(palettes: &[u8; PALETTE_RAM_SIZE], palette: u8, colour: u8) -> [u8; 4]
+{
+    let index = (palette as usize * 4 + colour as usize) * 2;
+    rgb555_colour(palettes[index] as u16 | (palettes[index + 1] as u16) << 8)
+}
+
+pub fn rgb555_colour(value: u16) -> [u8; 4]
+{
+    // Scale the 5 bit channels up to 8 bits
+    let channel = |shift: u16| {
+        let component = ((value >> shift) & 0x1F) as u8;
+        component << 3 | component >> 2
+    };
+    [channel(0), channel(5), channel(10), 255]
+}
+
+/// Palette index registers advance after every data write when bit 7 is set
+fn next_palette_index(index: u8) -> u8
+{
+    if index & 0x80 != 0
+    {
+        0x80 | ((index + 1) & 0x3F)
+    }
+    else
+    {
+        index
+    }
+}
+
+impl SaveState for GPU
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bytes(&self.vram);
+        writer.bytes(&self.vram1);
+        writer.bytes(&self.oam);
+        writer.bytes(&self.framebuffer);
+        writer.bytes(&self.shades);
+        writer.u8(self.mode as u8);
+        writer.u16(self.cycles);
+        for register in [
+            self.lcdc,
+            self.stat_interrupts,
+            self.scy,
+            self.scx,
+            self.ly,
+            self.lyc,
+            self.bgp,
+            self.obp0,
+            self.obp1,
+            self.wy,
+            self.wx,
+            self.window_line,
+        ]
+        {
+            writer.u8(register);
+        }
+        writer.bool(self.stat_line);
+        for colours in self.dmg_colours.iter()
+        {
+            writer.bytes(colours.as_flattened());
+        }
+        writer.bool(self.cgb_mode);
+        writer.usize(self.vram_bank);
+        writer.bytes(&self.bg_palettes);
+        writer.bytes(&self.obj_palettes);
+        writer.u8(self.bg_palette_index);
+        writer.u8(self.obj_palette_index);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        reader.bytes(&mut self.vram)?;
+        reader.bytes(&mut self.vram1)?;
+        reader.bytes(&mut self.oam)?;
+        reader.bytes(&mut self.framebuffer)?;
+        reader.bytes(&mut self.shades)?;
+        self.mode = match reader.u8()?
+        {
+            0 => Mode::HorizontalBlank,
+            1 => Mode::VerticalBlank,
+            2 => Mode::OamScan,
+            3 => Mode::PixelTransfer,
+            _ => return Err(invalid_state("bad LCD mode")),
+        };
+        self.cycles = reader.u16()?;
+        for register in [
+            &mut self.lcdc,
+            &mut self.stat_interrupts,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.window_line,
+        ]
+        {
+            *register = reader.u8()?;
+        }
+        self.stat_line = reader.bool()?;
+        for colours in self.dmg_colours.iter_mut()
+        {
+            reader.bytes(colours.as_flattened_mut())?;
+        }
+        self.cgb_mode = reader.bool()?;
+        self.vram_bank = reader.usize()? & 0x01;
+        reader.bytes(&mut self.bg_palettes)?;
+        reader.bytes(&mut self.obj_palettes)?;
+        self.bg_palette_index = reader.u8()?;
+        self.obj_palette_index = reader.u8()?;
+
+        // Decode the tile set again by writing the tile data back to the first bank
+        let vram_bank = std::mem::replace(&mut self.vram_bank, 0);
+        for index in 0..0x1800
+        {
+            self.write_vram(index, self.vram[index]);
+        }
+        self.vram_bank = vram_bank;
+        Ok(())
+    }
 }
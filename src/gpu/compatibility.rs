@@ -0,0 +1,196 @@
+use crate::cartridge::Cartridge;
+use crate::joypad::{Button, Joypad};
+
+/// RGB555 colours used by the CGB boot ROM to colourise monochrome games. Combinations below
+/// index into this table by colour rather than by palette, and a few of them start part way
+/// through a palette.
+const PALETTE_COLOURS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB, 0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, 0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000, 0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000, 0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, 0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF, 0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000, 0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120, 0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000, 0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF, 0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+/// Offsets into `PALETTE_COLOURS` of the OBJ0, OBJ1 and BG palettes of every combination
+const COMBINATIONS: [[usize; 3]; 51] = [
+    [16, 16, 116],
+    [72, 72, 72],
+    [80, 80, 80],
+    [96, 96, 96],
+    [36, 36, 36],
+    [0, 0, 0],
+    [108, 108, 108],
+    [20, 20, 20],
+    [48, 48, 48],
+    [104, 104, 104],
+    [64, 32, 32],
+    [16, 112, 112],
+    [16, 8, 8],
+    [12, 16, 16],
+    [16, 116, 116],
+    [112, 16, 112],
+    [8, 68, 8],
+    [64, 64, 32],
+    [16, 16, 28],
+    [16, 16, 72],
+    [16, 16, 80],
+    [76, 76, 36],
+    [15, 15, 44],
+    [68, 68, 8],
+    [16, 16, 8],
+    [16, 16, 12],
+    [112, 112, 0],
+    [12, 12, 0],
+    [0, 0, 4],
+    [72, 88, 72],
+    [80, 88, 80],
+    [96, 88, 96],
+    [64, 88, 32],
+    [68, 16, 52],
+    [111, 0, 56],
+    [111, 16, 60],
+    [76, 88, 36],
+    [64, 112, 40],
+    [16, 92, 112],
+    [68, 88, 8],
+    [16, 0, 8],
+    [16, 112, 12],
+    [112, 12, 0],
+    [12, 112, 16],
+    [84, 112, 16],
+    [12, 112, 0],
+    [100, 12, 112],
+    [0, 112, 32],
+    [16, 12, 112],
+    [112, 12, 24],
+    [16, 112, 116],
+];
+
+// Used for games that aren't in the table or weren't published by Nintendo
+const DEFAULT_COMBINATION: usize = 0;
+
+/// Sums of the title bytes of the games the boot ROM recognises
+const TITLE_CHECKSUMS: [u8; 65] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0xC3, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+];
+
+/// Checksums shared by several games, which are told apart by the fourth letter of the title.
+/// Each row of `DUPLICATE_LETTERS` goes with this list once.
+const DUPLICATE_CHECKSUMS: [u8; 14] =
+    [0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4];
+const DUPLICATE_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// Combination used for every entry of `TITLE_CHECKSUMS` followed by `DUPLICATE_LETTERS`
+const CHECKSUM_COMBINATIONS: [usize; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 34, 23, 18, 29, 28,
+];
+
+/// Combinations that can be picked by holding a direction, optionally with A or B, while the
+/// boot logo is shown. The names are accepted on the command line.
+const BUTTON_COMBINATIONS: [(&str, Button, Option<Button>, usize); 12] = [
+    ("green", Button::Right, None, 1),
+    ("dark-green", Button::Right, Some(Button::A), 0),
+    ("inverted", Button::Right, Some(Button::B), 6),
+    ("blue", Button::Left, None, 48),
+    ("dark-blue", Button::Left, Some(Button::A), 40),
+    ("grayscale", Button::Left, Some(Button::B), 7),
+    ("brown", Button::Up, None, 5),
+    ("red", Button::Up, Some(Button::A), 43),
+    ("dark-brown", Button::Up, Some(Button::B), 28),
+    ("pastel-mix", Button::Down, None, 8),
+    ("orange", Button::Down, Some(Button::A), 3),
+    ("yellow", Button::Down, Some(Button::B), 49),
+];
+
+/// Colours the CGB uses in place of the four DMG shades when running a monochrome game
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CompatibilityPalette
+{
+    pub background: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatibilityPalette
+{
+    fn from_combination(combination: usize) -> Self
+    {
+        let [obj0, obj1, background] = COMBINATIONS[combination];
+        let colours = |offset: usize| {
+            let mut palette = [0; 4];
+            palette.copy_from_slice(&PALETTE_COLOURS[offset..offset + 4]);
+            palette
+        };
+        CompatibilityPalette {
+            background: colours(background),
+            obj0: colours(obj0),
+            obj1: colours(obj1),
+        }
+    }
+
+    /// Pick the palette the CGB boot ROM would choose from the cartridge header
+    pub fn for_cartridge(cartridge: &Cartridge) -> Self
+    {
+        if !cartridge.licensed_by_nintendo()
+        {
+            return Self::from_combination(DEFAULT_COMBINATION);
+        }
+
+        let checksum = cartridge.title_checksum();
+        let fourth_letter = cartridge.title_bytes()[3];
+
+        let index = TITLE_CHECKSUMS.iter().position(|&entry| entry == checksum).or_else(|| {
+            DUPLICATE_LETTERS
+                .iter()
+                .enumerate()
+                .position(|(index, &letter)| {
+                    DUPLICATE_CHECKSUMS[index % DUPLICATE_CHECKSUMS.len()] == checksum
+                        && letter == fourth_letter
+                })
+                .map(|index| TITLE_CHECKSUMS.len() + index)
+        });
+
+        let combination = index.map_or(DEFAULT_COMBINATION, |index| CHECKSUM_COMBINATIONS[index]);
+        Self::from_combination(combination)
+    }
+
+    /// The palette picked by the buttons currently held, if they form one of the combinations
+    pub fn for_buttons(joypad: &Joypad) -> Option<Self>
+    {
+        // A takes precedence over B when both are held
+        let modifier = [Button::A, Button::B].into_iter().find(|&button| joypad.is_pressed(button));
+
+        BUTTON_COMBINATIONS
+            .iter()
+            .find(|(_, direction, button, _)| joypad.is_pressed(*direction) && *button == modifier)
+            .map(|&(_, _, _, combination)| Self::from_combination(combination))
+    }
+
+    /// Look up one of the palettes that can be picked with buttons by name
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        BUTTON_COMBINATIONS
+            .iter()
+            .find(|(palette_name, ..)| *palette_name == name)
+            .map(|&(_, _, _, combination)| Self::from_combination(combination))
+    }
+
+    /// Names accepted by `from_name`
+    pub fn names() -> impl Iterator<Item = &'static str>
+    {
+        BUTTON_COMBINATIONS.iter().map(|(name, ..)| *name)
+    }
+}
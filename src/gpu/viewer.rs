@@ -0,0 +1,321 @@
+use super::{
+    cgb_colour, dmg_shade, ATTRIBUTE_DMG_PALETTE, ATTRIBUTE_PALETTE, ATTRIBUTE_VRAM_BANK,
+    ATTRIBUTE_X_FLIP, ATTRIBUTE_Y_FLIP, GPU, LCDC_BG_TILE_MAP, LCDC_OBJ_SIZE, OAM_SIZE,
+    SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+
+// Fills the space around tiles, sprites and swatches, and stands in for transparent sprite pixels
+const BACKGROUND: [u8; 4] = [48, 48, 48, 255];
+const TEXT: [u8; 4] = [255, 255, 255, 255];
+const VIEWPORT: [u8; 4] = [255, 0, 0, 255];
+
+// 384 tiles, 16 to a row
+const TILES_PER_ROW: usize = 16;
+const TILE_COUNT: usize = 384;
+// Both 32x32 tile maps side by side
+const MAP_SIZE: usize = 256;
+const MAP_GAP: usize = 8;
+// Every sprite on a row of its own: the sprite, then its Y, X, tile and attributes in hex
+const SPRITE_COUNT: usize = OAM_SIZE / 4;
+const SPRITE_COLUMNS: usize = 2;
+const SPRITE_ROW_HEIGHT: usize = 18;
+const SPRITE_COLUMN_WIDTH: usize = 64;
+// 8 background palettes on the left and 8 OBJ palettes on the right, 4 colours each
+const SWATCH_SIZE: usize = 16;
+const PALETTE_GAP: usize = 8;
+
+// Hexadecimal digits drawn 3 pixels wide and 5 high, the top row in the highest bits
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const GLYPHS: [u16; 16] = [
+    0b111_101_101_101_111,
+    0b010_110_010_010_111,
+    0b111_001_111_100_111,
+    0b111_001_111_001_111,
+    0b101_101_111_001_001,
+    0b111_100_111_001_111,
+    0b111_100_111_101_111,
+    0b111_001_001_001_001,
+    0b111_101_111_101_111,
+    0b111_101_111_001_111,
+    0b010_101_111_101_101,
+    0b110_101_110_101_110,
+    0b011_100_100_100_011,
+    0b110_101_101_101_110,
+    0b111_100_111_100_111,
+    0b111_100_111_100_100,
+];
+
+/// Pictures of what's in VRAM, OAM and palette memory, for debug windows next to the screen
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DebugView
+{
+    /// Every tile in a VRAM bank
+    Tiles,
+    /// Both background maps, with the part the screen shows outlined
+    TileMaps,
+    /// Every sprite in OAM with its position, tile and attributes
+    Sprites,
+    /// Background and OBJ palettes
+    Palettes,
+}
+
+impl DebugView
+{
+    pub const ALL: [DebugView; 4] =
+        [DebugView::Tiles, DebugView::TileMaps, DebugView::Sprites, DebugView::Palettes];
+
+    pub fn title(self) -> &'static str
+    {
+        match self
+        {
+            DebugView::Tiles => "Tiles",
+            DebugView::TileMaps => "Tile Maps",
+            DebugView::Sprites => "OAM",
+            DebugView::Palettes => "Palettes",
+        }
+    }
+
+    /// Width and height of the picture
+    pub fn size(self) -> (usize, usize)
+    {
+        match self
+        {
+            DebugView::Tiles => (TILES_PER_ROW * 8, TILE_COUNT / TILES_PER_ROW * 8),
+            DebugView::TileMaps => (MAP_SIZE * 2 + MAP_GAP, MAP_SIZE),
+            DebugView::Sprites =>
+            {
+                let rows = SPRITE_COUNT.div_ceil(SPRITE_COLUMNS);
+                (SPRITE_COLUMN_WIDTH * SPRITE_COLUMNS, rows * SPRITE_ROW_HEIGHT)
+            }
+            DebugView::Palettes => (SWATCH_SIZE * 8 + PALETTE_GAP, SWATCH_SIZE * 8),
+        }
+    }
+
+    /// Draw the picture as RGBA into `frame`, which must be `size` big. Tiles come from
+    /// `vram_bank`, which is only 1 on the CGB.
+    pub fn render(self, gpu: &GPU, frame: &mut [u8], vram_bank: usize)
+    {
+        let (width, _) = self.size();
+        let mut canvas = Canvas { frame, width };
+        for pixel in canvas.frame.chunks_exact_mut(4)
+        {
+            pixel.copy_from_slice(&BACKGROUND);
+        }
+        match self
+        {
+            DebugView::Tiles => gpu.draw_tiles(&mut canvas, vram_bank == 1),
+            DebugView::TileMaps => gpu.draw_tile_maps(&mut canvas),
+            DebugView::Sprites => gpu.draw_sprites(&mut canvas),
+            DebugView::Palettes => gpu.draw_palettes(&mut canvas),
+        }
+    }
+}
+
+struct Canvas<'a>
+{
+    frame: &'a mut [u8],
+    width: usize,
+}
+
+impl Canvas<'_>
+{
+    fn set(&mut self, x: usize, y: usize, rgba: [u8; 4])
+    {
+        let index = (y * self.width + x) * 4;
+        self.frame[index..index + 4].copy_from_slice(&rgba);
+    }
+
+    fn fill(&mut self, x: usize, y: usize, size: usize, rgba: [u8; 4])
+    {
+        for row in y..y + size
+        {
+            for column in x..x + size
+            {
+                self.set(column, row, rgba);
+            }
+        }
+    }
+
+    /// Write a byte as two hex digits with its top left corner at x, y
+    fn hex(&mut self, x: usize, y: usize, value: u8)
+    {
+        for (digit, nibble) in [value >> 4, value & 0x0F].into_iter().enumerate()
+        {
+            let glyph = GLYPHS[nibble as usize];
+            for row in 0..GLYPH_HEIGHT
+            {
+                for column in 0..GLYPH_WIDTH
+                {
+                    let bit = (GLYPH_HEIGHT - row) * GLYPH_WIDTH - column - 1;
+                    if glyph >> bit & 1 != 0
+                    {
+                        self.set(x + digit * (GLYPH_WIDTH + 1) + column, y + row, TEXT);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl GPU
+{
+    /// Colour of a background colour number. The palette only matters on the CGB.
+    fn background_colour(&self, palette: u8, colour: u8) -> [u8; 4]
+    {
+        if self.cgb_mode
+        {
+            cgb_colour(&self.bg_palettes, palette, colour)
+        }
+        else
+        {
+            self.dmg_colours[0][dmg_shade(self.bgp, colour) as usize]
+        }
+    }
+
+    /// Colour of a sprite colour number in the palette its attributes pick
+    fn sprite_colour(&self, attributes: u8, colour: u8) -> [u8; 4]
+    {
+        if self.cgb_mode
+        {
+            cgb_colour(&self.obj_palettes, attributes & ATTRIBUTE_PALETTE, colour)
+        }
+        else if attributes & ATTRIBUTE_DMG_PALETTE != 0
+        {
+            self.dmg_colours[2][dmg_shade(self.obp1, colour) as usize]
+        }
+        else
+        {
+            self.dmg_colours[1][dmg_shade(self.obp0, colour) as usize]
+        }
+    }
+
+    fn draw_tiles(&self, canvas: &mut Canvas, bank1: bool)
+    {
+        for tile in 0..TILE_COUNT
+        {
+            let tile_x = tile % TILES_PER_ROW * 8;
+            let tile_y = tile / TILES_PER_ROW * 8;
+            for row in 0..8
+            {
+                for column in 0..8
+                {
+                    let colour = self.tile_pixel(tile * 16, bank1, row, column);
+                    canvas.set(tile_x + column, tile_y + row, self.background_colour(0, colour));
+                }
+            }
+        }
+    }
+
+    fn draw_tile_maps(&self, canvas: &mut Canvas)
+    {
+        for (map, map_base) in [0x1800, 0x1C00].into_iter().enumerate()
+        {
+            let left = map * (MAP_SIZE + MAP_GAP);
+            for y in 0..MAP_SIZE
+            {
+                for x in 0..MAP_SIZE
+                {
+                    let map_index = map_base + (y / 8) * 32 + x / 8;
+                    let tile_number = self.vram[map_index];
+                    let attributes = if self.cgb_mode { self.vram1[map_index] } else { 0 };
+                    let row = if attributes & ATTRIBUTE_Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+                    let column = if attributes & ATTRIBUTE_X_FLIP != 0 { 7 - x % 8 } else { x % 8 };
+                    let colour = self.tile_pixel(
+                        self.tile_data_address(tile_number),
+                        attributes & ATTRIBUTE_VRAM_BANK != 0,
+                        row,
+                        column,
+                    );
+                    let rgba = self.background_colour(attributes & ATTRIBUTE_PALETTE, colour);
+                    canvas.set(left + x, y, rgba);
+                }
+            }
+        }
+
+        // The screen's view of the background map wraps around its edges
+        let left = if self.lcdc & LCDC_BG_TILE_MAP != 0 { MAP_SIZE + MAP_GAP } else { 0 };
+        let (scx, scy) = (self.scx as usize, self.scy as usize);
+        let mut outline = |x: usize, y: usize| {
+            canvas.set(left + (scx + x) % MAP_SIZE, (scy + y) % MAP_SIZE, VIEWPORT)
+        };
+        for x in 0..SCREEN_WIDTH
+        {
+            outline(x, 0);
+            outline(x, SCREEN_HEIGHT - 1);
+        }
+        for y in 0..SCREEN_HEIGHT
+        {
+            outline(0, y);
+            outline(SCREEN_WIDTH - 1, y);
+        }
+    }
+
+    fn draw_sprites(&self, canvas: &mut Canvas)
+    {
+        let height = if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 };
+        let rows = SPRITE_COUNT.div_ceil(SPRITE_COLUMNS);
+        for sprite in 0..SPRITE_COUNT
+        {
+            let left = sprite / rows * SPRITE_COLUMN_WIDTH + 1;
+            let top = sprite % rows * SPRITE_ROW_HEIGHT + 1;
+            let entry = &self.oam[sprite * 4..sprite * 4 + 4];
+            let attributes = entry[3];
+            let tile_number = if height == 16 { entry[2] & 0xFE } else { entry[2] };
+            let bank1 = self.cgb_mode && attributes & ATTRIBUTE_VRAM_BANK != 0;
+
+            for row in 0..height
+            {
+                for column in 0..8
+                {
+                    let colour = self.tile_pixel(tile_number as usize * 16, bank1, row, column);
+                    // Colour 0 is transparent
+                    if colour != 0
+                    {
+                        canvas.set(
+                            left + column,
+                            top + row,
+                            self.sprite_colour(attributes, colour),
+                        );
+                    }
+                }
+            }
+
+            // Y, X, tile and attributes as they are in OAM
+            let text_top = top + (16 - GLYPH_HEIGHT) / 2;
+            for (field, &value) in entry.iter().enumerate()
+            {
+                canvas.hex(left + 12 + field * 12, text_top, value);
+            }
+        }
+    }
+
+    fn draw_palettes(&self, canvas: &mut Canvas)
+    {
+        for palette in 0..8u8
+        {
+            let top = palette as usize * SWATCH_SIZE;
+            for colour in 0..4u8
+            {
+                let left = colour as usize * SWATCH_SIZE;
+                let right = SWATCH_SIZE * 4 + PALETTE_GAP + left;
+                if self.cgb_mode
+                {
+                    canvas.fill(left, top, SWATCH_SIZE, self.background_colour(palette, colour));
+                    canvas.fill(right, top, SWATCH_SIZE, self.sprite_colour(palette, colour));
+                    continue;
+                }
+                // Outside of CGB mode there's only BGP, then OBP0 and OBP1
+                if palette == 0
+                {
+                    canvas.fill(left, top, SWATCH_SIZE, self.background_colour(0, colour));
+                }
+                if palette < 2
+                {
+                    let attributes = if palette == 1 { ATTRIBUTE_DMG_PALETTE } else { 0 };
+                    canvas.fill(right, top, SWATCH_SIZE, self.sprite_colour(attributes, colour));
+                }
+            }
+        }
+    }
+}
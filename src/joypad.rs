@@ -0,0 +1,234 @@
+use std::io;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const JOYP_ADDRESS: usize = 0xFF00;
+
+// Writing 0 to these bits of JOYP selects which group of buttons is read back
+const SELECT_DIRECTIONS: u8 = 0x10;
+const SELECT_ACTIONS: u8 = 0x20;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Button
+{
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button
+{
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    /// Bit used for the button within its group
+    fn mask(self) -> u8
+    {
+        match self
+        {
+            Button::Right | Button::A => 0x01,
+            Button::Left | Button::B => 0x02,
+            Button::Up | Button::Select => 0x04,
+            Button::Down | Button::Start => 0x08,
+        }
+    }
+
+    /// Bit used for the button in a mask of every button, in the order of `Button::ALL`
+    pub fn bit(self) -> u8
+    {
+        if self.is_direction()
+        {
+            self.mask()
+        }
+        else
+        {
+            self.mask() << 4
+        }
+    }
+
+    fn is_direction(self) -> bool
+    {
+        matches!(self, Button::Right | Button::Left | Button::Up | Button::Down)
+    }
+}
+
+// The Super Game Boy can read up to four controllers
+pub const MAX_PLAYERS: usize = 4;
+
+// Pressed buttons are stored as set bits, the register reports them as cleared bits
+#[derive(Copy, Clone, Default)]
+struct Buttons
+{
+    directions: u8,
+    actions: u8,
+}
+
+impl Buttons
+{
+    fn group(&mut self, button: Button) -> &mut u8
+    {
+        if button.is_direction()
+        {
+            &mut self.directions
+        }
+        else
+        {
+            &mut self.actions
+        }
+    }
+}
+
+pub struct Joypad
+{
+    select: u8,
+    players: [Buttons; MAX_PLAYERS],
+    current_player: usize,
+    interrupt_requested: bool,
+}
+
+impl Default for Joypad
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Joypad
+{
+    pub fn new() -> Self
+    {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            players: [Buttons::default(); MAX_PLAYERS],
+            current_player: 0,
+            interrupt_requested: false,
+        }
+    }
+
+    pub fn read(&self) -> u8
+    {
+        let buttons = self.players[self.current_player];
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0
+        {
+            pressed |= buttons.directions;
+        }
+        if self.select & SELECT_ACTIONS == 0
+        {
+            pressed |= buttons.actions;
+        }
+        // With neither group selected the Super Game Boy reports which controller is being
+        // read, 0xF for the first and counting down from there
+        if self.select == SELECT_DIRECTIONS | SELECT_ACTIONS
+        {
+            pressed = self.current_player as u8;
+        }
+        0xC0 | self.select | (!pressed & 0x0F)
+    }
+
+    pub fn write(&mut self, value: u8)
+    {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool)
+    {
+        self.set_player_button(0, button, pressed);
+    }
+
+    pub fn set_player_button(&mut self, player: usize, button: Button, pressed: bool)
+    {
+        let group = self.players[player].group(button);
+        let was_pressed = *group & button.mask() != 0;
+        if pressed
+        {
+            *group |= button.mask();
+        }
+        else
+        {
+            *group &= !button.mask();
+        }
+
+        // The interrupt fires when a line of a selected group goes low
+        let group_selected = if button.is_direction()
+        {
+            self.select & SELECT_DIRECTIONS == 0
+        }
+        else
+        {
+            self.select & SELECT_ACTIONS == 0
+        };
+        if pressed && !was_pressed && group_selected && player == self.current_player
+        {
+            self.interrupt_requested = true;
+        }
+    }
+
+    /// True if the first player is holding `button`
+    pub fn is_pressed(&self, button: Button) -> bool
+    {
+        let mut buttons = self.players[0];
+        *buttons.group(button) & button.mask() != 0
+    }
+
+    /// Every button a controller is holding, as a mask of `Button::bit`
+    pub fn buttons(&self, player: usize) -> u8
+    {
+        let buttons = self.players[player];
+        buttons.directions | buttons.actions << 4
+    }
+
+    /// Select the controller read through JOYP, used by Super Game Boy multiplayer
+    pub fn set_current_player(&mut self, player: usize)
+    {
+        self.current_player = player;
+    }
+
+    /// True once for every joypad interrupt that has been requested
+    pub fn take_interrupt(&mut self) -> bool
+    {
+        std::mem::take(&mut self.interrupt_requested)
+    }
+}
+
+impl SaveState for Joypad
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.u8(self.select);
+        for buttons in self.players.iter()
+        {
+            writer.u8(buttons.directions);
+            writer.u8(buttons.actions);
+        }
+        writer.usize(self.current_player);
+        writer.bool(self.interrupt_requested);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.select = reader.u8()?;
+        for buttons in self.players.iter_mut()
+        {
+            buttons.directions = reader.u8()?;
+            buttons.actions = reader.u8()?;
+        }
+        self.current_player = reader.usize()? % MAX_PLAYERS;
+        self.interrupt_requested = reader.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+//! Game Boy, Super Game Boy and Game Boy Color emulation. `GameBoy` runs the whole system a
+//! frame at a time, the modules below give access to the individual components.
+
+pub mod apu;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod cpu;
+pub mod crc;
+pub mod debugger;
+pub mod gameboy;
+pub mod gdb;
+pub mod gpu;
+pub mod joypad;
+pub mod model;
+pub mod movie;
+pub mod profiler;
+pub mod rewind;
+pub mod savestate;
+pub mod serial;
+pub mod sgb;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+
+pub use gameboy::GameBoy;
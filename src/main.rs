@@ -1,19 +1,67 @@
-mod cpu;
-pub mod gpu;
-
+use emulator::cartridge::Cartridge;
+use emulator::cdl::{self, CodeDataLog};
+use emulator::cheats::Cheats;
+use emulator::cpu::disassembler::disassemble_with_labels;
+use emulator::debugger::Debugger;
+use emulator::gdb::GdbStub;
+use emulator::gpu::compatibility::CompatibilityPalette;
+use emulator::gpu::viewer::DebugView;
+use emulator::joypad::Button;
+use emulator::model::Model;
+use emulator::movie::Movie;
+use emulator::rewind::Rewind;
+use emulator::serial;
+use emulator::serial::link_cable::TcpLinkPartner;
+use emulator::serial::printer::GameBoyPrinter;
+use emulator::symbols::Symbols;
+use emulator::trace::Trace;
+use emulator::GameBoy;
 use pixels::{Pixels, SurfaceTexture};
 use winit::{
-    dpi::{LogicalSize, PhysicalSize},
+    dpi::LogicalSize,
     event::{ElementState, Event, WindowEvent},
-    event_loop::{ControlFlow, EventLoop},
-    keyboard::NamedKey,
-    window::WindowBuilder,
+    event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget},
+    keyboard::{Key, NamedKey},
+    window::{Window, WindowBuilder},
 };
 
-use std::{thread, time::Duration};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const DMG_BOOT_ROM_PATH: &str = "dmg_boot.bin";
+const CGB_BOOT_ROM_PATH: &str = "cgb_boot.bin";
+
+// Bytes listed on each line of data when disassembling
+const DB_LINE_LENGTH: usize = 8;
+
+// The LCD refreshes at about 59.7Hz
+const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+
+// Options followed by a value, which must not be mistaken for the ROM path
+const OPTIONS_WITH_VALUES: [&str; 10] = [
+    "--link-listen",
+    "--link-connect",
+    "--palette",
+    "--model",
+    "--rewind-interval",
+    "--record-movie",
+    "--play-movie",
+    "--trace",
+    "--gdb",
+    "--profile",
+];
+
+// Debug windows are drawn at a smaller scale than the screen to fit beside it
+const DEBUG_WINDOW_SCALE: usize = 2;
+
+// How far back holding R can rewind, and how many frames pass between snapshots by default
+const REWIND_SECONDS: u32 = 120;
+const DEFAULT_REWIND_INTERVAL: u32 = 4;
 
 fn load_boot_rom(path: &str) -> std::io::Result<Vec<u8>>
 {
@@ -25,47 +73,269 @@ fn load_boot_rom(path: &str) -> std::io::Result<Vec<u8>>
 
 fn main() -> Result<(), pixels::Error>
 {
-    let boot_rom = load_boot_rom("dmg_boot.bin").expect("Failed to load boot ROM");
-    println!("Boot ROM loaded, {} bytes", boot_rom.len());
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|arg| arg == "disassemble")
+    {
+        disassemble_file(&args[2..]);
+        return Ok(());
+    }
+    let rom_path = rom_path_from_args(&args);
+    let cartridge =
+        rom_path.map(|path| Cartridge::load(Path::new(path)).expect("Failed to load ROM"));
+    let symbols = rom_path.map_or_else(Symbols::new, |path| load_symbols(Path::new(path)));
+
+    let mut title = String::from("Game Boy Emulator");
+    if let Some(cartridge) = cartridge.as_ref()
+    {
+        title = format!("{} - {}", title, cartridge.title());
+    }
+
+    let model = model_from_args(&args, cartridge.as_ref());
+
+    // `--play-movie <file>` replays a recording, printing frame hashes and exiting straight
+    // away with `--headless`. `--record-movie <file>` records from power on, saving on exit.
+    let movie_to_play = option_value(&args, "--play-movie")
+        .map(|path| Movie::load(Path::new(path)).expect("Failed to load movie"));
+    let record_path = option_value(&args, "--record-movie").map(PathBuf::from);
+    if let Some(movie) = movie_to_play.as_ref()
+    {
+        if args.iter().any(|arg| arg == "--headless")
+        {
+            let cartridge = cartridge.expect("Playing a movie needs a ROM");
+            let (final_frame, all_frames) =
+                movie.play_headless(cartridge).expect("Failed to play movie");
+            println!("Frames: {}", movie.frame_count());
+            println!("Final frame: {:08x}", final_frame);
+            println!("All frames: {:08x}", all_frames);
+            return Ok(());
+        }
+    }
+
+    // Colour games need the CGB boot ROM to start up in colour mode. Monochrome games on a CGB
+    // go through the DMG boot ROM and get their palette from the emulator instead.
+    let boot_rom_path = match cartridge.as_ref()
+    {
+        Some(cartridge) if model.is_cgb() && cartridge.supports_cgb() => CGB_BOOT_ROM_PATH,
+        _ => DMG_BOOT_ROM_PATH,
+    };
+    // `--trace <file>` logs every instruction for comparing with Gameboy Doctor, whose logs
+    // start after the boot ROM
+    let trace_path = option_value(&args, "--trace").map(PathBuf::from);
+    // Movies start from power on without the boot ROM, which not everyone has
+    let boot_rom = if args.iter().any(|arg| arg == "--skip-boot-rom")
+        || movie_to_play.is_some()
+        || record_path.is_some()
+        || trace_path.is_some()
+    {
+        None
+    }
+    else
+    {
+        match load_boot_rom(boot_rom_path)
+        {
+            Ok(boot_rom) =>
+            {
+                println!("Boot ROM loaded, {} bytes", boot_rom.len());
+                Some(boot_rom)
+            }
+            // Boot ROMs can't be distributed, so carry on without one
+            Err(error) =>
+            {
+                println!(
+                    "Failed to load boot ROM {}: {}, starting without it",
+                    boot_rom_path, error
+                );
+                None
+            }
+        }
+    };
+
+    let palette =
+        cartridge.as_ref().and_then(|cartridge| palette_from_args(&args, cartridge, model));
+    let mut gameboy = match movie_to_play.as_ref()
+    {
+        Some(movie) => movie
+            .start(cartridge.expect("Playing a movie needs a ROM"))
+            .expect("Failed to start movie"),
+        None => GameBoy::new(model, boot_rom, cartridge),
+    };
+    if let Some(path) = trace_path.as_ref()
+    {
+        let trace = Trace::create(path).expect("Failed to create trace");
+        gameboy.start_trace(trace.with_symbols(symbols.clone()));
+    }
+    // `--profile <file>` prints the hottest functions on exit and writes the call stacks to the
+    // file in the folded format flame graph tools read
+    let profile_path = option_value(&args, "--profile").map(PathBuf::from);
+    if profile_path.is_some()
+    {
+        gameboy.start_profiling();
+    }
+    // `--cdl` marks the bytes of the ROM the game runs as code and reads as data, adding to the
+    // log in the `.cdl` file next to the ROM, which `disassemble` uses to tell code from data
+    let cdl_path = rom_path
+        .filter(|_| args.iter().any(|arg| arg == "--cdl"))
+        .map(|path| Path::new(path).with_extension("cdl"));
+    if let Some(path) = cdl_path.as_ref()
+    {
+        start_code_data_log(&mut gameboy, path);
+    }
+    // Cheats come from the `.cht` file next to the ROM and changes made in the debugger are saved
+    // back to it. C turns them all off and back on. Movies are played and recorded without them,
+    // as they would change the game.
+    let cheats_path = rom_path
+        .filter(|_| movie_to_play.is_none() && record_path.is_none())
+        .map(|path| Path::new(path).with_extension("cht"));
+    if let Some(path) = cheats_path.as_ref()
+    {
+        load_cheats(&mut gameboy, path);
+    }
+    let mut recording = record_path.as_ref().map(|_| Movie::from_power_on(&gameboy));
+    let mut movie_frame = 0;
+    if let Some(palette) = palette
+    {
+        gameboy.set_compatibility_palette(&palette);
+    }
 
-    let mut cpu = cpu::CPU::new(boot_rom);
+    // Keep whatever the game sends over the link cable so test ROM results can be reported,
+    // unless a link cable to another emulator or a printer has been requested
+    let serial_log = if let Some(link_cable) = link_cable_from_args(&args)
+    {
+        gameboy.connect_serial(Box::new(link_cable));
+        None
+    }
+    else if args.iter().any(|arg| arg == "--printer")
+    {
+        // Printouts are saved next to the ROM
+        let printer = GameBoyPrinter::new(Path::new(rom_path.unwrap_or(boot_rom_path)));
+        gameboy.connect_serial(Box::new(printer));
+        None
+    }
+    else
+    {
+        let capture = serial::CapturePartner::new();
+        let serial_log = capture.log();
+        gameboy.connect_serial(Box::new(capture));
+        Some(serial_log)
+    };
 
     let event_loop = EventLoop::new().unwrap();
 
     let scale = 4;
-    let logical_width = 160.0 * scale as f64;
-    let logical_height = 144.0 * scale as f64;
+    let (width, height) = gameboy.screen_size();
+    let logical_width = (width * scale) as f64;
+    let logical_height = (height * scale) as f64;
 
     let window = WindowBuilder::new()
-        .with_title("Game Boy Emulator")
+        .with_title(title)
         .with_inner_size(LogicalSize::new(logical_width, logical_height))
         .build(&event_loop)
         .unwrap();
 
     let window_size = window.inner_size(); // This is a PhysicalSize<u32>
     let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-    let mut pixels: Pixels = Pixels::new(160, 144, surface_texture)?;
+    let mut pixels: Pixels = Pixels::new(width as u32, height as u32, surface_texture)?;
+    let mut next_frame = Instant::now();
+    let mut shift_held = false;
+    let mut rewind = rewind_from_args(&args);
+    let mut rewinding = false;
+    // Rewinding or loading a state would leave the movie out of step with the game
+    let movie_active = movie_to_play.is_some() || recording.is_some();
+    let rom_path = rom_path.map(|path| Path::new(path).to_path_buf());
+    let exit_files = ExitFiles { record_path, profile_path, cdl_path, cheats_path };
+    let mut debugger = debugger_from_args(&args, movie_active, symbols.clone());
+    let debugger_commands = debugger.as_ref().map(|_| read_commands());
+    let mut gdb = gdb_from_args(&args, movie_active);
+    // T, M, O and P open the tile, tile map, OAM and palette viewers. B picks the VRAM bank of
+    // the tile viewer on the CGB.
+    let mut debug_windows: Vec<DebugWindow> = Vec::new();
+    let mut tile_bank = 0;
 
     let _ = event_loop.run(move |event, event_loop_target| {
         event_loop_target.set_control_flow(ControlFlow::Poll);
 
         match event
         {
-            Event::WindowEvent { event, .. } => match event
+            Event::WindowEvent { window_id, event } => match event
             {
+                WindowEvent::CloseRequested if window_id != window.id() =>
+                {
+                    debug_windows.retain(|debug_window| debug_window.window.id() != window_id);
+                }
+
                 WindowEvent::CloseRequested =>
                 {
+                    shut_down(&mut gameboy, &serial_log, recording.as_ref(), &exit_files, &symbols);
                     event_loop_target.exit();
                 }
 
+                WindowEvent::ModifiersChanged(modifiers) =>
+                {
+                    shift_held = modifiers.state().shift_key();
+                }
+
                 WindowEvent::KeyboardInput { event, .. } =>
                 {
+                    if let Some((player, button)) = button_for_key(&event.logical_key)
+                    {
+                        let pressed = event.state == ElementState::Pressed;
+                        gameboy.set_button(player, button, pressed);
+                    }
+                    if let Key::Character(character) = &event.logical_key
+                    {
+                        if character.to_lowercase() == "r"
+                        {
+                            rewinding = event.state == ElementState::Pressed && !movie_active;
+                        }
+                        if character.to_lowercase() == "b"
+                            && event.state == ElementState::Pressed
+                            && gameboy.model().is_cgb()
+                        {
+                            tile_bank ^= 1;
+                        }
+                        if character.to_lowercase() == "c"
+                            && event.state == ElementState::Pressed
+                            && exit_files.cheats_path.is_some()
+                        {
+                            let cheats = gameboy.cheats_mut();
+                            cheats.active = !cheats.active;
+                            println!("Cheats {}", if cheats.active { "on" } else { "off" });
+                        }
+                    }
+
                     if event.state == ElementState::Pressed
                     {
-                        if let winit::keyboard::Key::Named(NamedKey::Escape) = event.logical_key
+                        if let Key::Named(NamedKey::Escape) = event.logical_key
                         {
+                            shut_down(
+                                &mut gameboy,
+                                &serial_log,
+                                recording.as_ref(),
+                                &exit_files,
+                                &symbols,
+                            );
                             event_loop_target.exit();
                         }
+                        if let Some(view) = debug_view_for_key(&event.logical_key)
+                        {
+                            toggle_debug_window(&mut debug_windows, view, event_loop_target);
+                        }
+                        if let (Some(slot), Some(rom_path), false) = (
+                            state_slot_for_key(&event.logical_key),
+                            rom_path.as_ref(),
+                            movie_active,
+                        )
+                        {
+                            let path = rom_path.with_extension(format!("ss{}", slot));
+                            if shift_held
+                            {
+                                save_state(&gameboy, &path);
+                            }
+                            else if load_state(&mut gameboy, &path)
+                            {
+                                rewind.clear();
+                            }
+                        }
                         /*if let winit::keyboard::Key::Named(NamedKey::Shift) = event.logical_key
                         {
                             thread::sleep(Duration::from_millis(500));
@@ -73,11 +343,27 @@ fn main() -> Result<(), pixels::Error>
                     }
                 }
 
+                WindowEvent::RedrawRequested if window_id != window.id() =>
+                {
+                    let Some(debug_window) = debug_windows
+                        .iter_mut()
+                        .find(|debug_window| debug_window.window.id() == window_id)
+                    else
+                    {
+                        return;
+                    };
+                    let gpu = &gameboy.cpu().bus.gpu;
+                    debug_window.view.render(gpu, debug_window.pixels.frame_mut(), tile_bank);
+                    if let Err(error) = debug_window.pixels.render()
+                    {
+                        println!("Failed to draw {} window: {}", debug_window.view.title(), error);
+                        debug_windows.retain(|debug_window| debug_window.window.id() != window_id);
+                    }
+                }
+
                 WindowEvent::RedrawRequested =>
                 {
-                    let vram = cpu.bus.gpu.vram;
-                    render_tileset(&vram, pixels.frame_mut(), 160 as usize);
-                    //thread::sleep(Duration::from_millis(10));
+                    pixels.frame_mut().copy_from_slice(gameboy.framebuffer());
 
                     if pixels.render().is_err()
                     {
@@ -91,11 +377,74 @@ fn main() -> Result<(), pixels::Error>
 
             Event::AboutToWait =>
             {
-                for _ in 0..100
+                // Keep to the speed of the real hardware
+                let now = Instant::now();
+                if now < next_frame
+                {
+                    thread::sleep(next_frame - now);
+                }
+                next_frame = next_frame.max(now) + FRAME_DURATION;
+
+                if rewinding
                 {
-                    cpu.step(); // or whatever your stepping function is
+                    rewind.step_back(&mut gameboy);
+                }
+                else
+                {
+                    // Once the movie is over the keyboard takes over
+                    if let Some(movie) = movie_to_play.as_ref()
+                    {
+                        if movie.apply_frame(movie_frame, &mut gameboy)
+                        {
+                            movie_frame += 1;
+                        }
+                    }
+                    if let Some(movie) = recording.as_mut()
+                    {
+                        movie.record_frame(&gameboy);
+                    }
+                    match debugger.as_mut()
+                    {
+                        _ if gdb.is_some() =>
+                        {
+                            if gdb.as_mut().is_some_and(|gdb| gdb.run_frame(&mut gameboy))
+                            {
+                                rewind.record(&gameboy);
+                            }
+                        }
+                        Some(debugger) =>
+                        {
+                            for line in
+                                debugger_commands.iter().flat_map(|commands| commands.try_iter())
+                            {
+                                let output = debugger.execute(&mut gameboy, &line);
+                                if !output.is_empty()
+                                {
+                                    println!("{}", output);
+                                }
+                            }
+                            // Only whole frames go into the rewind buffer
+                            if !debugger.is_paused()
+                            {
+                                match debugger.run_frame(&mut gameboy)
+                                {
+                                    Some(reason) => println!("{}", reason),
+                                    None => rewind.record(&gameboy),
+                                }
+                            }
+                        }
+                        None =>
+                        {
+                            gameboy.run_frame();
+                            rewind.record(&gameboy);
+                        }
+                    }
                 }
                 window.request_redraw();
+                for debug_window in debug_windows.iter()
+                {
+                    debug_window.window.request_redraw();
+                }
             }
 
             _ =>
@@ -105,74 +454,564 @@ fn main() -> Result<(), pixels::Error>
     Ok(())
 }
 
-const TILE_WIDTH: usize = 8;
-const TILE_HEIGHT: usize = 8;
-const TILE_BYTES: usize = 16;
-const TILE_COUNT: usize = 384;
-const TILES_PER_ROW: usize = 24;
-const TILESET_ROWS: usize = TILE_COUNT / TILES_PER_ROW; // 16
+/// A window showing what's in VRAM, OAM or palette memory
+struct DebugWindow
+{
+    view: DebugView,
+    window: Window,
+    pixels: Pixels,
+}
 
-/// Decode a single tile
-fn decode_tile(tile_data: &[u8]) -> [[u8; 8]; 8]
+fn debug_view_for_key(key: &Key) -> Option<DebugView>
 {
-    let mut tile = [[0u8; 8]; 8];
+    let Key::Character(character) = key
+    else
+    {
+        return None;
+    };
+    match character.to_lowercase().as_str()
+    {
+        "t" => Some(DebugView::Tiles),
+        "m" => Some(DebugView::TileMaps),
+        "o" => Some(DebugView::Sprites),
+        "p" => Some(DebugView::Palettes),
+        _ => None,
+    }
+}
 
-    for y in 0..8
+/// Open the window for a debug view, or close it if it's already open
+fn toggle_debug_window(
+    debug_windows: &mut Vec<DebugWindow>,
+    view: DebugView,
+    event_loop_target: &EventLoopWindowTarget<()>,
+)
+{
+    if let Some(index) = debug_windows.iter().position(|debug_window| debug_window.view == view)
     {
-        let low = tile_data[y * 2];
-        let high = tile_data[y * 2 + 1];
+        debug_windows.remove(index);
+        return;
+    }
 
-        for x in 0..8
+    let (width, height) = view.size();
+    let size =
+        LogicalSize::new((width * DEBUG_WINDOW_SCALE) as f64, (height * DEBUG_WINDOW_SCALE) as f64);
+    let window = match WindowBuilder::new()
+        .with_title(view.title())
+        .with_inner_size(size)
+        .build(event_loop_target)
+    {
+        Ok(window) => window,
+        Err(error) =>
         {
-            let bit = 1 << (7 - x);
-            let lo_bit = (low & bit) >> (7 - x);
-            let hi_bit = (high & bit) >> (7 - x);
-            tile[y][x] = (hi_bit << 1) | lo_bit;
+            println!("Failed to open {} window: {}", view.title(), error);
+            return;
         }
+    };
+    let window_size = window.inner_size();
+    let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
+    match Pixels::new(width as u32, height as u32, surface_texture)
+    {
+        Ok(pixels) => debug_windows.push(DebugWindow { view, window, pixels }),
+        Err(error) => println!("Failed to open {} window: {}", view.title(), error),
     }
+}
+
+/// `disassemble <file> [start] [end]` lists the code in a ROM or boot ROM from the hexadecimal
+/// file offset `start` up to `end`. Offsets past the first bank are shown as the bank number and
+/// the address the bank is mapped to. Labels from a symbol file next to the ROM are shown before
+/// the code they name and in place of the addresses they stand for. With a code/data log next to
+/// the ROM, bytes the game only ever read as data are listed as `DB` rather than code.
+fn disassemble_file(args: &[String])
+{
+    let Some(path) = args.first()
+    else
+    {
+        println!("Usage: disassemble <file> [start] [end]");
+        return;
+    };
+    let data = std::fs::read(path).expect("Failed to read file to disassemble");
+    let symbols = load_symbols(Path::new(path));
+    let log = load_code_data_log(Path::new(path), data.len());
+    let is_data = |position: usize| {
+        log.as_ref().is_some_and(|log| {
+            let flags = log.flags(position);
+            flags != 0 && flags & cdl::OPCODE == 0
+        })
+    };
+    let location = |position: usize| {
+        let bank = position / 0x4000;
+        (bank, if bank == 0 { position } else { 0x4000 | (position % 0x4000) } as u16)
+    };
+    let offset = |index: usize, default: usize| {
+        args.get(index).map_or(default, |offset| {
+            let digits = offset.trim_start_matches("0x").trim_start_matches('$');
+            usize::from_str_radix(digits, 16).expect("Offsets must be hexadecimal")
+        })
+    };
+    let end = offset(2, data.len()).min(data.len());
 
-    tile
+    let mut position = offset(1, 0);
+    while position < end
+    {
+        let (bank, address) = location(position);
+        // Code in a switchable bank calls into its own bank or the fixed one
+        let labels = |target: u16| {
+            let target_bank = if (0x4000..0x8000).contains(&target) { bank } else { 0 };
+            symbols.label_at(target_bank, target).map(str::to_string)
+        };
+        if let Some(label) = symbols.label_at(bank, address)
+        {
+            println!("{}:", label);
+        }
+        if is_data(position)
+        {
+            // Up to 8 bytes to a line, stopping at the next label or bank
+            let length = (1..DB_LINE_LENGTH)
+                .find(|&length| {
+                    let (next_bank, next_address) = location(position + length);
+                    position + length >= end
+                        || !is_data(position + length)
+                        || next_bank != bank
+                        || symbols.label_at(next_bank, next_address).is_some()
+                })
+                .unwrap_or(DB_LINE_LENGTH);
+            let bytes: Vec<String> = data[position..position + length]
+                .iter()
+                .map(|byte| format!("${:02X}", byte))
+                .collect();
+            println!("{:02X}:{:04X}  {:<9} DB {}", bank, address, "", bytes.join(", "));
+            position += length;
+            continue;
+        }
+        let instruction = disassemble_with_labels(&data[position..], address, &labels);
+        let length = instruction.length as usize;
+        let bytes: Vec<String> =
+            data[position..position + length].iter().map(|byte| format!("{:02X}", byte)).collect();
+        println!("{:02X}:{:04X}  {:<9} {}", bank, address, bytes.join(" "), instruction.text);
+        position += length;
+    }
 }
 
-/// Convert pixel value to RGBA
-fn pixel_value_to_rgba(value: u8) -> [u8; 4]
+/// The code/data log next to a ROM, if it has one
+fn load_code_data_log(rom_path: &Path, rom_size: usize) -> Option<CodeDataLog>
 {
-    match value
+    let path = rom_path.with_extension("cdl");
+    if !path.exists()
+    {
+        return None;
+    }
+    match CodeDataLog::load(&path, rom_size)
     {
-        0 => [255, 255, 255, 255],
-        1 => [192, 192, 192, 255],
-        2 => [96, 96, 96, 255],
-        3 => [0, 0, 0, 255],
-        _ => [255, 0, 255, 255], // Error color
+        Ok(log) =>
+        {
+            println!("Loaded code/data log from {}", path.display());
+            Some(log)
+        }
+        Err(error) =>
+        {
+            println!("Failed to load code/data log {}: {}", path.display(), error);
+            None
+        }
     }
 }
 
-/// Render all tiles to the frame buffer
-fn render_tileset(vram: &[u8], frame: &mut [u8], frame_width: usize)
+/// Symbols from the `.sym` or `.map` file next to a ROM, if it has one
+fn load_symbols(rom_path: &Path) -> Symbols
 {
-    for tile_index in 0..TILE_COUNT
+    match Symbols::for_rom(rom_path)
     {
-        let tile_x = tile_index % TILES_PER_ROW;
-        let tile_y = tile_index / TILES_PER_ROW;
+        Some((symbols, path)) =>
+        {
+            println!("Loaded {} symbols from {}", symbols.len(), path.display());
+            symbols
+        }
+        None => Symbols::new(),
+    }
+}
 
-        let tile_base = tile_index * TILE_BYTES;
-        let tile_data = &vram[tile_base..tile_base + TILE_BYTES];
-        let tile = decode_tile(tile_data);
+/// The ROM is the first argument that isn't an option
+fn rom_path_from_args(args: &[String]) -> Option<&str>
+{
+    let mut index = 1;
+    while index < args.len()
+    {
+        let arg = args[index].as_str();
+        if OPTIONS_WITH_VALUES.contains(&arg)
+        {
+            index += 2;
+            continue;
+        }
+        if !arg.starts_with("--")
+        {
+            return Some(arg);
+        }
+        index += 1;
+    }
+    None
+}
+
+/// The value following an option
+fn option_value<'a>(args: &'a [String], option: &str) -> Option<&'a str>
+{
+    let position = args.iter().position(|arg| arg == option)?;
+    let value = args.get(position + 1).unwrap_or_else(|| panic!("Missing value for {}", option));
+    Some(value.as_str())
+}
+
+/// Set up a link cable from `--link-listen <address>` or `--link-connect <address>`
+fn link_cable_from_args(args: &[String]) -> Option<TcpLinkPartner>
+{
+    let position = args.iter().position(|arg| arg == "--link-listen" || arg == "--link-connect")?;
+    let address = args.get(position + 1).expect("Missing address for link cable");
+
+    let link_cable = if args[position] == "--link-listen"
+    {
+        println!("Waiting for link cable connection on {}", address);
+        TcpLinkPartner::listen(address.as_str())
+    }
+    else
+    {
+        TcpLinkPartner::connect(address.as_str())
+    };
+    Some(link_cable.expect("Failed to connect link cable"))
+}
+
+/// Pick the model with `--model <name>`, otherwise go by the cartridge header
+fn model_from_args(args: &[String], cartridge: Option<&Cartridge>) -> Model
+{
+    let default = cartridge.map_or(Model::Dmg, Model::for_cartridge);
+    let Some(position) = args.iter().position(|arg| arg == "--model")
+    else
+    {
+        return default;
+    };
+
+    let name = args.get(position + 1).expect("Missing name for model");
+    Model::from_name(name).unwrap_or_else(|| {
+        println!("Unknown model {}, expected dmg0, dmg, mgb, sgb, sgb2, cgb or agb", name);
+        default
+    })
+}
+
+/// Take a rewind snapshot every `--rewind-interval <frames>` frames. Fewer frames between
+/// snapshots rewind more smoothly but use more memory.
+fn rewind_from_args(args: &[String]) -> Rewind
+{
+    let interval = match args.iter().position(|arg| arg == "--rewind-interval")
+    {
+        Some(position) =>
+        {
+            let frames = args.get(position + 1).expect("Missing frames for rewind interval");
+            frames.parse().expect("Rewind interval must be a number of frames")
+        }
+        None => DEFAULT_REWIND_INTERVAL,
+    };
+    let interval = interval.max(1);
+    Rewind::new(interval, (REWIND_SECONDS * 60 / interval) as usize)
+}
+
+/// `--debug` starts the game paused in the debugger, which reads commands from the terminal
+fn debugger_from_args(args: &[String], movie_active: bool, symbols: Symbols) -> Option<Debugger>
+{
+    if !args.iter().any(|arg| arg == "--debug")
+    {
+        return None;
+    }
+    // Stepping through a movie would leave it out of step with the game
+    if movie_active
+    {
+        println!("The debugger can't be used with movies");
+        return None;
+    }
+    let mut debugger = Debugger::new();
+    debugger.set_symbols(symbols);
+    debugger.pause();
+    println!("Debugger paused, type help for commands");
+    Some(debugger)
+}
+
+/// `--gdb <address>` waits for GDB remote protocol clients on the address, such as
+/// `127.0.0.1:2345`
+fn gdb_from_args(args: &[String], movie_active: bool) -> Option<GdbStub>
+{
+    let address = option_value(args, "--gdb")?;
+    if movie_active
+    {
+        println!("GDB can't be used with movies");
+        return None;
+    }
+    let gdb = GdbStub::listen(address).expect("Failed to listen for GDB");
+    println!("Listening for GDB on {}", address);
+    Some(gdb)
+}
 
-        for y in 0..TILE_HEIGHT
+/// Lines typed into the terminal, read on another thread so the window keeps drawing
+fn read_commands() -> mpsc::Receiver<String>
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines()
         {
-            for x in 0..TILE_WIDTH
+            let Ok(line) = line
+            else
+            {
+                break;
+            };
+            if sender.send(line).is_err()
             {
-                let color = pixel_value_to_rgba(tile[y][x]);
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Colourise monochrome games with `--palette auto`, which picks the palette the CGB would use
+/// for the game, or `--palette <name>` for one of the palettes that can be picked with buttons.
+/// Monochrome games on a CGB are colourised with `auto` by default.
+fn palette_from_args(
+    args: &[String],
+    cartridge: &Cartridge,
+    model: Model,
+) -> Option<CompatibilityPalette>
+{
+    // Colour games bring their own palettes
+    if cartridge.supports_cgb() && model.is_cgb()
+    {
+        return None;
+    }
 
-                //println!("Color {} {} {} {}", color[0], color[1], color[2], color[3]);
-                let screen_x = tile_x * TILE_WIDTH + x;
-                let screen_y = tile_y * TILE_HEIGHT + y;
+    let position = args.iter().position(|arg| arg == "--palette")?;
+    let name = args.get(position + 1).expect("Missing name for palette");
 
-                let i = (screen_y * frame_width + screen_x) * 4;
+    if name == "auto"
+    {
+        return Some(CompatibilityPalette::for_cartridge(cartridge));
+    }
+    let palette = CompatibilityPalette::from_name(name);
+    if palette.is_none()
+    {
+        let names: Vec<&str> = CompatibilityPalette::names().collect();
+        println!("Unknown palette {}, expected auto or one of {}", name, names.join(", "));
+    }
+    palette
+}
+
+/// The first player uses the arrow keys for the D-pad, X and Z for A and B, Enter for Start and
+/// Backspace for Select. A second player on the Super Game Boy uses WASD, E and Q, 2 and 1.
+fn button_for_key(key: &Key) -> Option<(usize, Button)>
+{
+    match key
+    {
+        Key::Named(NamedKey::ArrowRight) => Some((0, Button::Right)),
+        Key::Named(NamedKey::ArrowLeft) => Some((0, Button::Left)),
+        Key::Named(NamedKey::ArrowUp) => Some((0, Button::Up)),
+        Key::Named(NamedKey::ArrowDown) => Some((0, Button::Down)),
+        Key::Named(NamedKey::Enter) => Some((0, Button::Start)),
+        Key::Named(NamedKey::Backspace) => Some((0, Button::Select)),
+        Key::Character(character) => match character.to_lowercase().as_str()
+        {
+            "x" => Some((0, Button::A)),
+            "z" => Some((0, Button::B)),
+            "d" => Some((1, Button::Right)),
+            "a" => Some((1, Button::Left)),
+            "w" => Some((1, Button::Up)),
+            "s" => Some((1, Button::Down)),
+            "e" => Some((1, Button::A)),
+            "q" => Some((1, Button::B)),
+            "2" => Some((1, Button::Start)),
+            "1" => Some((1, Button::Select)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// F1 to F10 pick one of ten save state slots, loading with the key alone and saving with Shift
+fn state_slot_for_key(key: &Key) -> Option<usize>
+{
+    let Key::Named(named) = key
+    else
+    {
+        return None;
+    };
+    let slots = [
+        NamedKey::F1,
+        NamedKey::F2,
+        NamedKey::F3,
+        NamedKey::F4,
+        NamedKey::F5,
+        NamedKey::F6,
+        NamedKey::F7,
+        NamedKey::F8,
+        NamedKey::F9,
+        NamedKey::F10,
+    ];
+    slots.iter().position(|slot| slot == named)
+}
 
-                frame[i..i + 4].copy_from_slice(&color);
+fn save_state(gameboy: &GameBoy, path: &Path)
+{
+    match std::fs::write(path, gameboy.save_state())
+    {
+        Ok(()) => println!("Saved state to {}", path.display()),
+        Err(error) => println!("Failed to save state to {}: {}", path.display(), error),
+    }
+}
+
+/// Files written when the emulator closes, for the options that asked for them
+struct ExitFiles
+{
+    record_path: Option<PathBuf>,
+    profile_path: Option<PathBuf>,
+    cdl_path: Option<PathBuf>,
+    cheats_path: Option<PathBuf>,
+}
+
+/// Report and save everything collected while the game ran
+fn shut_down(
+    gameboy: &mut GameBoy,
+    serial_log: &Option<serial::CaptureLog>,
+    recording: Option<&Movie>,
+    files: &ExitFiles,
+    symbols: &Symbols,
+)
+{
+    print_serial_output(serial_log);
+    save_movie(recording, files.record_path.as_deref());
+    stop_trace(gameboy);
+    stop_profiling(gameboy, files.profile_path.as_deref(), symbols);
+    stop_code_data_log(gameboy, files.cdl_path.as_deref());
+    save_cheats(gameboy, files.cheats_path.as_deref());
+}
+
+fn save_movie(movie: Option<&Movie>, path: Option<&Path>)
+{
+    let (Some(movie), Some(path)) = (movie, path)
+    else
+    {
+        return;
+    };
+    match movie.save(path)
+    {
+        Ok(()) => println!("Saved {} frame movie to {}", movie.frame_count(), path.display()),
+        Err(error) => println!("Failed to save movie to {}: {}", path.display(), error),
+    }
+}
+
+fn stop_trace(gameboy: &mut GameBoy)
+{
+    if let Err(error) = gameboy.stop_trace()
+    {
+        println!("Failed to write trace: {}", error);
+    }
+}
+
+fn stop_profiling(gameboy: &mut GameBoy, path: Option<&Path>, symbols: &Symbols)
+{
+    let (Some(profiler), Some(path)) = (gameboy.stop_profiling(), path)
+    else
+    {
+        return;
+    };
+    println!("{}", profiler.report(symbols));
+    let written = File::create(path).and_then(|file| {
+        let mut output = BufWriter::new(file);
+        profiler.write_folded(&mut output, symbols)?;
+        output.flush()
+    });
+    match written
+    {
+        Ok(()) => println!("Call stacks written to {}", path.display()),
+        Err(error) => println!("Failed to write call stacks to {}: {}", path.display(), error),
+    }
+}
+
+fn start_code_data_log(gameboy: &mut GameBoy, path: &Path)
+{
+    let rom_size = gameboy.cartridge().map_or(0, Cartridge::rom_size);
+    match CodeDataLog::load(path, rom_size)
+    {
+        Ok(log) => gameboy.start_code_data_log(log),
+        Err(error) => println!("Failed to load code/data log {}: {}", path.display(), error),
+    }
+}
+
+fn stop_code_data_log(gameboy: &mut GameBoy, path: Option<&Path>)
+{
+    let (Some(log), Some(path)) = (gameboy.stop_code_data_log(), path)
+    else
+    {
+        return;
+    };
+    let (code, data, used) = log.coverage();
+    let percent = |bytes: usize| bytes as f64 * 100.0 / log.len().max(1) as f64;
+    match log.save(path)
+    {
+        Ok(()) => println!(
+            "Code/data log saved to {}: {:.2}% code, {:.2}% data, {:.2}% of the ROM used",
+            path.display(),
+            percent(code),
+            percent(data),
+            percent(used)
+        ),
+        Err(error) => println!("Failed to save code/data log to {}: {}", path.display(), error),
+    }
+}
+
+fn load_cheats(gameboy: &mut GameBoy, path: &Path)
+{
+    match Cheats::load(path)
+    {
+        Ok(cheats) =>
+        {
+            if !cheats.cheats().is_empty()
+            {
+                println!("Loaded {} cheats from {}", cheats.cheats().len(), path.display());
             }
+            *gameboy.cheats_mut() = cheats;
         }
+        Err(error) => println!("Failed to load cheats from {}: {}", path.display(), error),
+    }
+}
+
+fn save_cheats(gameboy: &mut GameBoy, path: Option<&Path>)
+{
+    let (Some(path), true) = (path, gameboy.cheats().is_modified())
+    else
+    {
+        return;
+    };
+    match gameboy.cheats_mut().save(path)
+    {
+        Ok(()) => println!("Saved cheats to {}", path.display()),
+        Err(error) => println!("Failed to save cheats to {}: {}", path.display(), error),
+    }
+}
+
+/// Returns true if the state was loaded
+fn load_state(gameboy: &mut GameBoy, path: &Path) -> bool
+{
+    match std::fs::read(path).and_then(|state| gameboy.load_state(&state))
+    {
+        Ok(()) =>
+        {
+            println!("Loaded state from {}", path.display());
+            true
+        }
+        Err(error) =>
+        {
+            println!("Failed to load state from {}: {}", path.display(), error);
+            false
+        }
+    }
+}
+
+fn print_serial_output(serial_log: &Option<serial::CaptureLog>)
+{
+    let text = serial_log.as_ref().map(|log| log.text()).unwrap_or_default();
+    if !text.is_empty()
+    {
+        println!("Serial output:\n{}", text);
     }
 }
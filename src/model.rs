@@ -0,0 +1,68 @@
+use crate::cartridge::Cartridge;
+
+/// Game Boy hardware revisions, which differ in the state their boot ROMs leave behind
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Model
+{
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl Model
+{
+    /// The model a game would most likely be played on, judging by its header
+    pub fn for_cartridge(cartridge: &Cartridge) -> Self
+    {
+        if cartridge.supports_cgb()
+        {
+            Model::Cgb
+        }
+        else if cartridge.supports_sgb()
+        {
+            Model::Sgb
+        }
+        else
+        {
+            Model::Dmg
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name.to_lowercase().as_str()
+        {
+            "dmg0" => Some(Model::Dmg0),
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "sgb2" => Some(Model::Sgb2),
+            "cgb" => Some(Model::Cgb),
+            "agb" => Some(Model::Agb),
+            _ => None,
+        }
+    }
+
+    /// Inverse of `model as u8`, used to store the model in save states
+    pub fn from_index(index: u8) -> Option<Self>
+    {
+        [Model::Dmg0, Model::Dmg, Model::Mgb, Model::Sgb, Model::Sgb2, Model::Cgb, Model::Agb]
+            .get(index as usize)
+            .copied()
+    }
+
+    /// True for the Game Boy Color and the Game Boy Advance, which share its hardware
+    pub fn is_cgb(self) -> bool
+    {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(self) -> bool
+    {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+}
@@ -0,0 +1,241 @@
+use std::io;
+use std::path::Path;
+
+use crate::cartridge::{Cartridge, Rtc};
+use crate::crc::{crc32, Crc32};
+use crate::gameboy::GameBoy;
+use crate::joypad::{Button, MAX_PLAYERS};
+use crate::model::Model;
+use crate::savestate::{invalid_state, StateReader, StateWriter};
+
+pub const MOVIE_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"GBMV";
+
+/// A recording of the buttons held on every frame, from power on or from an embedded save
+/// state. The core never looks at the wall clock, so playing the inputs back on the same ROM
+/// reproduces the recording exactly. The MBC3 clock starts from the time in the movie and runs
+/// on emulated cycles.
+pub struct Movie
+{
+    model: Model,
+    rom_checksum: u32,
+    // Clock registers when the recording started, for cartridges with a clock
+    rtc_start: Option<[u8; 5]>,
+    // Played from power on without a boot ROM when there is no state
+    start_state: Option<Vec<u8>>,
+    // Masks of `Button::bit` for every controller
+    frames: Vec<[u8; MAX_PLAYERS]>,
+}
+
+impl Movie
+{
+    /// Start a recording for a Game Boy that was just created without a boot ROM
+    pub fn from_power_on(gameboy: &GameBoy) -> Self
+    {
+        Movie {
+            model: gameboy.model(),
+            rom_checksum: gameboy.rom_checksum(),
+            rtc_start: gameboy.cartridge().and_then(Cartridge::rtc).map(Rtc::registers),
+            start_state: None,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Start a recording from wherever the Game Boy is now
+    pub fn from_state(gameboy: &GameBoy) -> Self
+    {
+        Movie { start_state: Some(gameboy.save_state()), ..Self::from_power_on(gameboy) }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self>
+    {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>
+    {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self>
+    {
+        let mut reader = StateReader::new(data);
+        let mut magic = [0; 4];
+        reader.bytes(&mut magic)?;
+        if &magic != MAGIC
+        {
+            return Err(invalid_state("not a movie"));
+        }
+        let version = reader.u32()?;
+        if version != MOVIE_VERSION
+        {
+            return Err(invalid_state(&format!(
+                "movie version {} isn't supported, expected {}",
+                version, MOVIE_VERSION
+            )));
+        }
+
+        let model = Model::from_index(reader.u8()?).ok_or_else(|| invalid_state("bad model"))?;
+        let rom_checksum = reader.u32()?;
+        let rtc_start = if reader.bool()?
+        {
+            let mut registers = [0; 5];
+            reader.bytes(&mut registers)?;
+            Some(registers)
+        }
+        else
+        {
+            None
+        };
+        let start_state = if reader.bool()? { Some(reader.vec()?) } else { None };
+        // The count is checked against what's left before anything is allocated for it
+        let frame_count = reader.usize()?;
+        if frame_count > reader.remaining() / MAX_PLAYERS
+        {
+            return Err(invalid_state("movie is truncated"));
+        }
+        let mut frames = vec![[0; MAX_PLAYERS]; frame_count];
+        for frame in frames.iter_mut()
+        {
+            reader.bytes(frame)?;
+        }
+        Ok(Movie { model, rom_checksum, rtc_start, start_state, frames })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut writer = StateWriter::new();
+        writer.bytes(MAGIC);
+        writer.u32(MOVIE_VERSION);
+        writer.u8(self.model as u8);
+        writer.u32(self.rom_checksum);
+        writer.bool(self.rtc_start.is_some());
+        if let Some(registers) = self.rtc_start.as_ref()
+        {
+            writer.bytes(registers);
+        }
+        writer.bool(self.start_state.is_some());
+        if let Some(state) = self.start_state.as_ref()
+        {
+            writer.vec(state);
+        }
+        writer.usize(self.frames.len());
+        for frame in self.frames.iter()
+        {
+            writer.bytes(frame);
+        }
+        writer.into_bytes()
+    }
+
+    pub fn frame_count(&self) -> usize
+    {
+        self.frames.len()
+    }
+
+    /// Add the buttons being held for the frame about to be run
+    pub fn record_frame(&mut self, gameboy: &GameBoy)
+    {
+        self.frames.push(std::array::from_fn(|player| gameboy.buttons(player)));
+    }
+
+    /// Set up a Game Boy in the state the recording started from
+    pub fn start(&self, mut cartridge: Cartridge) -> io::Result<GameBoy>
+    {
+        if cartridge.rom_checksum() != self.rom_checksum
+        {
+            return Err(invalid_state("movie was recorded with a different game"));
+        }
+        if let (Some(rtc), Some(registers)) = (cartridge.rtc_mut(), self.rtc_start)
+        {
+            rtc.set_registers(registers);
+        }
+        let mut gameboy = GameBoy::new(self.model, None, Some(cartridge));
+        if let Some(state) = self.start_state.as_ref()
+        {
+            gameboy.load_state(state)?;
+        }
+        Ok(gameboy)
+    }
+
+    /// Hold the recorded buttons for a frame. Returns false once the movie is over.
+    pub fn apply_frame(&self, frame: usize, gameboy: &mut GameBoy) -> bool
+    {
+        let Some(masks) = self.frames.get(frame)
+        else
+        {
+            return false;
+        };
+        for (player, &mask) in masks.iter().enumerate()
+        {
+            let pressed: Vec<Button> =
+                Button::ALL.into_iter().filter(|button| mask & button.bit() != 0).collect();
+            gameboy.set_buttons(player, &pressed);
+        }
+        true
+    }
+
+    /// Play the whole movie without a screen. Returns the CRC-32 of the final frame and of
+    /// every frame in turn, which only match another run if playback was identical.
+    pub fn play_headless(&self, cartridge: Cartridge) -> io::Result<(u32, u32)>
+    {
+        let mut gameboy = self.start(cartridge)?;
+        let mut all_frames = Crc32::new();
+        for frame in 0..self.frames.len()
+        {
+            self.apply_frame(frame, &mut gameboy);
+            all_frames.update(gameboy.run_frame());
+        }
+        Ok((crc32(gameboy.framebuffer()), all_frames.finish()))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::gameboy::{test_cartridge, test_gameboy};
+
+    // Copies the buttons into the background palette forever, so every frame shows what was held:
+    // LD A,$10; LDH [$00],A; LDH A,[$00]; LDH [$47],A; JR -8
+    const PROGRAM: [u8; 10] = [0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xE0, 0x47, 0x18, 0xF6];
+
+    #[test]
+    fn recorded_movie_replays_the_same_frames()
+    {
+        let mut gameboy = test_gameboy(&PROGRAM);
+        let mut movie = Movie::from_power_on(&gameboy);
+        let mut all_frames = Crc32::new();
+        for frame in 0..12
+        {
+            let pressed = match frame % 3
+            {
+                0 => vec![],
+                1 => vec![Button::A],
+                _ => vec![Button::A, Button::B],
+            };
+            gameboy.set_buttons(0, &pressed);
+            movie.record_frame(&gameboy);
+            all_frames.update(gameboy.run_frame());
+        }
+        let recorded = (crc32(gameboy.framebuffer()), all_frames.finish());
+
+        let path = std::env::temp_dir().join(format!("movie-test-{}.gbm", std::process::id()));
+        movie.save(&path).unwrap();
+        let loaded = Movie::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.frames, movie.frames);
+        assert_eq!(loaded.play_headless(test_cartridge(&PROGRAM)).unwrap(), recorded);
+    }
+
+    #[test]
+    fn frame_count_past_the_end_is_rejected()
+    {
+        let movie = Movie::from_power_on(&test_gameboy(&PROGRAM));
+        let mut bytes = movie.to_bytes();
+        let count = bytes.len() - 4;
+        bytes[count..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Movie::from_bytes(&bytes).is_err());
+    }
+}
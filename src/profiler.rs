@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::symbols::Symbols;
+
+// Functions and instructions listed in the report
+const REPORT_LENGTH: usize = 20;
+
+/// A bank and address, which tells apart code at the same address in different banks
+type Location = (usize, u16);
+
+#[derive(Default)]
+struct Counts
+{
+    instructions: u64,
+    cycles: u64,
+}
+
+/// A function in the call tree, reached through the calls to its ancestors
+struct Node
+{
+    function: Option<Location>,
+    children: HashMap<Location, usize>,
+    calls: u64,
+    // Cycles spent in the function itself, not in the functions it calls
+    cycles: u64,
+}
+
+/// Where a game spends its time, for finding what to optimise.
+///
+/// Every instruction adds its cycles to its own address and to the function running it. CALL,
+/// RST and interrupts enter a function, which is left once the stack pointer moves above the
+/// return address pushed on entry. That covers RET and RETI as well as code that drops the
+/// return address to jump elsewhere.
+pub struct Profiler
+{
+    instructions: HashMap<Location, Counts>,
+    // The root, whatever runs outside of any call, is node 0
+    nodes: Vec<Node>,
+    // Nodes of the functions being run, with the stack pointer right after each was called
+    stack: Vec<(usize, u16)>,
+    total_cycles: u64,
+}
+
+impl Default for Profiler
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Profiler
+{
+    pub fn new() -> Self
+    {
+        let root = Node { function: None, children: HashMap::new(), calls: 0, cycles: 0 };
+        Profiler {
+            instructions: HashMap::new(),
+            nodes: vec![root],
+            stack: Vec::new(),
+            total_cycles: 0,
+        }
+    }
+
+    fn current_node(&self) -> usize
+    {
+        self.stack.last().map_or(0, |&(node, _)| node)
+    }
+
+    /// Count an instruction that has just run. `sp` is the stack pointer it left behind and
+    /// `called` is where it jumped to if it was a CALL or RST that was taken.
+    pub fn instruction(
+        &mut self,
+        location: Location,
+        cycles: u32,
+        sp: u16,
+        called: Option<Location>,
+    )
+    {
+        let counts = self.instructions.entry(location).or_default();
+        counts.instructions += 1;
+        counts.cycles += cycles as u64;
+        let node = self.current_node();
+        self.nodes[node].cycles += cycles as u64;
+        self.total_cycles += cycles as u64;
+
+        match called
+        {
+            Some(function) => self.enter(function, sp),
+            None => self.leave(sp),
+        }
+    }
+
+    /// An interrupt handler was entered, with `sp` pointing at its return address
+    pub fn interrupt(&mut self, vector: Location, sp: u16)
+    {
+        self.enter(vector, sp);
+    }
+
+    /// Count the cycles spent dispatching an interrupt against its handler
+    pub fn add_cycles(&mut self, cycles: u32)
+    {
+        let node = self.current_node();
+        self.nodes[node].cycles += cycles as u64;
+        self.total_cycles += cycles as u64;
+    }
+
+    fn enter(&mut self, function: Location, sp: u16)
+    {
+        let parent = self.current_node();
+        let node = match self.nodes[parent].children.get(&function)
+        {
+            Some(&node) => node,
+            None =>
+            {
+                let node = self.nodes.len();
+                self.nodes.push(Node {
+                    function: Some(function),
+                    children: HashMap::new(),
+                    calls: 0,
+                    cycles: 0,
+                });
+                self.nodes[parent].children.insert(function, node);
+                node
+            }
+        };
+        self.nodes[node].calls += 1;
+        self.stack.push((node, sp));
+    }
+
+    /// Leave every function whose return address has been popped off the stack
+    fn leave(&mut self, sp: u16)
+    {
+        while self.stack.last().is_some_and(|&(_, entry_sp)| sp > entry_sp)
+        {
+            self.stack.pop();
+        }
+    }
+
+    /// Functions by the cycles spent in them and what they call, then the instructions that
+    /// took the most cycles, which are usually the inside of the hottest loops
+    pub fn report(&self, symbols: &Symbols) -> String
+    {
+        let mut functions: HashMap<Option<Location>, (u64, u64, u64)> = HashMap::new();
+        for (index, node) in self.nodes.iter().enumerate()
+        {
+            let function = functions.entry(node.function).or_default();
+            function.0 += self.subtree_cycles(index, node.function);
+            function.1 += node.cycles;
+            function.2 += node.calls;
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by_key(|&(location, (total, _, _))| (std::cmp::Reverse(total), location));
+
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.total_cycles.max(1) as f64;
+        let mut lines = vec![
+            format!("Profiled {} cycles", self.total_cycles),
+            "Hottest functions:".to_string(),
+            format!("{:>7} {:>7} {:>9}  function", "total", "self", "calls"),
+        ];
+        for (location, (total, own, calls)) in functions.iter().take(REPORT_LENGTH)
+        {
+            lines.push(format!(
+                "{:>6.2}% {:>6.2}% {:>9}  {}",
+                percent(*total),
+                percent(*own),
+                calls,
+                function_name(*location, symbols)
+            ));
+        }
+
+        let mut instructions: Vec<_> = self.instructions.iter().collect();
+        instructions
+            .sort_by_key(|&(&location, counts)| (std::cmp::Reverse(counts.cycles), location));
+        lines.push("Hottest instructions:".to_string());
+        lines.push(format!("{:>7} {:>12}  address", "cycles", "executed"));
+        for (&location, counts) in instructions.into_iter().take(REPORT_LENGTH)
+        {
+            lines.push(format!(
+                "{:>6.2}% {:>12}  {}",
+                percent(counts.cycles),
+                counts.instructions,
+                location_name(location, symbols)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Cycles spent in a node and everything it called, leaving out calls back into `function`
+    /// further down so recursion isn't counted twice
+    fn subtree_cycles(&self, index: usize, function: Option<Location>) -> u64
+    {
+        let node = &self.nodes[index];
+        node.cycles
+            + node
+                .children
+                .values()
+                .filter(|&&child| self.nodes[child].function != function)
+                .map(|&child| self.subtree_cycles(child, function))
+                .sum::<u64>()
+    }
+
+    /// Write the call tree as folded stacks, one `root;caller;callee cycles` line for every
+    /// path through it, which flamegraph.pl, inferno and speedscope all read
+    pub fn write_folded(&self, output: &mut dyn Write, symbols: &Symbols) -> io::Result<()>
+    {
+        let mut pending = vec![(0, function_name(None, symbols))];
+        while let Some((index, path)) = pending.pop()
+        {
+            let node = &self.nodes[index];
+            if node.cycles > 0
+            {
+                writeln!(output, "{} {}", path, node.cycles)?;
+            }
+            for &child in node.children.values()
+            {
+                let name = function_name(self.nodes[child].function, symbols);
+                pending.push((child, format!("{};{}", path, name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn function_name(location: Option<Location>, symbols: &Symbols) -> String
+{
+    match location
+    {
+        Some(location) => location_name(location, symbols),
+        None => "(root)".to_string(),
+    }
+}
+
+fn location_name((bank, address): Location, symbols: &Symbols) -> String
+{
+    match symbols.describe_in(bank, address)
+    {
+        Some(name) => name,
+        None => format!("{:02X}:{:04X}", bank, address),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn call_stacks_are_folded()
+    {
+        let symbols =
+            Symbols::parse_sym("00:0038 Reset38\n00:0040 VBlank\n00:0200 Update\n00:0300 Helper\n");
+        let mut profiler = Profiler::new();
+        profiler.instruction((0, 0x0100), 4, 0xFFFE, None);
+        // CALL Update, which runs RST $38, which is interrupted, then both return
+        profiler.instruction((0, 0x0101), 24, 0xFFFC, Some((0, 0x0200)));
+        profiler.instruction((0, 0x0200), 4, 0xFFFC, None);
+        profiler.instruction((0, 0x0201), 16, 0xFFFA, Some((0, 0x0038)));
+        profiler.instruction((0, 0x0038), 4, 0xFFFA, None);
+        profiler.interrupt((0, 0x0040), 0xFFF8);
+        profiler.add_cycles(20);
+        profiler.instruction((0, 0x0040), 4, 0xFFF8, None);
+        profiler.instruction((0, 0x0041), 16, 0xFFFA, None);
+        profiler.instruction((0, 0x0039), 16, 0xFFFC, None);
+        profiler.instruction((0, 0x0202), 16, 0xFFFE, None);
+        // A RET with nothing called stays at the root
+        profiler.instruction((0, 0x0104), 16, 0xFFFE, None);
+        // Dropping two return addresses at once leaves both functions
+        profiler.instruction((0, 0x0105), 24, 0xFFFC, Some((0, 0x0200)));
+        profiler.instruction((0, 0x0203), 24, 0xFFFA, Some((0, 0x0300)));
+        profiler.instruction((0, 0x0300), 12, 0xFFFE, None);
+        profiler.instruction((0, 0x0108), 4, 0xFFFE, None);
+
+        let mut output = Vec::new();
+        profiler.write_folded(&mut output, &symbols).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let mut lines: Vec<&str> = text.lines().collect();
+        lines.sort();
+        assert_eq!(lines, [
+            "(root) 72",
+            "(root);Update 60",
+            "(root);Update;Helper 12",
+            "(root);Update;Reset38 20",
+            "(root);Update;Reset38;VBlank 40",
+        ]);
+    }
+}
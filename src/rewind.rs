@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+
+use crate::gameboy::GameBoy;
+
+/// Snapshots of the last few minutes of play which can be stepped back through.
+///
+/// A snapshot is taken every `interval` frames using the save state format. Only the newest
+/// snapshot is kept whole. Every older one is stored as the difference to the snapshot after
+/// it, which is mostly zeroes and run length encoded.
+pub struct Rewind
+{
+    interval: u32,
+    capacity: usize,
+    frames_since_snapshot: u32,
+    latest: Option<Vec<u8>>,
+    // Oldest first, each one turns the snapshot after it back into the one before
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind
+{
+    /// Keep up to `capacity` snapshots, one every `interval` frames
+    pub fn new(interval: u32, capacity: usize) -> Self
+    {
+        Rewind {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames_since_snapshot: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Call once for every frame that is run
+    pub fn record(&mut self, gameboy: &GameBoy)
+    {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.interval
+        {
+            return;
+        }
+        self.frames_since_snapshot = 0;
+
+        let state = gameboy.save_state();
+        if let Some(latest) = self.latest.take()
+        {
+            self.deltas.push_back(encode_delta(&latest, &state));
+            if self.deltas.len() >= self.capacity
+            {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    /// Go back to the previous snapshot. Returns false once there is nothing left to rewind.
+    pub fn step_back(&mut self, gameboy: &mut GameBoy) -> bool
+    {
+        let Some(state) = self.latest.take()
+        else
+        {
+            return false;
+        };
+        gameboy.load_state(&state).expect("Failed to load rewind snapshot");
+
+        self.latest = self.deltas.pop_back().map(|delta| decode_delta(&state, &delta));
+        self.frames_since_snapshot = 0;
+        true
+    }
+
+    /// Forget everything, for when the game jumps somewhere else such as loading a state
+    pub fn clear(&mut self)
+    {
+        self.latest = None;
+        self.deltas.clear();
+        self.frames_since_snapshot = 0;
+    }
+
+    /// Bytes held by the snapshots
+    pub fn memory_used(&self) -> usize
+    {
+        self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+/// Encode `old` relative to `new` as its length followed by runs of unchanged bytes and of
+/// bytes XORed with `new`, each run prefixed by its length
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8>
+{
+    let xor = |index: usize| old[index] ^ new.get(index).copied().unwrap_or(0);
+
+    let mut delta = Vec::new();
+    write_length(&mut delta, old.len());
+    let mut index = 0;
+    while index < old.len()
+    {
+        let unchanged = (index..old.len()).take_while(|&i| xor(i) == 0).count();
+        index += unchanged;
+        // Short unchanged runs are cheaper to store as part of the changed run
+        let changed_end = (index..old.len())
+            .find(|&i| (i..old.len().min(i + 4)).all(|j| xor(j) == 0))
+            .unwrap_or(old.len());
+
+        write_length(&mut delta, unchanged);
+        write_length(&mut delta, changed_end - index);
+        delta.extend((index..changed_end).map(xor));
+        index = changed_end;
+    }
+    delta
+}
+
+fn decode_delta(new: &[u8], delta: &[u8]) -> Vec<u8>
+{
+    let mut position = 0;
+    let length = read_length(delta, &mut position);
+    let mut old: Vec<u8> = (0..length).map(|index| new.get(index).copied().unwrap_or(0)).collect();
+
+    let mut index = 0;
+    while position < delta.len()
+    {
+        index += read_length(delta, &mut position);
+        let changed = read_length(delta, &mut position);
+        for (byte, &difference) in
+            old[index..index + changed].iter_mut().zip(&delta[position..position + changed])
+        {
+            *byte ^= difference;
+        }
+        index += changed;
+        position += changed;
+    }
+    old
+}
+
+// Lengths use 7 bits per byte with the top bit set while more bytes follow
+fn write_length(output: &mut Vec<u8>, mut length: usize)
+{
+    while length >= 0x80
+    {
+        output.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    output.push(length as u8);
+}
+
+fn read_length(input: &[u8], position: &mut usize) -> usize
+{
+    let mut length = 0;
+    let mut shift = 0;
+    loop
+    {
+        let byte = input[*position];
+        *position += 1;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0
+        {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn round_trip(old: &[u8], new: &[u8])
+    {
+        let delta = encode_delta(old, new);
+        assert_eq!(decode_delta(new, &delta), old);
+    }
+
+    #[test]
+    fn delta_round_trips()
+    {
+        round_trip(&[], &[]);
+        round_trip(&[1, 2, 3], &[1, 2, 3]);
+        round_trip(&[1, 2, 3, 4, 5, 6], &[1, 0, 3, 4, 0, 6]);
+
+        // Long runs need lengths of more than one byte
+        let old: Vec<u8> = (0..1000).map(|index| (index * 7) as u8).collect();
+        let mut new = old.clone();
+        new[3] ^= 0xFF;
+        new[500..700].fill(0x55);
+        new[999] = 0;
+        round_trip(&old, &new);
+        assert!(encode_delta(&old, &old).len() < 8);
+    }
+
+    #[test]
+    fn delta_round_trips_between_lengths()
+    {
+        let short: Vec<u8> = (0..300).map(|index| index as u8).collect();
+        let long: Vec<u8> = (0..500).map(|index| (index * 3) as u8).collect();
+        round_trip(&short, &long);
+        round_trip(&long, &short);
+        round_trip(&long, &[]);
+        round_trip(&[], &long);
+    }
+}
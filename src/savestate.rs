@@ -0,0 +1,249 @@
+use std::io;
+
+use crate::model::Model;
+
+// Bumped whenever the layout of any component's state changes
+pub const STATE_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"GBST";
+
+/// Components that can snapshot their complete state and restore it exactly. States are only
+/// restored onto a machine of the same model running the same game.
+pub trait SaveState
+{
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>;
+}
+
+/// Builds a state as little endian binary data
+#[derive(Default)]
+pub struct StateWriter
+{
+    data: Vec<u8>,
+}
+
+impl StateWriter
+{
+    pub fn new() -> Self
+    {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8>
+    {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8)
+    {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool)
+    {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize)
+    {
+        self.u32(value as u32);
+    }
+
+    /// A block whose size is known when reading it back
+    pub fn bytes(&mut self, bytes: &[u8])
+    {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn u16s(&mut self, values: &[u16])
+    {
+        for &value in values
+        {
+            self.u16(value);
+        }
+    }
+
+    /// A block preceded by its length
+    pub fn vec(&mut self, bytes: &[u8])
+    {
+        self.usize(bytes.len());
+        self.bytes(bytes);
+    }
+}
+
+/// Reads back a state built by `StateWriter`
+pub struct StateReader<'a>
+{
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a>
+{
+    pub fn new(data: &'a [u8]) -> Self
+    {
+        StateReader { data }
+    }
+
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]>
+    {
+        if length > self.data.len()
+        {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "state is truncated"));
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    /// Bytes left to read
+    pub fn remaining(&self) -> usize
+    {
+        self.data.len()
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8>
+    {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool>
+    {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16>
+    {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32>
+    {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn usize(&mut self) -> io::Result<usize>
+    {
+        Ok(self.u32()? as usize)
+    }
+
+    /// Fill `bytes` completely
+    pub fn bytes(&mut self, bytes: &mut [u8]) -> io::Result<()>
+    {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    pub fn u16s(&mut self, values: &mut [u16]) -> io::Result<()>
+    {
+        for value in values.iter_mut()
+        {
+            *value = self.u16()?;
+        }
+        Ok(())
+    }
+
+    pub fn vec(&mut self) -> io::Result<Vec<u8>>
+    {
+        let length = self.usize()?;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /// Fill `bytes` from a block preceded by its length, which has to match
+    pub fn vec_into(&mut self, bytes: &mut [u8]) -> io::Result<()>
+    {
+        if self.usize()? != bytes.len()
+        {
+            return Err(invalid_state("block has the wrong size"));
+        }
+        self.bytes(bytes)
+    }
+}
+
+pub fn invalid_state(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Identifies what a state was saved from, with a small picture of the screen at the time
+pub struct StateHeader
+{
+    pub model: Model,
+    pub rom_checksum: u32,
+    pub thumbnail_width: usize,
+    pub thumbnail_height: usize,
+    // RGBA at half the size of the screen
+    pub thumbnail: Vec<u8>,
+}
+
+impl StateHeader
+{
+    /// Scale a screen down to a thumbnail by keeping every other pixel
+    pub fn new(model: Model, rom_checksum: u32, screen: &[u8], width: usize) -> Self
+    {
+        let height = screen.len() / 4 / width;
+        let mut thumbnail = Vec::with_capacity(screen.len() / 4);
+        for y in (0..height).step_by(2)
+        {
+            for x in (0..width).step_by(2)
+            {
+                let index = (y * width + x) * 4;
+                thumbnail.extend_from_slice(&screen[index..index + 4]);
+            }
+        }
+        StateHeader {
+            model,
+            rom_checksum,
+            thumbnail_width: width / 2,
+            thumbnail_height: height / 2,
+            thumbnail,
+        }
+    }
+
+    pub fn write(&self, writer: &mut StateWriter)
+    {
+        writer.bytes(MAGIC);
+        writer.u32(STATE_VERSION);
+        writer.u8(self.model as u8);
+        writer.u32(self.rom_checksum);
+        writer.u16(self.thumbnail_width as u16);
+        writer.u16(self.thumbnail_height as u16);
+        writer.vec(&self.thumbnail);
+    }
+
+    pub fn read(reader: &mut StateReader) -> io::Result<Self>
+    {
+        let mut magic = [0; 4];
+        reader.bytes(&mut magic)?;
+        if &magic != MAGIC
+        {
+            return Err(invalid_state("not a save state"));
+        }
+        let version = reader.u32()?;
+        if version != STATE_VERSION
+        {
+            return Err(invalid_state(&format!(
+                "save state version {} isn't supported, expected {}",
+                version, STATE_VERSION
+            )));
+        }
+        let model = Model::from_index(reader.u8()?).ok_or_else(|| invalid_state("bad model"))?;
+        Ok(StateHeader {
+            model,
+            rom_checksum: reader.u32()?,
+            thumbnail_width: reader.u16()? as usize,
+            thumbnail_height: reader.u16()? as usize,
+            thumbnail: reader.vec()?,
+        })
+    }
+}
@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub mod link_cable;
+pub mod printer;
+
+pub const SB_ADDRESS: usize = 0xFF01;
+pub const SC_ADDRESS: usize = 0xFF02;
+
+// The internal clock runs at 8192 Hz, i.e. one bit every 512 CPU cycles
+const INTERNAL_CLOCK_CYCLES_PER_BIT: u16 = 512;
+
+// SC bits
+const TRANSFER_START_BIT: u8 = 0x80;
+const INTERNAL_CLOCK_BIT: u8 = 0x01;
+// Bits 1-6 of SC are unused and always read back as 1
+const SC_UNUSED_BITS: u8 = 0x7E;
+
+/// A device on the other end of the link cable.
+pub trait LinkPartner
+{
+    /// Called when the Game Boy starts a transfer with its internal clock. The partner is given
+    /// the byte being sent and returns the byte that will be shifted back in.
+    fn exchange(&mut self, outgoing: u8) -> u8;
+
+    /// Called on every serial step. `waiting` holds the byte in SB while the Game Boy waits on an
+    /// external clock. A partner that drives the clock returns the incoming byte once it has
+    /// clocked a full transfer, otherwise `None`.
+    fn step(&mut self, _cycles: u8, _waiting: Option<u8>) -> Option<u8>
+    {
+        None
+    }
+}
+
+/// Shared buffer holding every byte a `CapturePartner` has received.
+#[derive(Clone, Default)]
+pub struct CaptureLog(Rc<RefCell<Vec<u8>>>);
+
+impl CaptureLog
+{
+    pub fn text(&self) -> String
+    {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+/// Link partner that records transmitted bytes and otherwise behaves like an empty port.
+///
+/// Test ROMs such as blargg's write their results to the serial port, so the captured text is
+/// how their pass or fail report is read back.
+#[derive(Default)]
+pub struct CapturePartner
+{
+    log: CaptureLog,
+}
+
+impl CapturePartner
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn log(&self) -> CaptureLog
+    {
+        self.log.clone()
+    }
+}
+
+impl LinkPartner for CapturePartner
+{
+    fn exchange(&mut self, outgoing: u8) -> u8
+    {
+        self.log.0.borrow_mut().push(outgoing);
+        // Nothing drives the data line so it is read as high
+        0xFF
+    }
+}
+
+pub struct Serial
+{
+    data: u8,
+    transfer_in_progress: bool,
+    internal_clock: bool,
+    incoming: u8,
+    bits_remaining: u8,
+    cycles: u16,
+    partner: Option<Box<dyn LinkPartner>>,
+}
+
+impl Default for Serial
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Serial
+{
+    pub fn new() -> Self
+    {
+        Self {
+            data: 0,
+            transfer_in_progress: false,
+            internal_clock: false,
+            incoming: 0xFF,
+            bits_remaining: 0,
+            cycles: 0,
+            partner: None,
+        }
+    }
+
+    pub fn connect(&mut self, partner: Box<dyn LinkPartner>)
+    {
+        self.partner = Some(partner);
+    }
+
+    /// Unplug the device in the link port, if any
+    pub fn disconnect(&mut self) -> Option<Box<dyn LinkPartner>>
+    {
+        self.partner.take()
+    }
+
+    pub fn read_data(&self) -> u8
+    {
+        self.data
+    }
+
+    pub fn write_data(&mut self, value: u8)
+    {
+        self.data = value;
+    }
+
+    pub fn read_control(&self) -> u8
+    {
+        let start = if self.transfer_in_progress { TRANSFER_START_BIT } else { 0 };
+        let clock = if self.internal_clock { INTERNAL_CLOCK_BIT } else { 0 };
+        start | SC_UNUSED_BITS | clock
+    }
+
+    pub fn write_control(&mut self, value: u8)
+    {
+        self.internal_clock = value & INTERNAL_CLOCK_BIT != 0;
+        self.transfer_in_progress = value & TRANSFER_START_BIT != 0;
+        if !self.transfer_in_progress
+        {
+            return;
+        }
+
+        self.bits_remaining = 8;
+        self.cycles = 0;
+
+        // With the internal clock the whole byte is exchanged up front and then shifted in one
+        // bit at a time. With no cable attached the data line floats high.
+        if self.internal_clock
+        {
+            self.incoming = match self.partner.as_mut()
+            {
+                Some(partner) => partner.exchange(self.data),
+                None => 0xFF,
+            };
+        }
+    }
+
+    /// Set the clock source without starting or stopping a transfer
+    pub fn poke_control(&mut self, value: u8)
+    {
+        self.internal_clock = value & INTERNAL_CLOCK_BIT != 0;
+    }
+
+    /// Advance the serial clock. Returns true when a transfer completes and the serial
+    /// interrupt should be requested.
+    pub fn step(&mut self, cycles: u8) -> bool
+    {
+        let waiting =
+            if self.transfer_in_progress && !self.internal_clock { Some(self.data) } else { None };
+        let incoming = match self.partner.as_mut()
+        {
+            Some(partner) => partner.step(cycles, waiting),
+            None => None,
+        };
+
+        if !self.transfer_in_progress
+        {
+            return false;
+        }
+
+        if !self.internal_clock
+        {
+            // An external clock is only ever supplied by a partner. Without one the transfer
+            // waits forever, just as it does on hardware with no cable plugged in.
+            return match incoming
+            {
+                Some(value) =>
+                {
+                    self.data = value;
+                    self.finish_transfer();
+                    true
+                }
+                None => false,
+            };
+        }
+
+        self.cycles += cycles as u16;
+        while self.cycles >= INTERNAL_CLOCK_CYCLES_PER_BIT && self.bits_remaining > 0
+        {
+            self.cycles -= INTERNAL_CLOCK_CYCLES_PER_BIT;
+            self.bits_remaining -= 1;
+
+            let bit = (self.incoming >> self.bits_remaining) & 0x1;
+            self.data = (self.data << 1) | bit;
+        }
+
+        if self.bits_remaining == 0
+        {
+            self.finish_transfer();
+            return true;
+        }
+        false
+    }
+
+    fn finish_transfer(&mut self)
+    {
+        self.transfer_in_progress = false;
+        self.bits_remaining = 0;
+        self.cycles = 0;
+    }
+}
+
+// Whatever is plugged into the link port stays connected when a state is loaded
+impl SaveState for Serial
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.u8(self.data);
+        writer.bool(self.transfer_in_progress);
+        writer.bool(self.internal_clock);
+        writer.u8(self.incoming);
+        writer.u8(self.bits_remaining);
+        writer.u16(self.cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.data = reader.u8()?;
+        self.transfer_in_progress = reader.bool()?;
+        self.internal_clock = reader.bool()?;
+        self.incoming = reader.u8()?;
+        self.bits_remaining = reader.u8()?;
+        self.cycles = reader.u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::serial::LinkPartner;
+
+const PROTOCOL_VERSION: u8 = 1;
+
+const MESSAGE_HELLO: u8 = 0x01;
+const MESSAGE_SYNC: u8 = 0x02;
+const MESSAGE_TRANSFER: u8 = 0x03;
+const MESSAGE_REPLY: u8 = 0x04;
+
+// Each side reports its cycle count every SYNC_INTERVAL cycles and stalls when it gets more than
+// SYNC_WINDOW cycles ahead of the last count it heard. The window must be at least the interval
+// or both sides could end up waiting on each other.
+const SYNC_INTERVAL: u64 = 4096;
+const SYNC_WINDOW: u64 = 16384;
+
+enum Message
+{
+    Hello(u8),
+    Sync(u64),
+    Transfer(u8),
+    Reply(u8),
+}
+
+impl Message
+{
+    fn encode(&self) -> Vec<u8>
+    {
+        match self
+        {
+            Message::Hello(version) => vec![MESSAGE_HELLO, *version],
+            Message::Sync(cycles) =>
+            {
+                let mut bytes = vec![MESSAGE_SYNC];
+                bytes.extend_from_slice(&cycles.to_le_bytes());
+                bytes
+            }
+            Message::Transfer(value) => vec![MESSAGE_TRANSFER, *value],
+            Message::Reply(value) => vec![MESSAGE_REPLY, *value],
+        }
+    }
+
+    /// Decode the first message in the buffer, returning it with the number of bytes it used.
+    /// Returns `Ok(None)` if the buffer does not yet hold a complete message.
+    fn decode(buffer: &[u8]) -> io::Result<Option<(Message, usize)>>
+    {
+        let Some(&tag) = buffer.first()
+        else
+        {
+            return Ok(None);
+        };
+
+        let length = match tag
+        {
+            MESSAGE_SYNC => 9,
+            MESSAGE_HELLO | MESSAGE_TRANSFER | MESSAGE_REPLY => 2,
+            _ =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown link cable message 0x{:02x}", tag),
+                ))
+            }
+        };
+        if buffer.len() < length
+        {
+            return Ok(None);
+        }
+
+        let message = match tag
+        {
+            MESSAGE_HELLO => Message::Hello(buffer[1]),
+            MESSAGE_SYNC =>
+            {
+                let mut cycles = [0; 8];
+                cycles.copy_from_slice(&buffer[1..9]);
+                Message::Sync(u64::from_le_bytes(cycles))
+            }
+            MESSAGE_TRANSFER => Message::Transfer(buffer[1]),
+            _ => Message::Reply(buffer[1]),
+        };
+        Ok(Some((message, length)))
+    }
+}
+
+/// Link cable to another emulator instance over TCP.
+///
+/// Whichever Game Boy starts a transfer with its internal clock acts as master for that byte:
+/// it sends a transfer message and waits for the other side to reply with the contents of its
+/// SB register. If both sides start an internally clocked transfer at the same moment, the side
+/// that accepted the connection keeps the clock and the other side acts as slave.
+///
+/// To stop one emulator racing ahead, both sides regularly exchange their cycle counts and
+/// neither runs more than a bounded number of cycles ahead of the other.
+pub struct TcpLinkPartner
+{
+    stream: Option<TcpStream>,
+    has_clock_priority: bool,
+    buffer: Vec<u8>,
+    cycles: u64,
+    last_sync: u64,
+    remote_cycles: u64,
+    // Transfers and replies from the other side in the order they arrived, as telling a
+    // transfer that collided with ours from a new one depends on whether it came before our reply
+    incoming: VecDeque<Message>,
+}
+
+impl TcpLinkPartner
+{
+    /// Wait for another emulator to connect on the given address
+    pub fn listen<A: ToSocketAddrs>(address: A) -> io::Result<Self>
+    {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        Self::from_stream(stream, true)
+    }
+
+    /// Connect to another emulator that is listening on the given address
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Self>
+    {
+        let stream = TcpStream::connect(address)?;
+        Self::from_stream(stream, false)
+    }
+
+    fn from_stream(stream: TcpStream, has_clock_priority: bool) -> io::Result<Self>
+    {
+        stream.set_nodelay(true)?;
+
+        let mut link = TcpLinkPartner {
+            stream: Some(stream),
+            has_clock_priority,
+            buffer: Vec::new(),
+            cycles: 0,
+            last_sync: 0,
+            remote_cycles: 0,
+            incoming: VecDeque::new(),
+        };
+
+        // Both sides introduce themselves before anything else is sent
+        link.send(Message::Hello(PROTOCOL_VERSION));
+        let mut remote_version = None;
+        while remote_version.is_none()
+        {
+            let stream = link.stream.as_mut().ok_or(io::ErrorKind::ConnectionAborted)?;
+            let mut bytes = [0; 64];
+            let read = stream.read(&mut bytes)?;
+            if read == 0
+            {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            link.buffer.extend_from_slice(&bytes[..read]);
+
+            if let Some((Message::Hello(version), length)) = Message::decode(&link.buffer)?
+            {
+                link.buffer.drain(..length);
+                remote_version = Some(version);
+            }
+        }
+        if remote_version != Some(PROTOCOL_VERSION)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("link cable protocol version mismatch: {:?}", remote_version),
+            ));
+        }
+
+        if let Some(stream) = link.stream.as_ref()
+        {
+            stream.set_nonblocking(true)?;
+        }
+        // Anything that arrived together with the greeting still needs handling
+        link.process_buffer();
+        Ok(link)
+    }
+
+    fn disconnect(&mut self, error: io::Error)
+    {
+        println!("Link cable disconnected: {}", error);
+        self.stream = None;
+        self.incoming.clear();
+    }
+
+    fn send(&mut self, message: Message)
+    {
+        let Some(stream) = self.stream.as_mut()
+        else
+        {
+            return;
+        };
+
+        if let Err(error) = stream.write_all(&message.encode())
+        {
+            self.disconnect(error);
+        }
+    }
+
+    /// Read whatever has arrived from the other side. When `block` is set this waits until at
+    /// least some data is available.
+    fn receive(&mut self, block: bool)
+    {
+        let Some(stream) = self.stream.as_mut()
+        else
+        {
+            return;
+        };
+
+        let mut bytes = [0; 256];
+        let result = if block
+        {
+            stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.read(&mut bytes))
+                .and_then(|read| stream.set_nonblocking(true).map(|_| read))
+        }
+        else
+        {
+            stream.read(&mut bytes)
+        };
+
+        match result
+        {
+            Ok(0) => self.disconnect(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) =>
+            {
+                self.buffer.extend_from_slice(&bytes[..read]);
+                self.process_buffer();
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock =>
+            {}
+            Err(error) => self.disconnect(error),
+        }
+    }
+
+    fn process_buffer(&mut self)
+    {
+        loop
+        {
+            match Message::decode(&self.buffer)
+            {
+                Ok(Some((message, length))) =>
+                {
+                    self.buffer.drain(..length);
+                    match message
+                    {
+                        Message::Hello(_) =>
+                        {}
+                        Message::Sync(cycles) => self.remote_cycles = cycles,
+                        message => self.incoming.push_back(message),
+                    }
+                }
+                Ok(None) => return,
+                Err(error) =>
+                {
+                    self.disconnect(error);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Wait for the other side to answer the transfer we just sent
+    fn wait_for_reply(&mut self, outgoing: u8) -> u8
+    {
+        loop
+        {
+            while let Some(message) = self.incoming.pop_front()
+            {
+                match message
+                {
+                    Message::Reply(incoming) => return incoming,
+                    // The other side started a transfer at the same time as us, as it came
+                    // before our reply. Only one Game Boy can drive the clock, so the side
+                    // without priority gives way and acts as the slave. The side with priority
+                    // drops the other transfer and keeps waiting for its reply. Transfers after
+                    // the reply are new ones and stay queued for `step`.
+                    Message::Transfer(incoming) if !self.has_clock_priority =>
+                    {
+                        self.send(Message::Reply(outgoing));
+                        return incoming;
+                    }
+                    _ =>
+                    {}
+                }
+            }
+
+            if self.stream.is_none()
+            {
+                // With the cable unplugged the data line floats high
+                return 0xFF;
+            }
+            self.receive(true);
+        }
+    }
+}
+
+impl LinkPartner for TcpLinkPartner
+{
+    fn exchange(&mut self, outgoing: u8) -> u8
+    {
+        self.send(Message::Transfer(outgoing));
+        self.wait_for_reply(outgoing)
+    }
+
+    fn step(&mut self, cycles: u8, waiting: Option<u8>) -> Option<u8>
+    {
+        self.stream.as_ref()?;
+
+        self.cycles += cycles as u64;
+        if self.cycles - self.last_sync >= SYNC_INTERVAL
+        {
+            self.send(Message::Sync(self.cycles));
+            self.last_sync = self.cycles;
+        }
+
+        self.receive(false);
+
+        // Stay in lockstep by waiting for the other side to catch up. A transfer from the other
+        // side has to be answered straight away or it would stall waiting on us.
+        while self.stream.is_some()
+            && self.cycles > self.remote_cycles + SYNC_WINDOW
+            && self.incoming.is_empty()
+        {
+            self.receive(true);
+        }
+
+        // Replies only mean something while waiting in `exchange`
+        let incoming = loop
+        {
+            match self.incoming.pop_front()?
+            {
+                Message::Transfer(value) => break value,
+                _ => continue,
+            }
+        };
+        match waiting
+        {
+            Some(outgoing) =>
+            {
+                self.send(Message::Reply(outgoing));
+                Some(incoming)
+            }
+            None =>
+            {
+                // Our transfer is not armed so nothing is shifted out in return
+                self.send(Message::Reply(0xFF));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A listening and a connecting link over localhost
+    fn connected_pair() -> (TcpLinkPartner, TcpLinkPartner)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connecting = thread::spawn(move || TcpLinkPartner::connect(address).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let listening = TcpLinkPartner::from_stream(stream, true).unwrap();
+        (listening, connecting.join().unwrap())
+    }
+
+    /// Read until `count` transfers and replies are queued, so they're handled together
+    fn wait_for_messages(link: &mut TcpLinkPartner, count: usize)
+    {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while link.incoming.len() < count
+        {
+            assert!(Instant::now() < deadline, "timed out waiting for the other side");
+            link.receive(false);
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn simultaneous_transfers_stay_in_step()
+    {
+        let (mut master, slave) = connected_pair();
+
+        // Both sides start a transfer at once. The master waits until the slave's transfer and
+        // its reply to ours have both arrived, so they're read together.
+        let slave = thread::spawn(move || {
+            let mut slave = slave;
+            let incoming = slave.exchange(0x11);
+            (slave, incoming)
+        });
+        master.send(Message::Transfer(0xAA));
+        wait_for_messages(&mut master, 2);
+        assert_eq!(master.wait_for_reply(0xAA), 0x11);
+        let (slave, incoming) = slave.join().unwrap();
+        assert_eq!(incoming, 0xAA);
+
+        // The dropped transfer mustn't be answered later
+        assert_eq!(master.step(4, None), None);
+
+        // The next transfer from the slave gets the master's byte back, not a stale reply
+        let slave = thread::spawn(move || {
+            let mut slave = slave;
+            slave.exchange(0x22)
+        });
+        let incoming = loop
+        {
+            if let Some(incoming) = master.step(4, Some(0x33))
+            {
+                break incoming;
+            }
+        };
+        assert_eq!(incoming, 0x22);
+        assert_eq!(slave.join().unwrap(), 0x33);
+    }
+
+    #[test]
+    fn transfer_after_reply_is_kept()
+    {
+        let (mut master, mut slave) = connected_pair();
+
+        // The slave answers the master's transfer and then starts one of its own, which arrive
+        // together and must not be mistaken for a collision
+        master.send(Message::Transfer(0x44));
+        while slave.step(4, Some(0x55)).is_none()
+        {}
+        slave.send(Message::Transfer(0x66));
+        wait_for_messages(&mut master, 2);
+        assert_eq!(master.wait_for_reply(0x44), 0x55);
+
+        let slave = thread::spawn(move || {
+            let mut slave = slave;
+            slave.wait_for_reply(0x66)
+        });
+        let incoming = loop
+        {
+            if let Some(incoming) = master.step(4, Some(0x77))
+            {
+                break incoming;
+            }
+        };
+        assert_eq!(incoming, 0x66);
+        assert_eq!(slave.join().unwrap(), 0x77);
+    }
+}
@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::serial::LinkPartner;
+
+const MAGIC_1: u8 = 0x88;
+const MAGIC_2: u8 = 0x33;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_BREAK: u8 = 0x08;
+const COMMAND_STATUS: u8 = 0x0F;
+
+// The printer identifies itself by answering 0x81 during the first byte after the checksum
+const DEVICE_ID: u8 = 0x81;
+
+// Status bits
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+pub const PRINTER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINTER_WIDTH / 8;
+const TILE_BYTES: usize = 16;
+// The printer has room for 9 data packets of 2 tile rows each
+const MAX_IMAGE_ROWS: usize = 9 * 16;
+// Every unit of margin feeds the paper by one 16 pixel band
+const MARGIN_UNIT_ROWS: usize = 16;
+// Number of status requests answered as busy after a print command
+const PRINT_BUSY_POLLS: u8 = 4;
+
+// Grey levels used for the four printer shades, from white to black
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Copy, Clone)]
+enum PacketState
+{
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+/// Game Boy Printer attached to the link port.
+///
+/// Image data sent by the game is decoded into 160 pixel wide rows and added to a strip of
+/// paper on every print command. The strip is saved as a PNG next to the ROM once a print
+/// command feeds the paper out with a bottom margin.
+pub struct GameBoyPrinter
+{
+    output_stem: PathBuf,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy_polls: u8,
+    // Two bit colour indices for each decoded pixel, waiting for a print command
+    image: Vec<u8>,
+    // Grey levels for everything printed on the current strip of paper
+    paper: Vec<u8>,
+}
+
+impl GameBoyPrinter
+{
+    /// Create a printer that saves its printouts next to the ROM at `rom_path`
+    pub fn new(rom_path: &Path) -> Self
+    {
+        GameBoyPrinter {
+            output_stem: rom_path.with_extension(""),
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            busy_polls: 0,
+            image: Vec::new(),
+            paper: Vec::new(),
+        }
+    }
+
+    fn process_packet(&mut self)
+    {
+        if self.checksum != self.received_checksum
+        {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command
+        {
+            COMMAND_INIT =>
+            {
+                self.image.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            COMMAND_DATA =>
+            {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                self.add_image_data(&data);
+            }
+            COMMAND_PRINT if self.data.len() >= 4 =>
+            {
+                self.print(self.data[0], self.data[1], self.data[2]);
+            }
+            COMMAND_BREAK =>
+            {
+                self.image.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            COMMAND_STATUS if self.busy_polls > 0 =>
+            {
+                // Report that printing has finished after being polled a few times
+                self.busy_polls -= 1;
+                if self.busy_polls == 0
+                {
+                    self.status &= !(STATUS_PRINTING | STATUS_IMAGE_DATA_FULL);
+                }
+            }
+            _ =>
+            {}
+        }
+    }
+
+    fn add_image_data(&mut self, data: &[u8])
+    {
+        // Data arrives as bands of 20 tiles, each band being 8 pixels tall
+        for band in data.chunks_exact(TILES_PER_ROW * TILE_BYTES)
+        {
+            if self.image.len() / PRINTER_WIDTH >= MAX_IMAGE_ROWS
+            {
+                break;
+            }
+
+            for row in 0..8
+            {
+                for tile in 0..TILES_PER_ROW
+                {
+                    let low = band[tile * TILE_BYTES + row * 2];
+                    let high = band[tile * TILE_BYTES + row * 2 + 1];
+                    for pixel in 0..8
+                    {
+                        let mask = 1 << (7 - pixel);
+                        let lsb = (low & mask != 0) as u8;
+                        let msb = (high & mask != 0) as u8;
+                        self.image.push(msb << 1 | lsb);
+                    }
+                }
+            }
+        }
+
+        if !self.image.is_empty()
+        {
+            self.status |= STATUS_UNPROCESSED_DATA;
+        }
+    }
+
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8)
+    {
+        let top_margin = (margins >> 4) as usize;
+        let bottom_margin = (margins & 0x0F) as usize;
+
+        self.paper
+            .resize(self.paper.len() + top_margin * MARGIN_UNIT_ROWS * PRINTER_WIDTH, SHADES[0]);
+
+        // Zero sheets only feeds the paper
+        for _ in 0..sheets
+        {
+            for &colour in self.image.iter()
+            {
+                let shade = (palette >> (colour * 2)) & 0x3;
+                self.paper.push(SHADES[shade as usize]);
+            }
+        }
+        self.image.clear();
+
+        self.status = STATUS_PRINTING | STATUS_IMAGE_DATA_FULL;
+        self.busy_polls = PRINT_BUSY_POLLS;
+
+        if bottom_margin > 0
+        {
+            self.feed_out();
+        }
+    }
+
+    /// Save the current strip of paper and start a new one
+    fn feed_out(&mut self)
+    {
+        if self.paper.is_empty()
+        {
+            return;
+        }
+
+        let paper = std::mem::take(&mut self.paper);
+        match self.save_png(&paper)
+        {
+            Ok(path) => println!("Printed to {}", path.display()),
+            Err(error) => println!("Failed to save printout: {}", error),
+        }
+    }
+
+    fn save_png(&self, paper: &[u8]) -> io::Result<PathBuf>
+    {
+        let path = self.next_output_path();
+        let file = File::create(&path)?;
+
+        let height = (paper.len() / PRINTER_WIDTH) as u32;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), PRINTER_WIDTH as u32, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(paper).map_err(io::Error::other)?;
+        Ok(path)
+    }
+
+    fn next_output_path(&self) -> PathBuf
+    {
+        let stem = self.output_stem.to_string_lossy();
+        (1..)
+            .map(|index| PathBuf::from(format!("{}-print-{:03}.png", stem, index)))
+            .find(|path| !path.exists())
+            .unwrap()
+    }
+}
+
+impl Drop for GameBoyPrinter
+{
+    fn drop(&mut self)
+    {
+        // Don't lose anything that was printed but never fed out
+        self.feed_out();
+    }
+}
+
+impl LinkPartner for GameBoyPrinter
+{
+    fn exchange(&mut self, outgoing: u8) -> u8
+    {
+        let mut reply = 0x00;
+
+        self.state = match self.state
+        {
+            PacketState::Magic1 if outgoing == MAGIC_1 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if outgoing == MAGIC_2 => PacketState::Command,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command =>
+            {
+                self.command = outgoing;
+                self.checksum = outgoing as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression =>
+            {
+                self.compressed = outgoing & 0x1 != 0;
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow =>
+            {
+                self.length = outgoing as u16;
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh =>
+            {
+                self.length |= (outgoing as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                self.data.clear();
+                if self.length > 0
+                {
+                    PacketState::Data
+                }
+                else
+                {
+                    PacketState::ChecksumLow
+                }
+            }
+            PacketState::Data =>
+            {
+                self.data.push(outgoing);
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                if self.data.len() == self.length as usize
+                {
+                    PacketState::ChecksumLow
+                }
+                else
+                {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLow =>
+            {
+                self.received_checksum = outgoing as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh =>
+            {
+                self.received_checksum |= (outgoing as u16) << 8;
+                self.process_packet();
+                PacketState::DeviceId
+            }
+            PacketState::DeviceId =>
+            {
+                reply = DEVICE_ID;
+                PacketState::Status
+            }
+            PacketState::Status =>
+            {
+                reply = self.status;
+                PacketState::Magic1
+            }
+        };
+
+        reply
+    }
+}
+
+/// Expand RLE compressed image data. A control byte with bit 7 set repeats the next byte
+/// (control & 0x7F) + 2 times, otherwise the next (control + 1) bytes are copied as they are.
+fn decompress(data: &[u8]) -> Vec<u8>
+{
+    let mut output = Vec::new();
+    let mut index = 0;
+    while index < data.len()
+    {
+        let control = data[index];
+        index += 1;
+
+        if control & 0x80 != 0
+        {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&value) = data.get(index)
+            {
+                output.extend(std::iter::repeat_n(value, count));
+            }
+            index += 1;
+        }
+        else
+        {
+            let count = control as usize + 1;
+            let end = (index + count).min(data.len());
+            output.extend_from_slice(&data[index..end]);
+            index = end;
+        }
+    }
+    output
+}
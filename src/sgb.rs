@@ -0,0 +1,667 @@
+use std::io;
+
+use crate::gpu::{rgb555_colour, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::joypad::MAX_PLAYERS;
+use crate::savestate::{invalid_state, SaveState, StateReader, StateWriter};
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+// Position of the Game Boy screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+// JOYP select bits as written by the game while sending packets
+const PULSE_RESET: u8 = 0x00;
+const PULSE_ONE: u8 = 0x10;
+const PULSE_ZERO: u8 = 0x20;
+const PULSE_IDLE: u8 = 0x30;
+
+// Palettes are assigned to the screen in blocks of 8x8 pixels
+const ATTRIBUTE_COLUMNS: usize = SCREEN_WIDTH / 8;
+const ATTRIBUTE_ROWS: usize = SCREEN_HEIGHT / 8;
+const ATTRIBUTE_CELLS: usize = ATTRIBUTE_COLUMNS * ATTRIBUTE_ROWS;
+
+// VRAM transfers copy 4KB that the game shows on screen as tiles 0-255
+const TRANSFER_SIZE: usize = 0x1000;
+const SYSTEM_PALETTES: usize = 512;
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = ATTRIBUTE_CELLS / 4;
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+// The border uses SNES palettes 4-7 of 16 colours each
+const BORDER_PALETTE_OFFSET: usize = 0x800;
+const BORDER_PALETTES: usize = 4;
+const BORDER_FIRST_PALETTE: usize = 4;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+// Colours shown until the game sets its own palettes
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Copy, Clone, PartialEq)]
+enum VramTransfer
+{
+    Palettes,
+    BorderTiles(usize),
+    Border,
+    AttributeFiles,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ScreenMask
+{
+    None,
+    Freeze,
+    Black,
+    Colour0,
+}
+
+/// Super Game Boy functions available to games with the SGB flag.
+///
+/// Games send 16 byte packets by pulsing the JOYP select lines. The commands colour the screen
+/// with four palettes assigned per 8x8 block, surround it with a custom border and enable
+/// reading up to four controllers. Larger data such as the border is sent by showing it on
+/// screen for a frame.
+pub struct Sgb
+{
+    receiving: bool,
+    packet: [u8; PACKET_SIZE],
+    bit_index: usize,
+    previous_pulse: u8,
+    // Commands can span several packets
+    command: Vec<u8>,
+    packets_remaining: usize,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    attributes: [u8; ATTRIBUTE_CELLS],
+    attribute_files: Vec<u8>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; BORDER_PALETTES],
+    pending_transfer: Option<VramTransfer>,
+    mask: ScreenMask,
+    player_count: usize,
+    current_player: usize,
+    pub framebuffer: Vec<u8>,
+}
+
+impl Default for Sgb
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Sgb
+{
+    pub fn new() -> Self
+    {
+        Sgb {
+            receiving: false,
+            packet: [0; PACKET_SIZE],
+            bit_index: 0,
+            previous_pulse: PULSE_IDLE,
+            command: Vec::new(),
+            packets_remaining: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: [0; ATTRIBUTE_CELLS],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            border_tiles: vec![0; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_MAP_WIDTH * BORDER_MAP_WIDTH],
+            border_palettes: [[0; 16]; BORDER_PALETTES],
+            pending_transfer: None,
+            mask: ScreenMask::None,
+            player_count: 1,
+            current_player: 0,
+            framebuffer: vec![255; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT * 4],
+        }
+    }
+
+    /// Controller currently read through JOYP
+    pub fn current_player(&self) -> usize
+    {
+        self.current_player
+    }
+
+    /// Follow writes to JOYP, which carry packets one bit at a time
+    pub fn write_joypad(&mut self, value: u8)
+    {
+        let pulse = value & 0x30;
+        match pulse
+        {
+            // Pulling both lines low starts a new packet
+            PULSE_RESET =>
+            {
+                self.receiving = true;
+                self.packet = [0; PACKET_SIZE];
+                self.bit_index = 0;
+            }
+            PULSE_ONE | PULSE_ZERO if self.receiving && self.previous_pulse == PULSE_IDLE =>
+            {
+                let bit = pulse == PULSE_ONE;
+                if self.bit_index == PACKET_BITS
+                {
+                    // Every packet ends with a 0 bit
+                    self.receiving = false;
+                    if !bit
+                    {
+                        self.packet_received();
+                    }
+                }
+                else
+                {
+                    if bit
+                    {
+                        self.packet[self.bit_index / 8] |= 1 << (self.bit_index % 8);
+                    }
+                    self.bit_index += 1;
+                }
+            }
+            // The next controller is selected once the buttons of the current one have been read
+            PULSE_IDLE if !self.receiving && self.previous_pulse == PULSE_ONE =>
+            {
+                self.current_player = (self.current_player + 1) % self.player_count;
+            }
+            _ =>
+            {}
+        }
+        self.previous_pulse = pulse;
+    }
+
+    /// Handle VRAM transfers and draw the full SGB screen once the Game Boy finished a frame
+    pub fn end_frame(&mut self, shades: &[u8])
+    {
+        if let Some(transfer) = self.pending_transfer.take()
+        {
+            self.vram_transfer(transfer, &transfer_data(shades));
+        }
+        self.render(shades);
+    }
+
+    fn packet_received(&mut self)
+    {
+        if self.packets_remaining == 0
+        {
+            // The low three bits of the first byte give the number of packets
+            self.packets_remaining = (self.packet[0] & 0x07).max(1) as usize;
+            self.command.clear();
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_remaining -= 1;
+        if self.packets_remaining == 0
+        {
+            self.run_command();
+        }
+    }
+
+    fn run_command(&mut self)
+    {
+        let data = std::mem::take(&mut self.command);
+        match data[0] >> 3
+        {
+            PAL01 => self.set_palette_pair(&data, 0, 1),
+            PAL23 => self.set_palette_pair(&data, 2, 3),
+            PAL03 => self.set_palette_pair(&data, 0, 3),
+            PAL12 => self.set_palette_pair(&data, 1, 2),
+            ATTR_BLK => self.attribute_blocks(&data),
+            ATTR_LIN => self.attribute_lines(&data),
+            ATTR_DIV => self.attribute_divide(&data),
+            ATTR_CHR => self.attribute_characters(&data),
+            PAL_SET =>
+            {
+                for (palette, number) in data[1..9].chunks_exact(2).enumerate()
+                {
+                    let number = (number[0] as usize | (number[1] as usize) << 8) % SYSTEM_PALETTES;
+                    self.palettes[palette]
+                        .copy_from_slice(&self.system_palettes[number * 4..number * 4 + 4]);
+                }
+                // Colour 0 is shared by all palettes
+                self.share_colour_0(self.palettes[0][0]);
+
+                if data[9] & 0x80 != 0
+                {
+                    self.apply_attribute_file(data[9] & 0x3F);
+                }
+                if data[9] & 0x40 != 0
+                {
+                    self.mask = ScreenMask::None;
+                }
+            }
+            ATTR_SET =>
+            {
+                self.apply_attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0
+                {
+                    self.mask = ScreenMask::None;
+                }
+            }
+            PAL_TRN => self.pending_transfer = Some(VramTransfer::Palettes),
+            CHR_TRN =>
+            {
+                let first_tile = if data[1] & 0x01 != 0 { 0x80 } else { 0x00 };
+                self.pending_transfer = Some(VramTransfer::BorderTiles(first_tile));
+            }
+            PCT_TRN => self.pending_transfer = Some(VramTransfer::Border),
+            ATTR_TRN => self.pending_transfer = Some(VramTransfer::AttributeFiles),
+            MLT_REQ =>
+            {
+                self.player_count = match data[1] & 0x03
+                {
+                    1 => 2,
+                    3 => MAX_PLAYERS,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            MASK_EN =>
+            {
+                self.mask = match data[1] & 0x03
+                {
+                    1 => ScreenMask::Freeze,
+                    2 => ScreenMask::Black,
+                    3 => ScreenMask::Colour0,
+                    _ => ScreenMask::None,
+                };
+            }
+            _ =>
+            {}
+        }
+    }
+
+    /// PAL01, PAL23, PAL03 and PAL12 set the shared colour 0 and colours 1-3 of two palettes
+    fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize)
+    {
+        let colour = |index: usize| data[1 + index * 2] as u16 | (data[2 + index * 2] as u16) << 8;
+        for index in 1..4
+        {
+            self.palettes[first][index] = colour(index);
+            self.palettes[second][index] = colour(index + 3);
+        }
+        self.share_colour_0(colour(0));
+    }
+
+    fn share_colour_0(&mut self, colour: u16)
+    {
+        for palette in self.palettes.iter_mut()
+        {
+            palette[0] = colour;
+        }
+    }
+
+    /// ATTR_BLK sets the palettes inside, on the edge of and outside rectangles
+    fn attribute_blocks(&mut self, data: &[u8])
+    {
+        let count = data[1] as usize;
+        for block in data[2..].chunks_exact(6).take(count)
+        {
+            let control = block[0] & 0x07;
+            let inside = block[1] & 0x03;
+            let outside = (block[1] >> 4) & 0x03;
+            // Changing only the inside or only the outside also changes the edge to match
+            let edge = match control
+            {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some((block[1] >> 2) & 0x03),
+                _ => None,
+            };
+            let (left, top, right, bottom) =
+                (block[2] as usize, block[3] as usize, block[4] as usize, block[5] as usize);
+
+            for y in 0..ATTRIBUTE_ROWS
+            {
+                for x in 0..ATTRIBUTE_COLUMNS
+                {
+                    let within = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+                    let on_edge = within && (x == left || x == right || y == top || y == bottom);
+                    let palette = if on_edge
+                    {
+                        edge
+                    }
+                    else if within && control & 0x01 != 0
+                    {
+                        Some(inside)
+                    }
+                    else if !within && control & 0x04 != 0
+                    {
+                        Some(outside)
+                    }
+                    else
+                    {
+                        None
+                    };
+
+                    if let Some(palette) = palette
+                    {
+                        self.attributes[y * ATTRIBUTE_COLUMNS + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN sets the palette of whole rows or columns
+    fn attribute_lines(&mut self, data: &[u8])
+    {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count)
+        {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0
+            {
+                if number < ATTRIBUTE_ROWS
+                {
+                    let row = number * ATTRIBUTE_COLUMNS;
+                    self.attributes[row..row + ATTRIBUTE_COLUMNS].fill(palette);
+                }
+            }
+            else if number < ATTRIBUTE_COLUMNS
+            {
+                for y in 0..ATTRIBUTE_ROWS
+                {
+                    self.attributes[y * ATTRIBUTE_COLUMNS + number] = palette;
+                }
+            }
+        }
+    }
+
+    /// ATTR_DIV splits the screen in two at a row or column, which gets a third palette
+    fn attribute_divide(&mut self, data: &[u8])
+    {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let split_rows = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+
+        for y in 0..ATTRIBUTE_ROWS
+        {
+            for x in 0..ATTRIBUTE_COLUMNS
+            {
+                let position = if split_rows { y } else { x };
+                self.attributes[y * ATTRIBUTE_COLUMNS + x] = match position.cmp(&line)
+                {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    /// ATTR_CHR sets palettes block by block, four blocks to a byte
+    fn attribute_characters(&mut self, data: &[u8])
+    {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (data[3] as usize | (data[4] as usize) << 8).min(ATTRIBUTE_CELLS);
+        let vertical = data[5] & 0x01 != 0;
+
+        for index in 0..count
+        {
+            let Some(&byte) = data.get(6 + index / 4)
+            else
+            {
+                break;
+            };
+            if x < ATTRIBUTE_COLUMNS && y < ATTRIBUTE_ROWS
+            {
+                self.attributes[y * ATTRIBUTE_COLUMNS + x] = (byte >> (6 - (index % 4) * 2)) & 0x03;
+            }
+
+            if vertical
+            {
+                y += 1;
+                if y == ATTRIBUTE_ROWS
+                {
+                    y = 0;
+                    x += 1;
+                }
+            }
+            else
+            {
+                x += 1;
+                if x == ATTRIBUTE_COLUMNS
+                {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8)
+    {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILES
+        {
+            return;
+        }
+        for (index, attribute) in self.attributes.iter_mut().enumerate()
+        {
+            let byte = self.attribute_files[file * ATTRIBUTE_FILE_SIZE + index / 4];
+            *attribute = (byte >> (6 - (index % 4) * 2)) & 0x03;
+        }
+    }
+
+    fn vram_transfer(&mut self, transfer: VramTransfer, data: &[u8])
+    {
+        let words = |bytes: &[u8]| -> Vec<u16> {
+            bytes.chunks_exact(2).map(|word| word[0] as u16 | (word[1] as u16) << 8).collect()
+        };
+
+        match transfer
+        {
+            VramTransfer::Palettes => self.system_palettes = words(data),
+            VramTransfer::BorderTiles(first_tile) =>
+            {
+                let start = first_tile * BORDER_TILE_SIZE;
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            VramTransfer::Border =>
+            {
+                self.border_map = words(&data[..BORDER_PALETTE_OFFSET]);
+                let colours = words(&data[BORDER_PALETTE_OFFSET..]);
+                for (palette, colours) in self.border_palettes.iter_mut().zip(colours.chunks(16))
+                {
+                    palette.copy_from_slice(colours);
+                }
+            }
+            VramTransfer::AttributeFiles =>
+            {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    fn render(&mut self, shades: &[u8])
+    {
+        for y in 0..SGB_SCREEN_HEIGHT
+        {
+            for x in 0..SGB_SCREEN_WIDTH
+            {
+                let on_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+
+                // The border is drawn over the Game Boy screen except where it is transparent
+                let colour = match self.border_colour(x, y)
+                {
+                    Some(colour) => colour,
+                    None if on_screen => match self.mask
+                    {
+                        // A frozen screen keeps whatever was drawn last
+                        ScreenMask::Freeze => continue,
+                        ScreenMask::Black => 0x0000,
+                        ScreenMask::Colour0 => self.palettes[0][0],
+                        ScreenMask::None =>
+                        {
+                            let (screen_x, screen_y) = (x - SCREEN_X, y - SCREEN_Y);
+                            let palette =
+                                self.attributes[(screen_y / 8) * ATTRIBUTE_COLUMNS + screen_x / 8];
+                            let shade = shades[screen_y * SCREEN_WIDTH + screen_x];
+                            self.palettes[palette as usize][shade as usize]
+                        }
+                    },
+                    None => self.palettes[0][0],
+                };
+
+                let index = (y * SGB_SCREEN_WIDTH + x) * 4;
+                self.framebuffer[index..index + 4].copy_from_slice(&rgb555_colour(colour));
+            }
+        }
+    }
+
+    /// Colour of the border at a point, or `None` where it is transparent
+    fn border_colour(&self, x: usize, y: usize) -> Option<u16>
+    {
+        // Map entries hold the tile number, the palette and flip bits
+        let entry = self.border_map[(y / 8) * BORDER_MAP_WIDTH + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let column = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+
+        // SNES tiles have four bit planes, stored as two pairs of planes one after the other
+        let data = &self.border_tiles[tile * BORDER_TILE_SIZE..(tile + 1) * BORDER_TILE_SIZE];
+        let bit = 7 - column;
+        let colour = (0..4).fold(0, |colour, plane| {
+            let byte = data[(plane / 2) * 16 + row * 2 + plane % 2];
+            colour | ((byte >> bit) & 0x1) << plane
+        });
+
+        let palette = palette.checked_sub(BORDER_FIRST_PALETTE)?;
+        if colour == 0 || palette >= BORDER_PALETTES
+        {
+            return None;
+        }
+        Some(self.border_palettes[palette][colour as usize])
+    }
+}
+
+/// Read back the 4KB the game is showing as tiles 0-255, twenty tiles to a row
+fn transfer_data(shades: &[u8]) -> Vec<u8>
+{
+    let mut data = vec![0; TRANSFER_SIZE];
+    for (tile, bytes) in data.chunks_exact_mut(16).enumerate()
+    {
+        let tile_x = (tile % ATTRIBUTE_COLUMNS) * 8;
+        let tile_y = (tile / ATTRIBUTE_COLUMNS) * 8;
+        for row in 0..8
+        {
+            for column in 0..8
+            {
+                let shade = shades[(tile_y + row) * SCREEN_WIDTH + tile_x + column];
+                let bit = 7 - column;
+                bytes[row * 2] |= (shade & 0x1) << bit;
+                bytes[row * 2 + 1] |= ((shade >> 1) & 0x1) << bit;
+            }
+        }
+    }
+    data
+}
+
+impl SaveState for Sgb
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.bool(self.receiving);
+        writer.bytes(&self.packet);
+        writer.usize(self.bit_index);
+        writer.u8(self.previous_pulse);
+        writer.vec(&self.command);
+        writer.usize(self.packets_remaining);
+        for palette in self.palettes.iter()
+        {
+            writer.u16s(palette);
+        }
+        writer.u16s(&self.system_palettes);
+        writer.bytes(&self.attributes);
+        writer.bytes(&self.attribute_files);
+        writer.bytes(&self.border_tiles);
+        writer.u16s(&self.border_map);
+        for palette in self.border_palettes.iter()
+        {
+            writer.u16s(palette);
+        }
+        writer.u8(match self.pending_transfer
+        {
+            None => 0,
+            Some(VramTransfer::Palettes) => 1,
+            Some(VramTransfer::BorderTiles(0)) => 2,
+            Some(VramTransfer::BorderTiles(_)) => 3,
+            Some(VramTransfer::Border) => 4,
+            Some(VramTransfer::AttributeFiles) => 5,
+        });
+        writer.u8(self.mask as u8);
+        writer.usize(self.player_count);
+        writer.usize(self.current_player);
+        writer.bytes(&self.framebuffer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.receiving = reader.bool()?;
+        reader.bytes(&mut self.packet)?;
+        self.bit_index = reader.usize()?.min(PACKET_BITS);
+        self.previous_pulse = reader.u8()?;
+        self.command = reader.vec()?;
+        self.packets_remaining = reader.usize()?;
+        for palette in self.palettes.iter_mut()
+        {
+            reader.u16s(palette)?;
+        }
+        reader.u16s(&mut self.system_palettes)?;
+        reader.bytes(&mut self.attributes)?;
+        reader.bytes(&mut self.attribute_files)?;
+        reader.bytes(&mut self.border_tiles)?;
+        reader.u16s(&mut self.border_map)?;
+        for palette in self.border_palettes.iter_mut()
+        {
+            reader.u16s(palette)?;
+        }
+        self.pending_transfer = match reader.u8()?
+        {
+            0 => None,
+            1 => Some(VramTransfer::Palettes),
+            2 => Some(VramTransfer::BorderTiles(0x00)),
+            3 => Some(VramTransfer::BorderTiles(0x80)),
+            4 => Some(VramTransfer::Border),
+            5 => Some(VramTransfer::AttributeFiles),
+            _ => return Err(invalid_state("bad SGB transfer")),
+        };
+        self.mask = match reader.u8()?
+        {
+            0 => ScreenMask::None,
+            1 => ScreenMask::Freeze,
+            2 => ScreenMask::Black,
+            3 => ScreenMask::Colour0,
+            _ => return Err(invalid_state("bad SGB screen mask")),
+        };
+        self.player_count = reader.usize()?.clamp(1, MAX_PLAYERS);
+        self.current_player = reader.usize()? % self.player_count;
+        reader.bytes(&mut self.framebuffer)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::memorybus::{mapped_bank, MemoryBus};
+
+#[derive(Clone)]
+struct Symbol
+{
+    bank: usize,
+    address: u16,
+    name: String,
+}
+
+/// Labels from the symbol files written by RGBDS and WLA-DX, so addresses can be shown by name.
+///
+/// `.sym` files have a `bank:address label` line for every label. RGBDS `.map` files list
+/// `$address = label` under a header naming the bank. The same address can hold different labels
+/// in different banks, so lookups go by the bank mapped at the time.
+#[derive(Clone, Default)]
+pub struct Symbols
+{
+    // Sorted by bank then address
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols
+{
+    pub fn new() -> Self
+    {
+        Symbols { symbols: Vec::new() }
+    }
+
+    /// Load a `.map` file or otherwise a `.sym` file
+    pub fn load(path: &Path) -> io::Result<Self>
+    {
+        let text = std::fs::read_to_string(path)?;
+        let is_map = path.extension().is_some_and(|extension| extension == "map");
+        Ok(if is_map { Self::parse_map(&text) } else { Self::parse_sym(&text) })
+    }
+
+    /// Load the `.sym` or `.map` file next to a ROM if there is one
+    pub fn for_rom(rom_path: &Path) -> Option<(Self, PathBuf)>
+    {
+        ["sym", "map"].into_iter().find_map(|extension| {
+            let path = rom_path.with_extension(extension);
+            match Self::load(&path)
+            {
+                Ok(symbols) => Some((symbols, path)),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+                Err(error) =>
+                {
+                    println!("Failed to load symbols from {}: {}", path.display(), error);
+                    None
+                }
+            }
+        })
+    }
+
+    pub fn parse_sym(text: &str) -> Self
+    {
+        let mut symbols = Symbols::new();
+        for line in text.lines()
+        {
+            // Comments start with a semicolon and WLA-DX splits the file into [sections]
+            let line = line.split(';').next().unwrap_or_default().trim();
+            let Some((location, name)) = line.split_once(char::is_whitespace)
+            else
+            {
+                continue;
+            };
+            let Some((bank, address)) = location.split_once(':')
+            else
+            {
+                continue;
+            };
+            if let (Ok(bank), Ok(address)) =
+                (usize::from_str_radix(bank, 16), u16::from_str_radix(address, 16))
+            {
+                symbols.add(bank, address, name.trim());
+            }
+        }
+        symbols.sort();
+        symbols
+    }
+
+    pub fn parse_map(text: &str) -> Self
+    {
+        let mut symbols = Symbols::new();
+        let mut bank = 0;
+        for line in text.lines()
+        {
+            let line = line.trim();
+            // Headers like `ROMX bank #2:`
+            if let Some((_, number)) = line.split_once("bank #")
+            {
+                bank = number.trim_end_matches(':').parse().unwrap_or(0);
+                continue;
+            }
+            let Some((address, name)) = line.split_once(" = ")
+            else
+            {
+                continue;
+            };
+            if let Some(Ok(address)) =
+                address.trim().strip_prefix('$').map(|digits| u16::from_str_radix(digits, 16))
+            {
+                symbols.add(bank, address, name.trim());
+            }
+        }
+        symbols.sort();
+        symbols
+    }
+
+    fn add(&mut self, bank: usize, address: u16, name: &str)
+    {
+        let bank = mapped_bank(address, bank);
+        self.symbols.push(Symbol { bank, address, name: name.to_string() });
+    }
+
+    fn sort(&mut self)
+    {
+        self.symbols.sort_by_key(|symbol| (symbol.bank, symbol.address));
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.symbols.is_empty()
+    }
+
+    /// Bank and address of a label
+    pub fn find(&self, name: &str) -> Option<(usize, u16)>
+    {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| (symbol.bank, symbol.address))
+    }
+
+    /// The label at exactly this address in a bank
+    pub fn label_at(&self, bank: usize, address: u16) -> Option<&str>
+    {
+        self.symbols
+            .binary_search_by_key(&(bank, address), |symbol| (symbol.bank, symbol.address))
+            .ok()
+            .map(|index| self.symbols[index].name.as_str())
+    }
+
+    /// The closest label at or before an address in a bank, with the distance from it, such as
+    /// `Main.loop+$3`. Only labels in the same area of memory count.
+    pub fn describe_in(&self, bank: usize, address: u16) -> Option<String>
+    {
+        let index =
+            self.symbols.partition_point(|symbol| (symbol.bank, symbol.address) <= (bank, address));
+        let symbol = self.symbols[..index].last()?;
+        if symbol.bank != bank || area(symbol.address) != area(address)
+        {
+            return None;
+        }
+        Some(match address - symbol.address
+        {
+            0 => symbol.name.clone(),
+            offset => format!("{}+${:X}", symbol.name, offset),
+        })
+    }
+
+    /// The label at exactly this address in whatever bank is mapped there now
+    pub fn label(&self, bus: &MemoryBus, address: u16) -> Option<&str>
+    {
+        self.label_at(bus.bank_at(address), address)
+    }
+
+    /// `describe_in` for whatever bank is mapped at the address now
+    pub fn describe(&self, bus: &MemoryBus, address: u16) -> Option<String>
+    {
+        self.describe_in(bus.bank_at(address), address)
+    }
+}
+
+/// Start of the area of memory holding an address
+fn area(address: u16) -> u16
+{
+    match address
+    {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xE000..=0xFF7F => 0xE000,
+        _ => 0xFF80,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+[labels]
+00:0150 Main
+00:0158 Main.loop
+02:4000 BankedData ; in ROMX
+00:D000 wState
+not a label
+01:40zz Broken
+";
+
+    const MAP: &str = "\
+ROM0 bank #0:
+\tSECTION: $0150-$015f ($0010 bytes) [\"Main\"]
+\t         $0150 = Main
+\t         $0158 = Main.loop
+ROMX bank #2:
+\tSECTION: $4000-$400f ($0010 bytes) [\"Data\"]
+\t         $4000 = BankedData
+WRAMX bank #0:
+\t         $d000 = wState
+\t         $40zz = Broken
+";
+
+    #[test]
+    fn sym_files_give_banked_labels()
+    {
+        let symbols = Symbols::parse_sym(SYM);
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.find("Main.loop"), Some((0, 0x0158)));
+        assert_eq!(symbols.find("BankedData"), Some((2, 0x4000)));
+        // Bank 0 of WRAMX is bank 1
+        assert_eq!(symbols.find("wState"), Some((1, 0xD000)));
+        assert_eq!(symbols.find("Broken"), None);
+        assert_eq!(symbols.label_at(2, 0x4000), Some("BankedData"));
+        assert_eq!(symbols.label_at(1, 0x4000), None);
+    }
+
+    #[test]
+    fn map_files_give_the_same_labels()
+    {
+        let symbols = Symbols::parse_map(MAP);
+        assert_eq!(symbols.len(), 4);
+        for name in ["Main", "Main.loop", "BankedData", "wState"]
+        {
+            assert_eq!(symbols.find(name), Symbols::parse_sym(SYM).find(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn addresses_are_described_from_the_closest_label()
+    {
+        let symbols = Symbols::parse_sym(SYM);
+        assert_eq!(symbols.describe_in(0, 0x0150).as_deref(), Some("Main"));
+        assert_eq!(symbols.describe_in(0, 0x015A).as_deref(), Some("Main.loop+$2"));
+        assert_eq!(symbols.describe_in(0, 0x0100), None);
+        // Labels don't reach into another bank or area of memory
+        assert_eq!(symbols.describe_in(1, 0x4002), None);
+        assert_eq!(symbols.describe_in(0, 0x4002), None);
+    }
+}
@@ -0,0 +1,146 @@
+use std::io;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const DIV_ADDRESS: usize = 0xFF04;
+pub const TIMA_ADDRESS: usize = 0xFF05;
+pub const TMA_ADDRESS: usize = 0xFF06;
+pub const TAC_ADDRESS: usize = 0xFF07;
+
+const TAC_ENABLE: u8 = 0x04;
+
+/// DIV and the programmable timer.
+///
+/// DIV is the top byte of a 16 bit counter that advances every cycle. TIMA is incremented
+/// whenever the counter bit picked by TAC falls from 1 to 0, so resetting DIV or changing TAC
+/// can increment it as well.
+pub struct Timer
+{
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl Default for Timer
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Timer
+{
+    pub fn new() -> Self
+    {
+        Timer { counter: 0, tima: 0, tma: 0, tac: 0 }
+    }
+
+    /// Set the internal counter, used to match the DIV phase left behind by the boot ROM
+    pub fn set_counter(&mut self, counter: u16)
+    {
+        self.counter = counter;
+    }
+
+    pub fn read_register(&self, address: usize) -> u8
+    {
+        match address
+        {
+            DIV_ADDRESS => (self.counter >> 8) as u8,
+            TIMA_ADDRESS => self.tima,
+            TMA_ADDRESS => self.tma,
+            TAC_ADDRESS => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    /// Returns true if the write overflowed TIMA and requests a timer interrupt
+    pub fn write_register(&mut self, address: usize, value: u8) -> bool
+    {
+        let was_high = self.timer_signal();
+        match address
+        {
+            DIV_ADDRESS => self.counter = 0,
+            TIMA_ADDRESS => self.tima = value,
+            TMA_ADDRESS => self.tma = value,
+            TAC_ADDRESS => self.tac = value & 0x07,
+            _ =>
+            {}
+        }
+        was_high && !self.timer_signal() && self.increment_tima()
+    }
+
+    /// Set a register without the side effects of writing it. DIV sets the top of the counter
+    /// instead of resetting it and TIMA is never incremented.
+    pub fn poke_register(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            DIV_ADDRESS => self.counter = (value as u16) << 8 | (self.counter & 0xFF),
+            TIMA_ADDRESS => self.tima = value,
+            TMA_ADDRESS => self.tma = value,
+            TAC_ADDRESS => self.tac = value & 0x07,
+            _ =>
+            {}
+        }
+    }
+
+    /// Advance by the number of cycles the last instruction took. Returns true if TIMA
+    /// overflowed and a timer interrupt should be requested.
+    pub fn step(&mut self, cycles: u8) -> bool
+    {
+        let mut interrupt = false;
+        for _ in 0..cycles / 4
+        {
+            let was_high = self.timer_signal();
+            self.counter = self.counter.wrapping_add(4);
+            if was_high && !self.timer_signal()
+            {
+                interrupt |= self.increment_tima();
+            }
+        }
+        interrupt
+    }
+
+    fn timer_signal(&self) -> bool
+    {
+        // Counter bit that clocks TIMA at 4096, 262144, 65536 and 16384 Hz
+        let bit = match self.tac & 0x03
+        {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & TAC_ENABLE != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment_tima(&mut self) -> bool
+    {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        // TIMA is reloaded from TMA when it overflows
+        self.tima = if overflow { self.tma } else { tima };
+        overflow
+    }
+}
+
+impl SaveState for Timer
+{
+    fn save_state(&self, writer: &mut StateWriter)
+    {
+        writer.u16(self.counter);
+        writer.u8(self.tima);
+        writer.u8(self.tma);
+        writer.u8(self.tac);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()>
+    {
+        self.counter = reader.u16()?;
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        self.tac = reader.u8()?;
+        Ok(())
+    }
+}
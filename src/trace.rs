@@ -0,0 +1,72 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::CPU;
+use crate::symbols::Symbols;
+
+/// Logs the registers before every instruction in the format used by Gameboy Doctor, so a run
+/// can be compared line by line with its reference logs:
+///
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+///
+/// With symbols, each line ends with the label the instruction falls under, like
+/// ` ; Main.loop+$3`.
+pub struct Trace
+{
+    output: Box<dyn Write>,
+    symbols: Option<Symbols>,
+}
+
+impl Trace
+{
+    pub fn new(output: Box<dyn Write>) -> Self
+    {
+        Trace { output, symbols: None }
+    }
+
+    pub fn create(path: &Path) -> io::Result<Self>
+    {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    pub fn with_symbols(self, symbols: Symbols) -> Self
+    {
+        Trace { symbols: Some(symbols), ..self }
+    }
+
+    pub fn write(&mut self, cpu: &CPU) -> io::Result<()>
+    {
+        let registers = &cpu.registers;
+        let memory: Vec<String> = (0..4)
+            .map(|offset| format!("{:02X}", cpu.bus.peek_byte(cpu.pc.wrapping_add(offset))))
+            .collect();
+        write!(
+            self.output,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} \
+             PC:{:04X} PCMEM:{}",
+            registers.a,
+            u8::from(registers.f),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            cpu.sp,
+            cpu.pc,
+            memory.join(",")
+        )?;
+        if let Some(name) =
+            self.symbols.as_ref().and_then(|symbols| symbols.describe(&cpu.bus, cpu.pc))
+        {
+            write!(self.output, " ; {}", name)?;
+        }
+        writeln!(self.output)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()>
+    {
+        self.output.flush()
+    }
+}